@@ -1,10 +1,7 @@
 use std::time::Duration;
 
 use axum::{extract::Request, routing::get, Router};
-use opentelemetry::{
-    trace::{self, TracerProvider as _},
-    KeyValue,
-};
+use opentelemetry::{trace::TracerProvider as _, KeyValue};
 use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::{
     runtime::Tokio,
@@ -83,7 +80,7 @@ async fn long_task() -> &'static str {
     join!(t1, t2, t3);
     let elapsed = start.elapsed().as_millis();
     warn!(app.task_duration = elapsed, "task takes too long");
-    "Hello, world!".into()
+    "Hello, world!"
 }
 
 #[instrument]
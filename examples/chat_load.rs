@@ -0,0 +1,286 @@
+// chat 压测工具: 模拟大量 LinesCodec 客户端, 统计广播扇出延迟和丢失数量
+// 先启动 chat: cargo run --example chat
+// 再运行: cargo run --release --example chat_load -- --peers 1000 --rate 1 --duration 30
+// 连接数较多时注意调大 ulimit -n
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use futures::{SinkExt, StreamExt};
+use hdrhistogram::Histogram;
+use tokio::{
+    net::TcpStream,
+    sync::Barrier,
+    task::JoinSet,
+    time::{interval, sleep, timeout, Instant, MissedTickBehavior},
+};
+use tokio_util::codec::{Framed, LinesCodec};
+
+#[derive(Debug, Parser)]
+#[command(about = "Load generator for the chat example")]
+struct Args {
+    /// chat server address
+    #[arg(long, default_value = "127.0.0.1:8081")]
+    addr: SocketAddr,
+    /// number of peers that stay connected and talk
+    #[arg(long, default_value_t = 100)]
+    peers: usize,
+    /// messages per second sent by each talking peer
+    #[arg(long, default_value_t = 1.0, value_parser = parse_rate)]
+    rate: f64,
+    /// length of the talk phase in seconds
+    #[arg(long, default_value_t = 10)]
+    duration: u64,
+    /// join, talk and leave pattern to run
+    #[arg(long, value_enum, default_value_t = Scenario::Steady)]
+    scenario: Scenario,
+    /// number of extra peers that keep leaving and rejoining (churn scenario only)
+    #[arg(long, default_value_t = 10)]
+    churners: usize,
+    /// seconds to keep listening after the talk phase so in-flight messages arrive
+    #[arg(long, default_value_t = 2)]
+    drain: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Scenario {
+    /// all peers join, talk for the whole duration, then leave together
+    Steady,
+    /// like steady, plus churners that join and leave in a loop during the talk phase
+    Churn,
+}
+
+#[derive(Debug, Default)]
+struct Stats {
+    peer_failures: AtomicU64,
+    sent: AtomicU64,
+    received: AtomicU64,
+    presence: AtomicU64,
+    rejoins: AtomicU64,
+}
+
+const LATENCY_MAX_US: u64 = 60_000_000;
+// 收到用户名之后 server 才把 peer 加入 state, 开始发消息前稍等一下
+const SETTLE: Duration = Duration::from_millis(500);
+
+// rate 用来计算发送间隔, 必须是正数, 并且间隔能用不为 0 的 Duration 表示
+fn parse_rate(s: &str) -> Result<f64, String> {
+    let rate: f64 = s.parse().map_err(|e| format!("{}", e))?;
+    let period = Duration::try_from_secs_f64(1.0 / rate);
+    if rate > 0.0 && matches!(period, Ok(period) if !period.is_zero()) {
+        Ok(rate)
+    } else {
+        Err(format!("rate must be a positive number, got {}", s))
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    if args.peers < 2 {
+        return Err(anyhow!("need at least 2 peers to measure fan-out"));
+    }
+
+    let stats = Arc::new(Stats::default());
+    let start = Instant::now();
+    // 所有 talker 都加入之后才开始发消息, 这样每条消息的期望接收数是确定的
+    let joined = Arc::new(Barrier::new(args.peers));
+    let talk = Duration::from_secs(args.duration);
+    let drain = Duration::from_secs(args.drain);
+    let period = Duration::from_secs_f64(1.0 / args.rate);
+
+    println!(
+        "running {:?} scenario against {}: {} peers, {} msg/s each, {}s",
+        args.scenario, args.addr, args.peers, args.rate, args.duration
+    );
+
+    let mut talkers = JoinSet::new();
+    for i in 0..args.peers {
+        let stats = Arc::clone(&stats);
+        let joined = Arc::clone(&joined);
+        talkers.spawn(talker(
+            args.addr, i, start, period, talk, drain, joined, stats,
+        ));
+    }
+
+    let mut churners = JoinSet::new();
+    if args.scenario == Scenario::Churn {
+        for i in 0..args.churners {
+            let stats = Arc::clone(&stats);
+            churners.spawn(churner(args.addr, i, talk, stats));
+        }
+    }
+
+    let mut latency = new_histogram()?;
+    while let Some(ret) = talkers.join_next().await {
+        match ret? {
+            Ok(hist) => latency.add(hist)?,
+            Err(e) => {
+                eprintln!("peer failed: {e}");
+                stats.peer_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    churners.abort_all();
+
+    report(&args, &stats, &latency, start.elapsed());
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn talker(
+    addr: SocketAddr,
+    id: usize,
+    start: Instant,
+    period: Duration,
+    talk: Duration,
+    drain: Duration,
+    joined: Arc<Barrier>,
+    stats: Arc<Stats>,
+) -> Result<Histogram<u64>> {
+    let username = format!("load-{id}");
+    let stream = join(addr, &username).await;
+    // barrier 里每个 talker 都要到齐, 连接失败也要 wait 一次, 否则其余 peer 会卡住
+    joined.wait().await;
+    let stream = stream?;
+    sleep(SETTLE).await;
+    let (mut sink, mut lines) = stream.split();
+
+    let sender = {
+        let stats = Arc::clone(&stats);
+        tokio::spawn(async move {
+            let mut ticker = interval(period);
+            ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            let deadline = Instant::now() + talk;
+            while Instant::now() < deadline {
+                ticker.tick().await;
+                // 消息内容是相对 start 的微秒数, 接收方据此算出扇出延迟
+                let sent_at = start.elapsed().as_micros();
+                sink.send(sent_at.to_string()).await?;
+                stats.sent.fetch_add(1, Ordering::Relaxed);
+            }
+            sleep(drain).await;
+            Ok::<_, anyhow::Error>(sink)
+        })
+    };
+
+    let mut hist = new_histogram()?;
+    let deadline = Instant::now() + talk + drain;
+    loop {
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        let line = match timeout(remaining, lines.next()).await {
+            Ok(Some(line)) => line?,
+            Ok(None) => return Err(anyhow!("{username}: server closed the connection")),
+            Err(_) => break,
+        };
+        match parse_chat(&line) {
+            Some((sender, sent_at)) if sender.starts_with("load-") => {
+                let now = start.elapsed().as_micros() as u64;
+                hist.saturating_record(now.saturating_sub(sent_at));
+                stats.received.fetch_add(1, Ordering::Relaxed);
+            }
+            _ => {
+                stats.presence.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    // 离开: 关闭连接, server 会广播 left 消息
+    let sink = sender.await??;
+    drop(sink.reunite(lines));
+    Ok(hist)
+}
+
+async fn churner(addr: SocketAddr, id: usize, talk: Duration, stats: Arc<Stats>) {
+    let deadline = Instant::now() + talk;
+    let username = format!("churn-{id}");
+    while Instant::now() < deadline {
+        match join(addr, &username).await {
+            Ok(stream) => {
+                stats.rejoins.fetch_add(1, Ordering::Relaxed);
+                sleep(Duration::from_millis(200)).await;
+                drop(stream);
+            }
+            Err(e) => eprintln!("churner failed: {e}"),
+        }
+        sleep(Duration::from_millis(100)).await;
+    }
+}
+
+async fn join(addr: SocketAddr, username: &str) -> Result<Framed<TcpStream, LinesCodec>> {
+    let stream = TcpStream::connect(addr).await?;
+    let mut stream = Framed::new(stream, LinesCodec::new());
+    // 读掉 "Enter your username:" 提示
+    match stream.next().await {
+        Some(line) => line?,
+        None => return Err(anyhow!("{username}: connection closed during handshake")),
+    };
+    stream.send(username).await?;
+    Ok(stream)
+}
+
+fn parse_chat(line: &str) -> Option<(&str, u64)> {
+    let (sender, content) = line.split_once(": ")?;
+    Some((sender, content.parse().ok()?))
+}
+
+fn new_histogram() -> Result<Histogram<u64>> {
+    Ok(Histogram::new_with_bounds(1, LATENCY_MAX_US, 3)?)
+}
+
+fn report(args: &Args, stats: &Stats, latency: &Histogram<u64>, elapsed: Duration) {
+    let sent = stats.sent.load(Ordering::Relaxed);
+    let received = stats.received.load(Ordering::Relaxed);
+    let failures = stats.peer_failures.load(Ordering::Relaxed);
+    let connected = (args.peers as u64).saturating_sub(failures.min(args.peers as u64));
+    // 每条消息应该被其余所有 talker 收到
+    let expected = sent * connected.saturating_sub(1);
+    let drops = expected.saturating_sub(received);
+
+    println!();
+    println!("elapsed:           {:.2}s", elapsed.as_secs_f64());
+    println!("connected peers:   {connected}");
+    println!("peer failures:     {failures}");
+    if args.scenario == Scenario::Churn {
+        println!(
+            "churn rejoins:     {}",
+            stats.rejoins.load(Ordering::Relaxed)
+        );
+    }
+    println!(
+        "presence events:   {}",
+        stats.presence.load(Ordering::Relaxed)
+    );
+    println!("messages sent:     {sent}");
+    println!(
+        "send rate:         {:.1} msg/s",
+        sent as f64 / args.duration.max(1) as f64
+    );
+    println!("deliveries:        {received}/{expected}");
+    println!(
+        "delivery rate:     {:.1} msg/s",
+        received as f64 / elapsed.as_secs_f64()
+    );
+    // drain 窗口结束时仍未收到的也算作丢失
+    println!("drops:             {drops}");
+    if latency.is_empty() {
+        println!("fan-out latency:   no samples");
+        return;
+    }
+    println!("fan-out latency:");
+    for q in [0.5, 0.9, 0.99, 0.999] {
+        println!(
+            "  p{:<6} {:>10.3}ms",
+            q * 100.0,
+            latency.value_at_quantile(q) as f64 / 1000.0
+        );
+    }
+    println!("  max     {:>10.3}ms", latency.max() as f64 / 1000.0);
+}
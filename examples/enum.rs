@@ -1,12 +1,13 @@
 use anyhow::Result;
 use strum::{
     EnumCount, EnumDiscriminants, EnumIs, EnumIter, EnumString, IntoEnumIterator, IntoStaticStr,
-    VariantArray, VariantNames,
+    VariantNames,
 };
 
 #[derive(
     Debug, EnumString, EnumCount, EnumDiscriminants, EnumIter, EnumIs, IntoStaticStr, VariantNames,
 )]
+#[allow(unused)]
 enum MyEnum {
     A,
     B(String),
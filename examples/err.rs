@@ -19,7 +19,7 @@ pub enum MyError {
 
 #[allow(unused)]
 #[derive(Debug)]
-struct BigError {
+pub struct BigError {
     a: String,
     b: Vec<String>,
     c: [u8; 64],
@@ -31,7 +31,7 @@ fn main() -> Result<()> {
 
     // 这里能这么做的原因是, Io error 能够转换成MyError
     let filename = "nonexistent_file.txt";
-    let fd =
+    let _fd =
         fs::File::open(filename).with_context(|| format!("can not find file: {}", filename))?;
     fail_with_error()?;
 
@@ -0,0 +1,162 @@
+// minginx 转发吞吐量的基准测试: 对比直连 backend、copy 转发和 splice 转发
+// cargo run --release --example minginx_bench -- --size 4096 --connections 4
+// 每个连接向 backend 上传数据, 关闭写之后 backend 回复收到的字节数
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
+
+use anyhow::{anyhow, Result};
+use clap::{Parser, ValueEnum};
+use ecosystem::minginx::{
+    self, AccessLogConfig, Config, Forwarding, ListenerConfig, ServerConfig, UpstreamConfig,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::JoinSet,
+    time::Instant,
+};
+
+#[derive(Debug, Parser)]
+#[command(about = "Compare minginx forwarding throughput")]
+struct Args {
+    /// total MiB to upload in each round
+    #[arg(long, default_value_t = 1024)]
+    size: usize,
+    /// concurrent connections sharing the upload
+    #[arg(long, default_value_t = 4)]
+    connections: usize,
+    /// rounds per mode, the best one is reported
+    #[arg(long, default_value_t = 3)]
+    rounds: usize,
+    /// modes to run, all of them by default
+    #[arg(long, value_enum)]
+    mode: Vec<Mode>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, ValueEnum)]
+enum Mode {
+    /// connect to the backend without minginx, as the upper bound
+    Direct,
+    Copy,
+    Splice,
+}
+
+const CHUNK: usize = 256 * 1024;
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let modes = if args.mode.is_empty() {
+        vec![Mode::Direct, Mode::Copy, Mode::Splice]
+    } else {
+        args.mode.clone()
+    };
+    let backend = start_sink().await?;
+    let per_connection = args.size * 1024 * 1024 / args.connections;
+
+    let mut results = BTreeMap::new();
+    for mode in modes {
+        let (addr, _handle) = match mode {
+            Mode::Direct => (backend, None),
+            Mode::Copy | Mode::Splice => {
+                let forwarding = match mode {
+                    Mode::Splice => Forwarding::Splice,
+                    _ => Forwarding::Copy,
+                };
+                let handle = minginx::start(config(backend, forwarding)).await?;
+                (handle.local_addrs()[0], Some(handle))
+            }
+        };
+
+        let mut best = Duration::MAX;
+        for _ in 0..args.rounds {
+            best = best.min(round(addr, args.connections, per_connection).await?);
+        }
+        let mib = (per_connection * args.connections) as f64 / 1024.0 / 1024.0;
+        results.insert(mode, mib / best.as_secs_f64());
+    }
+
+    println!("{:>8}  {:>12}", "mode", "MiB/s");
+    for (mode, throughput) in results {
+        println!("{:>8}  {:>12.1}", format!("{:?}", mode), throughput);
+    }
+    Ok(())
+}
+
+fn config(backend: SocketAddr, forwarding: Forwarding) -> Config {
+    Config {
+        listeners: vec![ListenerConfig {
+            upstream: "sink".into(),
+            ..Default::default()
+        }],
+        upstreams: BTreeMap::from([(
+            "sink".into(),
+            UpstreamConfig {
+                servers: vec![ServerConfig {
+                    addr: backend.to_string(),
+                    weight: 1,
+                }],
+                forwarding,
+                max_attempts: 1,
+                ..Default::default()
+            },
+        )]),
+        access_log: AccessLogConfig {
+            path: Some("/dev/null".into()),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+// 读到 EOF 之后回复收到的字节数
+async fn start_sink() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = vec![0; CHUNK];
+                let mut total = 0u64;
+                loop {
+                    let n = stream.read(&mut buf).await?;
+                    if n == 0 {
+                        break;
+                    }
+                    total += n as u64;
+                }
+                stream.write_u64(total).await?;
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok(addr)
+}
+
+async fn round(addr: SocketAddr, connections: usize, size: usize) -> Result<Duration> {
+    let start = Instant::now();
+    let mut tasks = JoinSet::new();
+    for _ in 0..connections {
+        tasks.spawn(upload(addr, size));
+    }
+    while let Some(ret) = tasks.join_next().await {
+        ret??;
+    }
+    Ok(start.elapsed())
+}
+
+async fn upload(addr: SocketAddr, size: usize) -> Result<()> {
+    let mut stream = TcpStream::connect(addr).await?;
+    let chunk = vec![0x5a; CHUNK];
+    let mut left = size;
+    while left > 0 {
+        let n = left.min(CHUNK);
+        stream.write_all(&chunk[..n]).await?;
+        left -= n;
+    }
+    stream.shutdown().await?;
+    let received = stream.read_u64().await?;
+    if received != size as u64 {
+        return Err(anyhow!("backend received {} of {} bytes", received, size));
+    }
+    Ok(())
+}
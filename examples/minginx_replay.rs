@@ -0,0 +1,60 @@
+use std::{path::PathBuf, process, time::Duration};
+
+use anyhow::Result;
+use clap::Parser;
+use ecosystem::minginx::{replay, CaptureFile, ReplayOptions};
+
+// 不一致时前后各显示的字节数
+const CONTEXT: usize = 32;
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// capture file written by minginx
+    capture: PathBuf,
+    /// address to replay against, defaults to the backend in the capture
+    #[arg(short, long)]
+    target: Option<String>,
+    /// send all client data at once instead of keeping the original timing
+    #[arg(long)]
+    fast: bool,
+    /// stop after the target is idle for this many milliseconds
+    #[arg(long, default_value_t = 1000)]
+    idle_ms: u64,
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let capture = CaptureFile::read(&args.capture).await?;
+    let header = &capture.header;
+    let target = args.target.as_deref().unwrap_or(&header.backend);
+    println!(
+        "session {} from {} via {} to {} at {}, replaying against {}",
+        header.session, header.client, header.listener, header.upstream, header.start, target
+    );
+
+    let options = ReplayOptions {
+        keep_timing: !args.fast,
+        idle_timeout: Duration::from_millis(args.idle_ms),
+    };
+    let report = replay(&capture, target, &options).await?;
+    let Some(at) = report.first_mismatch() else {
+        println!("ok: {} bytes match", report.actual.len());
+        return Ok(());
+    };
+    println!(
+        "mismatch at byte {}: expected {} bytes, got {}",
+        at,
+        report.expected.len(),
+        report.actual.len()
+    );
+    println!("expected: {}", around(&report.expected, at));
+    println!("actual:   {}", around(&report.actual, at));
+    process::exit(1);
+}
+
+fn around(data: &[u8], at: usize) -> String {
+    let start = at.saturating_sub(CONTEXT).min(data.len());
+    let end = (at + CONTEXT).min(data.len());
+    format!("\"{}\"", data[start..end].escape_ascii())
+}
@@ -4,6 +4,7 @@ use derive_more::{Add, Display, From, Into};
 #[derive(PartialEq, Copy, Clone, From, Add, Into, Display)]
 struct MyInt(i32);
 
+#[allow(unused)]
 #[derive(PartialEq, From)]
 struct Point2D {
     x: i32,
@@ -1,6 +1,10 @@
 use anyhow::Result;
 use axum::{
-    extract::{Path, State}, http::{header::LOCATION, HeaderMap, StatusCode}, response::IntoResponse, routing::{get, post}, Json, Router,
+    extract::{Path, State},
+    http::{header::LOCATION, HeaderMap, StatusCode},
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
 };
 use axum_macros::debug_handler;
 use derive_builder::Builder;
@@ -44,7 +48,6 @@ async fn main() -> Result<()> {
     // PgPool 本身是Arc的, 所以不需要再套Arc
     let state = AppState::try_new(url).await?;
     info!("connected to database: {url}");
-    let addr = "127.0.0.1:9876";
     let listener = tokio::net::TcpListener::bind(LISTEN_ADDR).await?;
     info!("listening on {}", LISTEN_ADDR);
 
@@ -64,30 +67,27 @@ async fn shorten(
     State(state): State<AppState>,
     Json(data): Json<ShortenReq>, // post body 只能consumey一次, 所以要放在最后一个
 ) -> Result<impl IntoResponse, StatusCode> {
-    let id = state
-        .shorten(&data.url)
-        .await
-        .map_err(|e| {
-            warn!("shorten failed: {}", e);
-            StatusCode::UNPROCESSABLE_ENTITY
-        })?;
+    let id = state.shorten(&data.url).await.map_err(|e| {
+        warn!("shorten failed: {}", e);
+        StatusCode::UNPROCESSABLE_ENTITY
+    })?;
     let body = Json(ShortenResp {
         url: format!("http://{}/{}", LISTEN_ADDR, id),
     });
     Ok((StatusCode::CREATED, body))
 }
 
-async fn redirect(State(state): State<AppState>, Path(id): Path<String>) -> Result<impl IntoResponse, StatusCode> {
+async fn redirect(
+    State(state): State<AppState>,
+    Path(id): Path<String>,
+) -> Result<impl IntoResponse, StatusCode> {
     let url = state
         .get_url(&id)
         .await
-        .map_err(|_|StatusCode::NOT_FOUND)?;
+        .map_err(|_| StatusCode::NOT_FOUND)?;
     let mut headers = HeaderMap::new();
-    headers.insert(
-        LOCATION,
-        url.parse().unwrap(),
-    );
-    Ok((StatusCode::PERMANENT_REDIRECT, headers ))
+    headers.insert(LOCATION, url.parse().unwrap());
+    Ok((StatusCode::PERMANENT_REDIRECT, headers))
 }
 
 impl AppState {
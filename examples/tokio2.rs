@@ -1,12 +1,12 @@
 // tokio asnyc task send message to worker for expensive blocking task
-use std::{any, thread, time::Duration};
+use std::{thread, time::Duration};
 
 use anyhow::Result;
 use tokio::sync::mpsc;
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    let (tx, mut rx) = mpsc::channel(32);
+    let (tx, rx) = mpsc::channel(32);
 
     let handler = worker(rx);
 
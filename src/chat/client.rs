@@ -0,0 +1,90 @@
+use std::{
+    path::Path,
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use anyhow::{anyhow, Result};
+use futures::{SinkExt, Stream, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpStream, ToSocketAddrs, UnixStream},
+};
+use tokio_util::codec::{Framed, LinesCodec};
+
+use super::{validate_username, Message};
+
+const PROMPT: &str = "Enter your username:";
+
+/// 聊天客户端, 作为 `Stream` 逐条产出服务器广播的 `Message`
+#[derive(Debug)]
+pub struct ChatClient<S = TcpStream> {
+    username: String,
+    stream: Framed<S, LinesCodec>,
+}
+
+impl ChatClient<TcpStream> {
+    pub async fn connect(addr: impl ToSocketAddrs, username: impl Into<String>) -> Result<Self> {
+        let username = checked_username(username.into())?;
+        let stream = TcpStream::connect(addr).await?;
+        Self::handshake(stream, username).await
+    }
+}
+
+impl ChatClient<UnixStream> {
+    pub async fn connect_unix(path: impl AsRef<Path>, username: impl Into<String>) -> Result<Self> {
+        let username = checked_username(username.into())?;
+        let stream = UnixStream::connect(path).await?;
+        Self::handshake(stream, username).await
+    }
+}
+
+impl<S> ChatClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    async fn handshake(stream: S, username: String) -> Result<Self> {
+        let mut stream = Framed::new(stream, LinesCodec::new());
+        // 服务器满了会直接回复原因, 而不是用户名提示
+        match stream.next().await {
+            Some(Ok(line)) if line == PROMPT => {}
+            Some(Ok(line)) => return Err(anyhow!("server refused connection: {}", line)),
+            Some(Err(e)) => return Err(e.into()),
+            None => return Err(anyhow!("connection closed during handshake")),
+        }
+        stream.send(&username).await?;
+        Ok(Self { username, stream })
+    }
+
+    pub fn username(&self) -> &str {
+        &self.username
+    }
+
+    pub async fn send(&mut self, content: impl AsRef<str>) -> Result<()> {
+        self.stream.send(content.as_ref()).await?;
+        Ok(())
+    }
+}
+
+fn checked_username(username: String) -> Result<String> {
+    if !validate_username(&username) {
+        return Err(anyhow!("invalid username: {:?}", username));
+    }
+    Ok(username)
+}
+
+impl<S> Stream for ChatClient<S>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    type Item = Result<Message>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.poll_next_unpin(cx).map(|line| {
+            line.map(|line| match line {
+                Ok(line) => line.parse(),
+                Err(e) => Err(e.into()),
+            })
+        })
+    }
+}
@@ -0,0 +1,98 @@
+use std::{fmt::Display, str::FromStr};
+
+use anyhow::{anyhow, Error};
+
+const JOINED_SUFFIX: &str = " joined the chat";
+const LEFT_SUFFIX: &str = " left the chat";
+
+/// 聊天室里广播的消息, 线上格式是一行文本, 见 `Display` 和 `FromStr`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    UserJoined(String),
+    UserLeft(String),
+    Chat { sender: String, content: String },
+}
+
+impl Message {
+    pub fn user_joined(username: impl Into<String>) -> Self {
+        Self::UserJoined(username.into())
+    }
+
+    pub fn user_left(username: impl Into<String>) -> Self {
+        Self::UserLeft(username.into())
+    }
+
+    // impl Into<String> 更广泛的接收可转换成Into类型的参数
+    pub fn chat(sender: impl Into<String>, content: impl Into<String>) -> Self {
+        Self::Chat {
+            sender: sender.into(),
+            content: content.into(),
+        }
+    }
+
+    pub(crate) fn kind(&self) -> &'static str {
+        match self {
+            Message::UserJoined(_) => "user_joined",
+            Message::UserLeft(_) => "user_left",
+            Message::Chat { .. } => "chat",
+        }
+    }
+}
+
+impl Display for Message {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Message::UserJoined(username) => write!(f, "{}{}", username, JOINED_SUFFIX),
+            Message::UserLeft(username) => write!(f, "{}{}", username, LEFT_SUFFIX),
+            Message::Chat { sender, content } => write!(f, "{}: {}", sender, content),
+        }
+    }
+}
+
+// 用户名里不允许有 ':', 所以第一个 ": " 一定是 chat 消息的分隔符
+impl FromStr for Message {
+    type Err = Error;
+
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        if let Some((sender, content)) = line.split_once(": ") {
+            return Ok(Self::chat(sender, content));
+        }
+        if let Some(username) = line.strip_suffix(JOINED_SUFFIX) {
+            return Ok(Self::user_joined(username));
+        }
+        if let Some(username) = line.strip_suffix(LEFT_SUFFIX) {
+            return Ok(Self::user_left(username));
+        }
+        Err(anyhow!("unrecognized message: {}", line))
+    }
+}
+
+/// 用户名不能为空, 也不能包含 ':', 否则消息无法被客户端解析
+pub fn validate_username(username: &str) -> bool {
+    !username.trim().is_empty() && !username.contains(':')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_should_round_trip_through_display() {
+        let messages = [
+            Message::user_joined("alice"),
+            Message::user_left("alice"),
+            Message::chat("alice", "hello: world"),
+        ];
+        for message in messages {
+            let line = message.to_string();
+            assert_eq!(line.parse::<Message>().unwrap(), message);
+        }
+    }
+
+    #[test]
+    fn username_with_colon_should_be_rejected() {
+        assert!(validate_username("alice"));
+        assert!(!validate_username("a:b"));
+        assert!(!validate_username("  "));
+    }
+}
@@ -0,0 +1,182 @@
+mod client;
+mod message;
+mod plugin;
+mod shortener;
+mod state;
+
+use std::{
+    future, io,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use anyhow::Result;
+use derive_builder::Builder;
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    net::{TcpListener, UnixListener, UnixStream},
+    task::JoinHandle,
+};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
+use tracing::{info, warn};
+
+pub use client::ChatClient;
+pub use message::{validate_username, Message};
+pub use plugin::{ChatPlugin, PluginAction};
+pub use shortener::{HttpShortener, Shortener, UrlShortenerPlugin};
+
+use state::{handle_client, PeerAddr, State};
+
+/// 聊天服务器配置, 通过 `ChatServer::builder()` 构建, 调用 `start` 开始监听
+#[derive(Debug, Clone, Builder)]
+pub struct ChatServer {
+    /// tcp 监听地址, 端口为 0 时由系统分配
+    #[builder(setter(into), default = "\"127.0.0.1:8081\".to_string()")]
+    addr: String,
+    /// 额外监听的 unix domain socket
+    #[builder(setter(into, strip_option), default)]
+    unix: Option<PathBuf>,
+    /// 同时在线的最大 peer 数
+    #[builder(default = "1024")]
+    max_peers: usize,
+    /// 每个 peer 待发送消息队列的长度
+    #[builder(default = "128")]
+    max_messages: usize,
+    /// 单行消息的最大字节数
+    #[builder(default = "4096")]
+    max_line_length: usize,
+    /// 在 chat 消息广播之前调用的插件
+    #[builder(setter(strip_option), default)]
+    plugin: Option<Arc<dyn ChatPlugin>>,
+}
+
+/// 运行中的聊天服务器, drop 不会停止服务, 需要调用 `shutdown`
+#[derive(Debug)]
+pub struct ChatServerHandle {
+    local_addr: SocketAddr,
+    shutdown: CancellationToken,
+    task: JoinHandle<Result<()>>,
+}
+
+impl ChatServer {
+    pub fn builder() -> ChatServerBuilder {
+        ChatServerBuilder::default()
+    }
+
+    pub async fn start(self) -> Result<ChatServerHandle> {
+        let listener = TcpListener::bind(&self.addr).await?;
+        let local_addr = listener.local_addr()?;
+        info!("listening on {}", local_addr);
+        let unix_listener = match &self.unix {
+            Some(path) => {
+                let listener = bind_unix(path)?;
+                info!("listening on {}", path.display());
+                Some(listener)
+            }
+            None => None,
+        };
+
+        let shutdown = CancellationToken::new();
+        let task = tokio::spawn(self.serve(listener, unix_listener, shutdown.clone()));
+        Ok(ChatServerHandle {
+            local_addr,
+            shutdown,
+            task,
+        })
+    }
+
+    async fn serve(
+        self,
+        listener: TcpListener,
+        unix_listener: Option<UnixListener>,
+        shutdown: CancellationToken,
+    ) -> Result<()> {
+        let state = Arc::new(State::new(
+            self.max_peers,
+            self.max_messages,
+            self.plugin.clone(),
+        ));
+        let tracker = TaskTracker::new();
+        let mut next_unix_id = 0;
+
+        loop {
+            // tcp 和 unix socket 的 peer 进入同一个 State, 可以互相聊天
+            tokio::select! {
+                _ = shutdown.cancelled() => break,
+                accepted = listener.accept() => {
+                    let (stream, addr) = accepted?;
+                    self.spawn_client(&tracker, &state, PeerAddr::Tcp(addr), stream, &shutdown);
+                }
+                accepted = accept_unix(unix_listener.as_ref()) => {
+                    next_unix_id += 1;
+                    let addr = PeerAddr::Unix(next_unix_id);
+                    self.spawn_client(&tracker, &state, addr, accepted?, &shutdown);
+                }
+            }
+        }
+
+        drop(listener);
+        drop(unix_listener);
+        tracker.close();
+        tracker.wait().await;
+        if let Some(path) = &self.unix {
+            std::fs::remove_file(path)?;
+        }
+        info!("chat server stopped");
+        Ok(())
+    }
+
+    fn spawn_client<S>(
+        &self,
+        tracker: &TaskTracker,
+        state: &Arc<State>,
+        addr: PeerAddr,
+        stream: S,
+        shutdown: &CancellationToken,
+    ) where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        info!("accepted from {}", addr);
+        let state_cloned = Arc::clone(state);
+        let fut = handle_client(
+            state_cloned,
+            addr,
+            stream,
+            self.max_line_length,
+            shutdown.clone(),
+        );
+        tracker.spawn(async move {
+            if let Err(e) = fut.await {
+                warn!("failed to handle peer: {}", e);
+            }
+        });
+    }
+}
+
+impl ChatServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// 停止接收新连接, 断开所有 peer, 等待所有连接处理完毕
+    pub async fn shutdown(self) -> Result<()> {
+        self.shutdown.cancel();
+        self.task.await?
+    }
+}
+
+fn bind_unix(path: &Path) -> Result<UnixListener> {
+    // 上次运行留下的 socket 文件会导致 bind 失败, 先删掉
+    if path.exists() {
+        std::fs::remove_file(path)?;
+    }
+    Ok(UnixListener::bind(path)?)
+}
+
+async fn accept_unix(listener: Option<&UnixListener>) -> io::Result<UnixStream> {
+    match listener {
+        Some(listener) => listener.accept().await.map(|(stream, _)| stream),
+        None => future::pending().await,
+    }
+}
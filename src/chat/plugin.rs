@@ -0,0 +1,22 @@
+use std::fmt::Debug;
+
+use futures::future::BoxFuture;
+
+/// 插件对一条 chat 消息的处理结果
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PluginAction {
+    /// 用改写后的内容广播给其他 peer
+    Broadcast(String),
+    /// 不广播, 只回复给发送者本人
+    Reply(String),
+}
+
+/// 聊天插件, 在 chat 消息广播之前被调用
+///
+/// 返回 `BoxFuture` 而不是 `async fn`, 这样插件可以作为 `Arc<dyn ChatPlugin>` 注册到 server
+pub trait ChatPlugin: Debug + Send + Sync + 'static {
+    /// 回复消息使用的发送者名字
+    fn name(&self) -> &str;
+
+    fn on_chat<'a>(&'a self, sender: &'a str, content: String) -> BoxFuture<'a, PluginAction>;
+}
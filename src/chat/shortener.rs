@@ -0,0 +1,234 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt::Debug,
+    ops::Range,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures::{future::BoxFuture, FutureExt};
+use serde::{Deserialize, Serialize};
+use tokio::time::timeout;
+use tracing::{info, warn};
+
+use super::{ChatPlugin, PluginAction};
+
+const EXPAND_COMMAND: &str = "/expand";
+// 插件在 peer 的读循环里执行, 缩短服务太慢时保留原始链接
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(2);
+const DEFAULT_MAX_LINKS: usize = 10_000;
+
+/// 把长链接换成短链接的服务
+pub trait Shortener: Debug + Send + Sync + 'static {
+    fn shorten<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String>>;
+}
+
+/// 调用 `examples/shortener.rs` 的 HTTP API: `POST /` `{"url": ...}` 返回 `{"url": <短链接>}`
+#[derive(Debug, Clone)]
+pub struct HttpShortener {
+    endpoint: String,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct ShortenBody {
+    url: String,
+}
+
+/// 自动缩短 chat 消息里的长链接, peer 可以发送 `/expand <短链接>` 查询原始链接
+#[derive(Debug)]
+pub struct UrlShortenerPlugin {
+    shortener: Arc<dyn Shortener>,
+    min_length: usize,
+    timeout: Duration,
+    originals: Mutex<Originals>,
+}
+
+// 短链接 -> 原始链接, 最多保留 max 个, 超过时淘汰最早加入的
+#[derive(Debug)]
+struct Originals {
+    urls: HashMap<String, String>,
+    order: VecDeque<String>,
+    max: usize,
+}
+
+impl HttpShortener {
+    pub fn new(endpoint: impl Into<String>) -> Self {
+        Self {
+            endpoint: endpoint.into(),
+            client: reqwest::Client::new(),
+        }
+    }
+}
+
+impl Shortener for HttpShortener {
+    fn shorten<'a>(&'a self, url: &'a str) -> BoxFuture<'a, Result<String>> {
+        async move {
+            let body: ShortenBody = self
+                .client
+                .post(&self.endpoint)
+                .json(&ShortenBody {
+                    url: url.to_string(),
+                })
+                .send()
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+            Ok(body.url)
+        }
+        .boxed()
+    }
+}
+
+impl UrlShortenerPlugin {
+    /// 只有长度不小于 `min_length` 的链接才会被缩短
+    pub fn new(shortener: Arc<dyn Shortener>, min_length: usize) -> Self {
+        Self {
+            shortener,
+            min_length,
+            timeout: DEFAULT_TIMEOUT,
+            originals: Mutex::new(Originals::new(DEFAULT_MAX_LINKS)),
+        }
+    }
+
+    /// 每个链接等待缩短服务的时间, 默认 2 秒
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// 最多记住多少个短链接用于 `/expand`, 默认 10000
+    pub fn max_links(mut self, max: usize) -> Self {
+        self.originals = Mutex::new(Originals::new(max));
+        self
+    }
+
+    /// 查询短链接对应的原始链接
+    pub fn original(&self, short: &str) -> Option<String> {
+        self.originals.lock().unwrap().urls.get(short).cloned()
+    }
+
+    async fn rewrite(&self, content: String) -> String {
+        let mut rewritten = String::with_capacity(content.len());
+        let mut last = 0;
+        for range in find_urls(&content) {
+            let url = &content[range.clone()];
+            if url.len() < self.min_length {
+                continue;
+            }
+            let short = match timeout(self.timeout, self.shortener.shorten(url)).await {
+                Ok(Ok(short)) => short,
+                // 缩短失败就保留原始链接, 不影响消息发送
+                Ok(Err(e)) => {
+                    warn!("failed to shorten {}: {}", url, e);
+                    continue;
+                }
+                Err(_) => {
+                    warn!("timed out shortening {}", url);
+                    continue;
+                }
+            };
+            info!("shortened {} to {}", url, short);
+            self.originals
+                .lock()
+                .unwrap()
+                .insert(short.clone(), url.to_string());
+            rewritten.push_str(&content[last..range.start]);
+            rewritten.push_str(&short);
+            last = range.end;
+        }
+        rewritten.push_str(&content[last..]);
+        rewritten
+    }
+
+    fn expand(&self, short: &str) -> String {
+        match self.original(short) {
+            Some(url) => format!("{} -> {}", short, url),
+            None => format!("unknown link: {}", short),
+        }
+    }
+}
+
+impl ChatPlugin for UrlShortenerPlugin {
+    fn name(&self) -> &str {
+        "shortener"
+    }
+
+    fn on_chat<'a>(&'a self, _sender: &'a str, content: String) -> BoxFuture<'a, PluginAction> {
+        async move {
+            match content.strip_prefix(EXPAND_COMMAND) {
+                Some(short) if short.is_empty() || short.starts_with(' ') => {
+                    PluginAction::Reply(self.expand(short.trim()))
+                }
+                _ => PluginAction::Broadcast(self.rewrite(content).await),
+            }
+        }
+        .boxed()
+    }
+}
+
+impl Originals {
+    fn new(max: usize) -> Self {
+        Self {
+            urls: HashMap::new(),
+            order: VecDeque::new(),
+            max,
+        }
+    }
+
+    fn insert(&mut self, short: String, url: String) {
+        if self.urls.insert(short.clone(), url).is_some() {
+            return;
+        }
+        self.order.push_back(short);
+        while self.order.len() > self.max {
+            if let Some(oldest) = self.order.pop_front() {
+                self.urls.remove(&oldest);
+            }
+        }
+    }
+}
+
+// 以 http:// 或 https:// 开头, 到空白字符为止
+fn find_urls(content: &str) -> Vec<Range<usize>> {
+    let mut urls = Vec::new();
+    let mut offset = 0;
+    for token in content.split_whitespace() {
+        // split_whitespace 不返回位置, 从上一个 token 之后查找当前 token
+        let start = offset + content[offset..].find(token).unwrap_or(0);
+        offset = start + token.len();
+        if token.starts_with("http://") || token.starts_with("https://") {
+            urls.push(start..offset);
+        }
+    }
+    urls
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn find_urls_should_return_url_ranges() {
+        let content = "see https://example.com/a and\thttp://b.io ok";
+        let urls: Vec<_> = find_urls(content)
+            .into_iter()
+            .map(|r| &content[r])
+            .collect();
+        assert_eq!(urls, ["https://example.com/a", "http://b.io"]);
+    }
+
+    #[test]
+    fn originals_should_evict_oldest_links() {
+        let mut originals = Originals::new(2);
+        originals.insert("s/1".into(), "http://a.io".into());
+        originals.insert("s/2".into(), "http://b.io".into());
+        // 已有的短链接不重复计数
+        originals.insert("s/1".into(), "http://a.io".into());
+        originals.insert("s/3".into(), "http://c.io".into());
+        assert!(!originals.urls.contains_key("s/1"));
+        assert_eq!(originals.urls.len(), 2);
+    }
+}
@@ -0,0 +1,298 @@
+use std::{
+    fmt::Display,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use anyhow::Result;
+use dashmap::DashMap;
+use futures::{stream::SplitStream, SinkExt, StreamExt};
+use tokio::{
+    io::{AsyncRead, AsyncWrite},
+    sync::mpsc,
+    task::JoinHandle,
+};
+use tokio_util::{
+    codec::{Framed, LinesCodec},
+    sync::CancellationToken,
+};
+use tracing::{field, info, info_span, instrument, warn, Instrument as _, Span};
+
+use super::{validate_username, ChatPlugin, Message, PluginAction};
+
+// 目前只有一个聊天室, 先作为span属性记录下来
+pub(crate) const ROOM: &str = "lobby";
+
+#[derive(Debug)]
+pub(crate) struct State {
+    peers: DashMap<PeerAddr, mpsc::Sender<Outgoing>>,
+    // 检查人数和加入 peers 时持有, 并发握手的 peer 加起来也不会超过 max_peers
+    joining: Mutex<()>,
+    max_peers: usize,
+    max_messages: usize,
+    plugin: Option<Arc<dyn ChatPlugin>>,
+}
+
+// peer 的身份: tcp 用对端地址, unix socket 的对端一般是匿名的, 用自增 id 区分
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub(crate) enum PeerAddr {
+    Tcp(SocketAddr),
+    Unix(u64),
+}
+
+// 发送给peer的消息, 附带broadcast的span, 这样每个peer的写入都能挂在同一条trace下
+#[derive(Debug)]
+struct Outgoing {
+    message: Arc<Message>,
+    span: Span,
+}
+
+#[derive(Debug)]
+struct Peer<S> {
+    username: String,
+    stream: SplitStream<Framed<S, LinesCodec>>,
+    writer: JoinHandle<()>,
+}
+
+#[instrument(
+    name = "connection",
+    skip_all,
+    fields(peer.addr = %addr, chat.room = ROOM, chat.username = field::Empty)
+)]
+pub(crate) async fn handle_client<S>(
+    state: Arc<State>,
+    addr: PeerAddr,
+    stream: S,
+    max_line_length: usize,
+    shutdown: CancellationToken,
+) -> Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    // frame 工具, 将字节流按Lines 分隔符进行解析
+    let mut stream = Framed::new(stream, LinesCodec::new_with_max_length(max_line_length));
+
+    // 提前拒绝, 客户端在握手时就能看到原因; 真正的限制在 add 中
+    if state.peers.len() >= state.max_peers {
+        warn!("too many peers, rejecting {}", addr);
+        stream.send("Server is full").await?;
+        return Ok(());
+    }
+
+    let username = tokio::select! {
+        username = handshake(&mut stream) => username?,
+        // server 关闭时不再等待还没输入用户名的 peer
+        _ = shutdown.cancelled() => return Ok(()),
+    };
+    let Some(username) = username else {
+        return Ok(());
+    };
+    if !validate_username(&username) {
+        stream.send("Invalid username").await?;
+        return Ok(());
+    }
+    Span::current().record("chat.username", username.as_str());
+
+    let Some(mut peer) = state.add(addr.clone(), username, stream).await? else {
+        return Ok(());
+    };
+
+    state
+        .broadcast(&addr, Arc::new(Message::user_joined(&peer.username)))
+        .await;
+    info!("{} joined the chat", peer.username);
+
+    loop {
+        let line = tokio::select! {
+            line = peer.stream.next() => line,
+            _ = shutdown.cancelled() => {
+                // server 关闭时直接断开, 不等待还没写出去的消息
+                peer.writer.abort();
+                break;
+            }
+        };
+        let line = match line {
+            Some(Ok(line)) => line,
+            Some(Err(e)) => {
+                warn!("failed to read line from stream: {}", e);
+                break;
+            }
+            None => break,
+        };
+
+        state.on_chat(&addr, &peer.username, line).await;
+    }
+
+    state.peers.remove(&addr);
+
+    let message = Arc::new(Message::user_left(&peer.username));
+    state.broadcast(&addr, message).await;
+    info!("{} left the chat", peer.username);
+
+    Ok(())
+}
+
+#[instrument(skip_all)]
+async fn handshake<S>(stream: &mut Framed<S, LinesCodec>) -> Result<Option<String>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    stream.send("Enter your username:").await?;
+
+    match stream.next().await {
+        Some(Ok(username)) => Ok(Some(username)),
+        Some(Err(e)) => Err(e.into()),
+        None => Ok(None),
+    }
+}
+
+impl State {
+    pub(crate) fn new(
+        max_peers: usize,
+        max_messages: usize,
+        plugin: Option<Arc<dyn ChatPlugin>>,
+    ) -> Self {
+        Self {
+            peers: DashMap::new(),
+            joining: Mutex::new(()),
+            max_peers,
+            max_messages,
+            plugin,
+        }
+    }
+
+    // 有插件时先交给插件处理, 插件可以改写内容, 或者只回复给发送者
+    async fn on_chat(&self, addr: &PeerAddr, username: &str, line: String) {
+        let Some(plugin) = &self.plugin else {
+            let message = Arc::new(Message::chat(username, line));
+            return self.broadcast(addr, message).await;
+        };
+        match plugin.on_chat(username, line).await {
+            PluginAction::Broadcast(content) => {
+                let message = Arc::new(Message::chat(username, content));
+                self.broadcast(addr, message).await;
+            }
+            PluginAction::Reply(content) => {
+                let message = Arc::new(Message::chat(plugin.name(), content));
+                self.reply(addr, message).await;
+            }
+        }
+    }
+
+    async fn reply(&self, addr: &PeerAddr, message: Arc<Message>) {
+        // 先 clone sender, 不要在持有 DashMap 引用的时候 await
+        let Some(sender) = self.peers.get(addr).map(|peer| peer.value().clone()) else {
+            return;
+        };
+        let outgoing = Outgoing {
+            message,
+            span: Span::current(),
+        };
+        if let Err(e) = sender.send(outgoing).await {
+            warn!("failed to reply to {}: {}", addr, e);
+        }
+    }
+
+    #[instrument(
+        skip_all,
+        fields(
+            peer.addr = %addr,
+            chat.room = ROOM,
+            chat.message.kind = message.kind(),
+            chat.fan_out = field::Empty,
+        )
+    )]
+    async fn broadcast(&self, addr: &PeerAddr, message: Arc<Message>) {
+        // 先 clone 所有 sender, 等待发送时不能持有 DashMap 的 shard 锁
+        let peers: Vec<_> = self
+            .peers
+            .iter()
+            .filter(|peer| peer.key() != addr)
+            .map(|peer| (peer.key().clone(), peer.value().clone()))
+            .collect();
+        let mut fan_out = 0;
+        let mut gone = Vec::new();
+        for (peer_addr, sender) in peers {
+            let outgoing = Outgoing {
+                message: Arc::clone(&message),
+                span: Span::current(),
+            };
+            // channel 满了会在这里等待, 慢的peer会体现在这个span上
+            let send = sender
+                .send(outgoing)
+                .instrument(info_span!("peer_send", peer.addr = %peer_addr));
+            if let Err(e) = send.await {
+                warn!("failed to send message to {}: {}", peer_addr, e);
+                gone.push(peer_addr);
+                continue;
+            }
+            fan_out += 1;
+        }
+        // if send failed, peer might be gone, remove peer from state
+        for peer_addr in gone {
+            self.peers.remove(&peer_addr);
+        }
+        Span::current().record("chat.fan_out", fan_out);
+    }
+
+    // 人数已满时回复原因并返回 None
+    async fn add<S>(
+        &self,
+        addr: PeerAddr,
+        username: String,
+        mut stream: Framed<S, LinesCodec>,
+    ) -> Result<Option<Peer<S>>>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let joined = {
+            let _joining = self.joining.lock().unwrap();
+            (self.peers.len() < self.max_peers).then(|| {
+                let (tx, rx) = mpsc::channel::<Outgoing>(self.max_messages);
+                self.peers.insert(addr.clone(), tx);
+                rx
+            })
+        };
+        let Some(mut rx) = joined else {
+            warn!("too many peers, rejecting {}", addr);
+            stream.send("Server is full").await?;
+            return Ok(None);
+        };
+
+        let (mut stream_sender, stream_receiver) = stream.split();
+
+        // recieve messages from the peer and broadcast them to all other peers
+        let peer_username = username.clone();
+        let writer = tokio::spawn(async move {
+            while let Some(Outgoing { message, span }) = rx.recv().await {
+                let span = info_span!(
+                    parent: &span,
+                    "peer_write",
+                    peer.addr = %addr,
+                    chat.username = %peer_username,
+                    chat.room = ROOM,
+                );
+                let write = stream_sender.send(message.to_string()).instrument(span);
+                if let Err(e) = write.await {
+                    warn!("failed to send message to {}: {}", addr, e);
+                }
+            }
+        });
+
+        // return peer
+        Ok(Some(Peer {
+            username,
+            stream: stream_receiver,
+            writer,
+        }))
+    }
+}
+
+impl Display for PeerAddr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PeerAddr::Tcp(addr) => write!(f, "{}", addr),
+            PeerAddr::Unix(id) => write!(f, "unix#{}", id),
+        }
+    }
+}
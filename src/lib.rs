@@ -1 +1,2 @@
-// empty file
+pub mod chat;
+pub mod minginx;
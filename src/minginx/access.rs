@@ -0,0 +1,214 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    net::IpAddr,
+    sync::{Arc, Mutex},
+};
+
+use tokio::time::Instant;
+
+use super::{AccessConfig, RateLimitConfig};
+
+// 令牌桶超过这个数量时清理已经补满的桶
+const MAX_BUCKETS: usize = 4096;
+
+/// 连接被拒绝的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Denied {
+    NotAllowed,
+    TooManyConnections,
+    TooManyConnectionsFromIp,
+    RateLimited,
+}
+
+/// 一个 listener 上的连接数和每个 IP 的令牌桶, reload 不会清零
+#[derive(Debug, Default)]
+pub(crate) struct ConnectionLimiter {
+    state: Mutex<State>,
+}
+
+/// 通过检查的连接, drop 时减掉连接数
+#[derive(Debug)]
+pub(crate) struct Permit {
+    limiter: Arc<ConnectionLimiter>,
+    ip: IpAddr,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    total: usize,
+    per_ip: HashMap<IpAddr, usize>,
+    buckets: HashMap<IpAddr, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl fmt::Display for Denied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NotAllowed => "not allowed",
+            Self::TooManyConnections => "too many connections",
+            Self::TooManyConnectionsFromIp => "too many connections from this IP",
+            Self::RateLimited => "new connections rate limited",
+        })
+    }
+}
+
+impl ConnectionLimiter {
+    /// 依次检查 deny/allow, 总连接数, 这个 IP 的连接数和新建连接速率
+    pub(crate) fn check(
+        self: &Arc<Self>,
+        config: &AccessConfig,
+        ip: IpAddr,
+    ) -> Result<Permit, Denied> {
+        // IPv4 客户端连到双栈 socket 时是 ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        if config.deny.iter().any(|net| net.contains(&ip))
+            || (!config.allow.is_empty() && !config.allow.iter().any(|net| net.contains(&ip)))
+        {
+            return Err(Denied::NotAllowed);
+        }
+
+        let mut state = self.state.lock().unwrap();
+        if config.max_connections > 0 && state.total >= config.max_connections {
+            return Err(Denied::TooManyConnections);
+        }
+        let active = state.per_ip.get(&ip).copied().unwrap_or(0);
+        if config.max_connections_per_ip > 0 && active >= config.max_connections_per_ip {
+            return Err(Denied::TooManyConnectionsFromIp);
+        }
+        if let Some(rate) = &config.rate_limit_per_ip {
+            if !state.take_token(ip, rate) {
+                return Err(Denied::RateLimited);
+            }
+        }
+
+        // 不配置限制时同样计数, reload 打开限制后对已有的连接也生效
+        state.total += 1;
+        *state.per_ip.entry(ip).or_default() += 1;
+        Ok(Permit {
+            limiter: Arc::clone(self),
+            ip,
+        })
+    }
+}
+
+impl State {
+    fn take_token(&mut self, ip: IpAddr, rate: &RateLimitConfig) -> bool {
+        let now = Instant::now();
+        let (per_second, burst) = (rate.per_second as f64, rate.burst as f64);
+        if self.buckets.len() >= MAX_BUCKETS {
+            self.buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * per_second
+                    < burst
+            });
+        }
+        let bucket = self.buckets.entry(ip).or_insert(Bucket {
+            tokens: burst,
+            updated: now,
+        });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * per_second).min(burst);
+        bucket.updated = now;
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut state = self.limiter.state.lock().unwrap();
+        state.total -= 1;
+        if let Some(count) = state.per_ip.get_mut(&self.ip) {
+            *count -= 1;
+            if *count == 0 {
+                state.per_ip.remove(&self.ip);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn deny_should_take_precedence_over_allow() {
+        let limiter = Arc::new(ConnectionLimiter::default());
+        let config = AccessConfig {
+            allow: vec!["10.0.0.0/8".parse().unwrap()],
+            deny: vec!["10.0.1.0/24".parse().unwrap()],
+            ..Default::default()
+        };
+        assert!(limiter.check(&config, ip("10.0.0.1")).is_ok());
+        assert!(limiter.check(&config, ip("::ffff:10.0.0.1")).is_ok());
+        assert_eq!(
+            limiter.check(&config, ip("10.0.1.1")).unwrap_err(),
+            Denied::NotAllowed
+        );
+        assert_eq!(
+            limiter.check(&config, ip("192.168.0.1")).unwrap_err(),
+            Denied::NotAllowed
+        );
+    }
+
+    #[test]
+    fn connection_caps_should_be_released_on_drop() {
+        let limiter = Arc::new(ConnectionLimiter::default());
+        let config = AccessConfig {
+            max_connections: 3,
+            max_connections_per_ip: 2,
+            ..Default::default()
+        };
+        let a1 = limiter.check(&config, ip("10.0.0.1")).unwrap();
+        let _a2 = limiter.check(&config, ip("10.0.0.1")).unwrap();
+        assert_eq!(
+            limiter.check(&config, ip("10.0.0.1")).unwrap_err(),
+            Denied::TooManyConnectionsFromIp
+        );
+        let _b1 = limiter.check(&config, ip("10.0.0.2")).unwrap();
+        assert_eq!(
+            limiter.check(&config, ip("10.0.0.3")).unwrap_err(),
+            Denied::TooManyConnections
+        );
+        drop(a1);
+        assert!(limiter.check(&config, ip("10.0.0.1")).is_ok());
+    }
+
+    #[tokio::test]
+    async fn rate_limit_should_allow_burst_then_refill() {
+        let limiter = Arc::new(ConnectionLimiter::default());
+        let config = AccessConfig {
+            rate_limit_per_ip: Some(RateLimitConfig {
+                per_second: 20,
+                burst: 3,
+            }),
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            assert!(limiter.check(&config, ip("10.0.0.1")).is_ok());
+        }
+        assert_eq!(
+            limiter.check(&config, ip("10.0.0.1")).unwrap_err(),
+            Denied::RateLimited
+        );
+        // 其他 IP 有自己的桶
+        assert!(limiter.check(&config, ip("10.0.0.2")).is_ok());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(limiter.check(&config, ip("10.0.0.1")).is_ok());
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    fmt,
+    fs::OpenOptions,
+    io::{self, Write},
+    net::SocketAddr,
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use serde_json::Value;
+use tracing::warn;
+use tracing_appender::non_blocking::{NonBlocking, WorkerGuard};
+
+use super::{AccessLogConfig, AccessLogFormat, CloseReason};
+
+/// 一个连接的访问日志, 字段名同时也是模板里的变量名
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct AccessRecord {
+    /// 连接结束的时间
+    pub(crate) time: DateTime<Utc>,
+    pub(crate) client: SocketAddr,
+    pub(crate) listener: SocketAddr,
+    pub(crate) upstream: String,
+    /// 没有连上任何 backend 时为空
+    pub(crate) backend: Option<String>,
+    pub(crate) bytes_in: u64,
+    pub(crate) bytes_out: u64,
+    pub(crate) duration_ms: u64,
+    pub(crate) reason: CloseReason,
+}
+
+// 日志在后台线程写入, 不阻塞转发
+pub(crate) struct AccessLog {
+    format: AccessLogFormat,
+    writer: NonBlocking,
+    _guard: WorkerGuard,
+}
+
+impl AccessLog {
+    pub(crate) fn new(config: &AccessLogConfig) -> Result<Self> {
+        let writer: Box<dyn Write + Send> = match &config.path {
+            Some(path) => Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .with_context(|| format!("failed to open {}", path.display()))?,
+            ),
+            None => Box::new(io::stdout()),
+        };
+        let (writer, guard) = tracing_appender::non_blocking(writer);
+        Ok(Self {
+            format: config.format.clone(),
+            writer,
+            _guard: guard,
+        })
+    }
+
+    pub(crate) fn write(&self, record: &AccessRecord) {
+        let mut line = render(&self.format, record);
+        line.push('\n');
+        // 一次 write 对应一行, 不会和其他连接的日志交错
+        if let Err(e) = self.writer.clone().write_all(line.as_bytes()) {
+            warn!("Failed to write access log: {}", e);
+        }
+    }
+}
+
+impl fmt::Debug for AccessLog {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AccessLog")
+            .field("format", &self.format)
+            .finish()
+    }
+}
+
+// 模板中的 `$name` 替换成对应字段, 不认识的变量原样保留, 空值输出 `-`
+fn render(format: &AccessLogFormat, record: &AccessRecord) -> String {
+    let AccessLogFormat::Template(template) = format else {
+        return serde_json::to_string(record).unwrap_or_default();
+    };
+    let Ok(Value::Object(fields)) = serde_json::to_value(record) else {
+        return template.clone();
+    };
+    expand(template, |name| {
+        fields.get(name).map(|value| match value {
+            Value::String(s) => s.clone(),
+            Value::Null => "-".into(),
+            value => value.to_string(),
+        })
+    })
+}
+
+/// 把模板中的 `$name` 替换成 `lookup` 返回的值, 返回 None 的变量原样保留
+pub(crate) fn expand(template: &str, lookup: impl Fn(&str) -> Option<String>) -> String {
+    let mut out = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(pos) = rest.find('$') {
+        out.push_str(&rest[..pos]);
+        rest = &rest[pos + 1..];
+        let len = rest
+            .find(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+            .unwrap_or(rest.len());
+        let (name, tail) = rest.split_at(len);
+        match lookup(name) {
+            Some(value) => out.push_str(&value),
+            None => {
+                out.push('$');
+                out.push_str(name);
+            }
+        }
+        rest = tail;
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn record() -> AccessRecord {
+        AccessRecord {
+            time: "2024-01-02T03:04:05Z".parse().unwrap(),
+            client: "10.0.0.1:5000".parse().unwrap(),
+            listener: "127.0.0.1:3000".parse().unwrap(),
+            upstream: "web".into(),
+            backend: None,
+            bytes_in: 12,
+            bytes_out: 345,
+            duration_ms: 67,
+            reason: CloseReason::NoUpstream,
+        }
+    }
+
+    #[test]
+    fn json_should_contain_all_fields() {
+        let line = render(&AccessLogFormat::Json, &record());
+        let value: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(value["client"], "10.0.0.1:5000");
+        assert_eq!(value["backend"], Value::Null);
+        assert_eq!(value["bytes_out"], 345);
+        assert_eq!(value["reason"], "no_upstream");
+    }
+
+    #[test]
+    fn template_should_replace_variables() {
+        let format = AccessLogFormat::Template(
+            "$time $client -> $upstream/$backend in=$bytes_in out=$bytes_out $reason $unknown$"
+                .into(),
+        );
+        assert_eq!(
+            render(&format, &record()),
+            "2024-01-02T03:04:05Z 10.0.0.1:5000 -> web/- in=12 out=345 no_upstream $unknown$"
+        );
+    }
+}
@@ -0,0 +1,249 @@
+use std::{collections::BTreeMap, sync::Arc};
+
+use anyhow::{Context, Result};
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    routing::{delete, get, post, put},
+    Json, Router,
+};
+use serde::{Deserialize, Serialize};
+use tokio::net::TcpListener;
+use tracing::info;
+
+use super::{
+    reload::Runtime, sessions::SessionInfo, BackendState, BackendStats, CircuitStats, Config,
+    FaultConfig, ServerConfig, TrafficStats, UpstreamConfig,
+};
+
+type AdminResult<T> = Result<T, (StatusCode, String)>;
+
+#[derive(Debug, Serialize)]
+struct AdminStats {
+    #[serde(flatten)]
+    traffic: TrafficStats,
+    upstreams: BTreeMap<String, Vec<BackendStats>>,
+    /// 配置了熔断的 upstream
+    circuit_breakers: BTreeMap<String, CircuitStats>,
+    /// 启动以来抓包记录的字节数
+    captured_bytes: u64,
+}
+
+#[derive(Debug, Deserialize)]
+struct SetState {
+    state: BackendState,
+}
+
+/// admin 接口:
+/// - `GET /stats` 累计的流量统计, 每个 backend 和熔断器的状态, 抓包记录的字节数
+/// - `GET /upstreams` 每个 upstream 中 backend 的健康状态、连接数和流量
+/// - `POST /upstreams/:name/backends` 添加 backend, body 和配置中的 server 相同
+/// - `DELETE /upstreams/:name/backends/:addr` 删除 backend, 已有的连接继续转发
+/// - `PUT /upstreams/:name/backends/:addr/state` 设置 active / draining / disabled
+/// - `GET /sessions` 正在处理的连接, `DELETE /sessions/:id` 关闭一个连接
+/// - `GET /listeners/:index/faults` 第 index 个 listener 的故障注入配置,
+///   `PUT` 整体替换, 只影响之后的新连接
+///
+/// 对 backend 的增删不会写回配置文件, 之后从文件 reload 时以文件为准
+pub(crate) async fn serve(listener: TcpListener, runtime: Arc<Runtime>) -> Result<()> {
+    let app = Router::new()
+        .route("/stats", get(stats))
+        .route("/upstreams", get(upstreams))
+        .route("/upstreams/:name/backends", post(add_backend))
+        .route("/upstreams/:name/backends/:addr", delete(remove_backend))
+        .route("/upstreams/:name/backends/:addr/state", put(set_state))
+        .route("/sessions", get(sessions))
+        .route("/sessions/:id", delete(kill_session))
+        .route("/listeners/:index/faults", get(faults).put(set_faults))
+        .with_state(runtime);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn stats(State(runtime): State<Arc<Runtime>>) -> Json<AdminStats> {
+    Json(AdminStats {
+        traffic: runtime.traffic().snapshot(),
+        upstreams: upstream_stats(&runtime),
+        circuit_breakers: runtime
+            .current()
+            .pools
+            .iter()
+            .filter_map(|(name, pool)| Some((name.clone(), pool.circuit_breaker()?.stats())))
+            .collect(),
+        captured_bytes: runtime.captures().total(),
+    })
+}
+
+async fn upstreams(
+    State(runtime): State<Arc<Runtime>>,
+) -> Json<BTreeMap<String, Vec<BackendStats>>> {
+    Json(upstream_stats(&runtime))
+}
+
+async fn add_backend(
+    State(runtime): State<Arc<Runtime>>,
+    Path(name): Path<String>,
+    Json(server): Json<ServerConfig>,
+) -> AdminResult<StatusCode> {
+    let servers = servers(&runtime, &name)?;
+    if servers.contains(&server.addr) {
+        return Err((
+            StatusCode::CONFLICT,
+            format!("backend {} already exists", server.addr),
+        ));
+    }
+    let addr = server.addr.clone();
+    runtime
+        .update(|config| {
+            upstream_mut(config, &name)?.servers.push(server);
+            Ok(())
+        })
+        .map_err(bad_request)?;
+    info!("Added backend {} to upstream {}", addr, name);
+    Ok(StatusCode::CREATED)
+}
+
+async fn remove_backend(
+    State(runtime): State<Arc<Runtime>>,
+    Path((name, addr)): Path<(String, String)>,
+) -> AdminResult<StatusCode> {
+    if !servers(&runtime, &name)?.contains(&addr) {
+        return Err(backend_not_found(&name, &addr));
+    }
+    runtime
+        .update(|config| {
+            let upstream = upstream_mut(config, &name)?;
+            upstream.servers.retain(|server| server.addr != addr);
+            Ok(())
+        })
+        .map_err(bad_request)?;
+    info!("Removed backend {} from upstream {}", addr, name);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn set_state(
+    State(runtime): State<Arc<Runtime>>,
+    Path((name, addr)): Path<(String, String)>,
+    Json(SetState { state }): Json<SetState>,
+) -> AdminResult<StatusCode> {
+    let snapshot = runtime.current();
+    let pool = snapshot
+        .pools
+        .get(&name)
+        .ok_or_else(|| backend_not_found(&name, &addr))?;
+    let backend = pool
+        .backends()
+        .iter()
+        .find(|b| b.addr() == addr)
+        .ok_or_else(|| backend_not_found(&name, &addr))?;
+    backend.set_state(state);
+    if state == BackendState::Disabled {
+        // HTTP 模式的隧道由 backend 的通知关闭, 这里丢掉空闲连接
+        pool.idle_connections().remove(&addr);
+        let killed = runtime.sessions().kill_backend(&name, &addr);
+        info!("Closed {} sessions on disabled backend {}", killed, addr);
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+async fn sessions(State(runtime): State<Arc<Runtime>>) -> Json<Vec<SessionInfo>> {
+    Json(runtime.sessions().list())
+}
+
+async fn kill_session(
+    State(runtime): State<Arc<Runtime>>,
+    Path(id): Path<u64>,
+) -> AdminResult<StatusCode> {
+    match runtime.sessions().kill(id) {
+        true => Ok(StatusCode::NO_CONTENT),
+        false => Err((StatusCode::NOT_FOUND, format!("session {} not found", id))),
+    }
+}
+
+async fn faults(
+    State(runtime): State<Arc<Runtime>>,
+    Path(index): Path<usize>,
+) -> AdminResult<Json<Vec<FaultConfig>>> {
+    let snapshot = runtime.current();
+    let listener = snapshot
+        .config
+        .listeners
+        .get(index)
+        .ok_or_else(|| listener_not_found(index))?;
+    Ok(Json(listener.faults.clone()))
+}
+
+async fn set_faults(
+    State(runtime): State<Arc<Runtime>>,
+    Path(index): Path<usize>,
+    Json(faults): Json<Vec<FaultConfig>>,
+) -> AdminResult<StatusCode> {
+    if index >= runtime.current().config.listeners.len() {
+        return Err(listener_not_found(index));
+    }
+    let n = faults.len();
+    runtime
+        .update(|config| {
+            // listener 不能通过 reload 增删, 检查之后不会变
+            config.listeners[index].faults = faults;
+            Ok(())
+        })
+        .map_err(bad_request)?;
+    info!("Set {} faults on listeners[{}]", n, index);
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn upstream_stats(runtime: &Runtime) -> BTreeMap<String, Vec<BackendStats>> {
+    runtime
+        .current()
+        .pools
+        .iter()
+        .map(|(name, pool)| {
+            let backends = pool.backends().iter().map(|b| b.stats()).collect();
+            (name.clone(), backends)
+        })
+        .collect()
+}
+
+// upstream 当前配置中的 backend 地址
+fn servers(runtime: &Runtime, name: &str) -> AdminResult<Vec<String>> {
+    let snapshot = runtime.current();
+    let upstream = snapshot.config.upstreams.get(name).ok_or_else(|| {
+        (
+            StatusCode::NOT_FOUND,
+            format!("upstream {} not found", name),
+        )
+    })?;
+    Ok(upstream
+        .servers
+        .iter()
+        .map(|server| server.addr.clone())
+        .collect())
+}
+
+// 检查之后 upstream 可能已经被配置文件的 reload 删掉了
+fn upstream_mut<'a>(config: &'a mut Config, name: &str) -> Result<&'a mut UpstreamConfig> {
+    config
+        .upstreams
+        .get_mut(name)
+        .with_context(|| format!("upstream {} not found", name))
+}
+
+fn backend_not_found(name: &str, addr: &str) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        format!("backend {} not found in upstream {}", addr, name),
+    )
+}
+
+fn listener_not_found(index: usize) -> (StatusCode, String) {
+    (
+        StatusCode::NOT_FOUND,
+        format!("listener {} not found", index),
+    )
+}
+
+// 新配置没有通过校验, 比如删掉了最后一个 backend
+fn bad_request(e: anyhow::Error) -> (StatusCode, String) {
+    (StatusCode::BAD_REQUEST, format!("{:#}", e))
+}
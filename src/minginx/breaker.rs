@@ -0,0 +1,312 @@
+use std::{
+    future::Future,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use serde::Serialize;
+use tokio::time::{sleep, Instant};
+use tracing::{info, warn};
+
+use super::{CircuitBreakerConfig, CloseReason, Transfer};
+
+/// 熔断器的状态
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitState {
+    /// 正常放行
+    Closed,
+    /// 拒绝所有新连接
+    Open,
+    /// 放行几个试探连接, 按结果关闭或者重新打开
+    HalfOpen,
+}
+
+/// 熔断器当前的状态和累计计数
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct CircuitStats {
+    pub state: CircuitState,
+    /// 打开的次数, 包括半开之后重新打开
+    pub opened: u64,
+    /// 被拒绝的连接数
+    pub rejected: u64,
+}
+
+/// 一个 upstream 的熔断器, reload 时配置没变就沿用, 保留状态
+#[derive(Debug)]
+pub struct CircuitBreaker {
+    upstream: String,
+    config: CircuitBreakerConfig,
+    inner: Mutex<Inner>,
+    opened: AtomicU64,
+    rejected: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Inner {
+    state: CircuitState,
+    // 每次切换状态加一, 切换之前放行的连接的结果不再计入
+    generation: u64,
+    // closed 时的连续失败次数
+    failures: u32,
+    // half-open 时放行的试探连接数, 以及其中成功的数
+    trials: u32,
+    successes: u32,
+    opened_at: Instant,
+}
+
+/// 熔断器打开, 拒绝新连接
+#[derive(Debug)]
+pub(crate) struct Rejected;
+
+/// 被放行的一个连接, 结束前报告结果. 没有报告就 drop 时不计入, 只归还试探名额
+#[derive(Debug)]
+pub(crate) struct Attempt {
+    breaker: Arc<CircuitBreaker>,
+    generation: u64,
+    done: bool,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(upstream: impl Into<String>, config: CircuitBreakerConfig) -> Self {
+        Self {
+            upstream: upstream.into(),
+            config,
+            inner: Mutex::new(Inner {
+                state: CircuitState::Closed,
+                generation: 0,
+                failures: 0,
+                trials: 0,
+                successes: 0,
+                opened_at: Instant::now(),
+            }),
+            opened: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    pub fn config(&self) -> &CircuitBreakerConfig {
+        &self.config
+    }
+
+    pub fn state(&self) -> CircuitState {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        inner.state
+    }
+
+    pub fn stats(&self) -> CircuitStats {
+        CircuitStats {
+            state: self.state(),
+            opened: self.opened.load(Ordering::Relaxed),
+            rejected: self.rejected.load(Ordering::Relaxed),
+        }
+    }
+
+    /// 为一个新连接申请放行, 打开时或者半开的试探名额用完时返回 None
+    pub(crate) fn acquire(self: &Arc<Self>) -> Option<Attempt> {
+        let mut inner = self.inner.lock().unwrap();
+        self.refresh(&mut inner);
+        let allowed = match inner.state {
+            CircuitState::Closed => true,
+            CircuitState::Open => false,
+            CircuitState::HalfOpen => {
+                let allowed = inner.trials < self.config.half_open_requests;
+                inner.trials += allowed as u32;
+                allowed
+            }
+        };
+        if !allowed {
+            self.rejected.fetch_add(1, Ordering::Relaxed);
+            return None;
+        }
+        Some(Attempt {
+            breaker: Arc::clone(self),
+            generation: inner.generation,
+            done: false,
+        })
+    }
+
+    // 打开超过 open_duration 之后进入半开, 在访问时检查, 不需要定时器
+    fn refresh(&self, inner: &mut Inner) {
+        if inner.state == CircuitState::Open
+            && inner.opened_at.elapsed() >= self.config.open_duration
+        {
+            self.transition(inner, CircuitState::HalfOpen);
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, state: CircuitState) {
+        inner.state = state;
+        inner.generation += 1;
+        inner.failures = 0;
+        inner.trials = 0;
+        inner.successes = 0;
+        match state {
+            CircuitState::Open => {
+                inner.opened_at = Instant::now();
+                self.opened.fetch_add(1, Ordering::Relaxed);
+                warn!(
+                    "Circuit breaker of upstream {} is open, rejecting new connections for {:?}",
+                    self.upstream, self.config.open_duration
+                );
+            }
+            CircuitState::HalfOpen => info!(
+                "Circuit breaker of upstream {} is half-open, trying {} connections",
+                self.upstream, self.config.half_open_requests
+            ),
+            CircuitState::Closed => {
+                info!("Circuit breaker of upstream {} is closed", self.upstream)
+            }
+        }
+    }
+
+    // ok 为 None 表示结果未知, 不计入
+    fn record(&self, generation: u64, ok: Option<bool>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.generation != generation {
+            return;
+        }
+        match (inner.state, ok) {
+            (CircuitState::Closed, Some(true)) => inner.failures = 0,
+            (CircuitState::Closed, Some(false)) => {
+                inner.failures += 1;
+                if inner.failures >= self.config.failure_threshold {
+                    self.transition(&mut inner, CircuitState::Open);
+                }
+            }
+            (CircuitState::HalfOpen, Some(true)) => {
+                inner.successes += 1;
+                if inner.successes >= self.config.half_open_requests {
+                    self.transition(&mut inner, CircuitState::Closed);
+                }
+            }
+            (CircuitState::HalfOpen, Some(false)) => {
+                self.transition(&mut inner, CircuitState::Open)
+            }
+            (CircuitState::HalfOpen, None) => inner.trials -= 1,
+            _ => {}
+        }
+    }
+}
+
+impl Attempt {
+    /// 运行连接建立之后的 session: early_reset 之内出错结束算失败, 超过之后算成功
+    pub(crate) async fn observe(self, session: impl Future<Output = Transfer>) -> Transfer {
+        let early_reset = self.breaker.config.early_reset;
+        tokio::pin!(session);
+        tokio::select! {
+            transfer = &mut session => {
+                match transfer.reason {
+                    CloseReason::Error => self.failure(),
+                    _ => self.success(),
+                }
+                transfer
+            }
+            _ = sleep(early_reset) => {
+                self.success();
+                session.await
+            }
+        }
+    }
+
+    pub(crate) fn success(self) {
+        self.report(true)
+    }
+
+    pub(crate) fn failure(self) {
+        self.report(false)
+    }
+
+    fn report(mut self, ok: bool) {
+        self.done = true;
+        self.breaker.record(self.generation, Some(ok));
+    }
+}
+
+impl Drop for Attempt {
+    fn drop(&mut self) {
+        if !self.done {
+            self.breaker.record(self.generation, None);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn breaker(open_duration: Duration) -> Arc<CircuitBreaker> {
+        Arc::new(CircuitBreaker::new(
+            "web",
+            CircuitBreakerConfig {
+                failure_threshold: 3,
+                open_duration,
+                half_open_requests: 2,
+                ..Default::default()
+            },
+        ))
+    }
+
+    #[test]
+    fn consecutive_failures_should_open_the_circuit() {
+        let breaker = breaker(Duration::from_secs(60));
+        breaker.acquire().unwrap().failure();
+        breaker.acquire().unwrap().failure();
+        // 成功之后重新计数
+        breaker.acquire().unwrap().success();
+        breaker.acquire().unwrap().failure();
+        breaker.acquire().unwrap().failure();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+
+        // 打开之前放行的连接, 结果不再计入
+        let late = breaker.acquire().unwrap();
+        breaker.acquire().unwrap().failure();
+        assert_eq!(breaker.state(), CircuitState::Open);
+        late.success();
+        assert!(breaker.acquire().is_none());
+        assert_eq!(
+            breaker.stats(),
+            CircuitStats {
+                state: CircuitState::Open,
+                opened: 1,
+                rejected: 1,
+            }
+        );
+    }
+
+    #[test]
+    fn half_open_should_limit_trials_and_close_after_successes() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..3 {
+            breaker.acquire().unwrap().failure();
+        }
+        // open_duration 为 0, 下一次访问时就进入半开
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        let first = breaker.acquire().unwrap();
+        let second = breaker.acquire().unwrap();
+        assert!(breaker.acquire().is_none());
+
+        // 没有报告结果的试探连接归还名额
+        drop(second);
+        let second = breaker.acquire().unwrap();
+        first.success();
+        assert_eq!(breaker.state(), CircuitState::HalfOpen);
+        second.success();
+        assert_eq!(breaker.state(), CircuitState::Closed);
+    }
+
+    #[test]
+    fn failed_trial_should_reopen_the_circuit() {
+        let breaker = breaker(Duration::ZERO);
+        for _ in 0..3 {
+            breaker.acquire().unwrap().failure();
+        }
+        breaker.acquire().unwrap().failure();
+        assert_eq!(breaker.stats().opened, 2);
+    }
+}
@@ -0,0 +1,483 @@
+use std::{
+    cell::Cell,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::{
+    fs::{self, File},
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufWriter, ReadBuf},
+    net::TcpStream,
+    sync::mpsc::{self, error::TrySendError},
+    time::{sleep_until, timeout, Instant},
+};
+use tokio_util::task::TaskTracker;
+use tracing::{info, warn};
+
+use super::CaptureConfig;
+
+// 文件格式: 第一行是 JSON 格式的 CaptureHeader, 之后每条记录依次是
+// 方向 1 字节, 距离开始的微秒数 u64, 数据长度 u32 (都是大端), 数据. 长度为 0 表示这个方向结束
+const CLIENT_TO_UPSTREAM: u8 = b'>';
+const UPSTREAM_TO_CLIENT: u8 = b'<';
+const RECORD_HEADER: usize = 1 + 8 + 4;
+// 等待写入文件的记录数, 满了之后停止抓包
+const CAPTURE_QUEUE: usize = 256;
+
+/// 抓包文件的第一行
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CaptureHeader {
+    pub session: u64,
+    pub start: DateTime<Utc>,
+    pub client: SocketAddr,
+    pub listener: SocketAddr,
+    pub upstream: String,
+    pub backend: String,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    ClientToUpstream,
+    UpstreamToClient,
+}
+
+/// 一次读到或者写出的数据, 为空表示这个方向结束
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureRecord {
+    pub direction: Direction,
+    /// 距离连接开始的时间
+    pub offset: Duration,
+    pub data: Bytes,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CaptureFile {
+    pub header: CaptureHeader,
+    pub records: Vec<CaptureRecord>,
+}
+
+#[derive(Debug, Error)]
+pub enum CaptureError {
+    #[error("invalid capture header: {0}")]
+    InvalidHeader(#[from] serde_json::Error),
+    #[error("invalid direction {0:#04x}")]
+    InvalidDirection(u8),
+    #[error("capture file is truncated")]
+    Truncated,
+}
+
+/// 重放的选项
+#[derive(Debug, Clone)]
+pub struct ReplayOptions {
+    /// 按抓包时的间隔发送客户端的数据, 否则一次发完
+    pub keep_timing: bool,
+    /// 客户端的数据发完之后, upstream 这么久没有发送数据就结束
+    pub idle_timeout: Duration,
+}
+
+/// 抓包时 upstream 的回复和重放时收到的回复
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayReport {
+    pub expected: Vec<u8>,
+    pub actual: Vec<u8>,
+}
+
+/// 启动以来抓包记录的字节数和所有写文件的任务, reload 不会清零
+#[derive(Debug, Default)]
+pub(crate) struct Captures {
+    total: AtomicU64,
+    writers: TaskTracker,
+}
+
+// 把一个连接的数据发给写文件的任务. 只放进队列, 从不等待; 写不过来时停止抓包, 不影响转发
+#[derive(Debug)]
+pub(crate) struct Recorder {
+    session: u64,
+    // 停止抓包之后为 None
+    tx: Option<mpsc::Sender<Bytes>>,
+    start: Instant,
+    // 这个连接还能记录的字节数
+    remaining: u64,
+    captures: Arc<Captures>,
+    max_total: u64,
+}
+
+// 读写时把数据交给 recorder 的客户端连接, 没有 recorder 时只是转发
+#[derive(Debug)]
+pub(crate) struct Captured<S> {
+    inner: S,
+    recorder: Option<Recorder>,
+}
+
+impl Direction {
+    fn byte(self) -> u8 {
+        match self {
+            Self::ClientToUpstream => CLIENT_TO_UPSTREAM,
+            Self::UpstreamToClient => UPSTREAM_TO_CLIENT,
+        }
+    }
+}
+
+impl CaptureFile {
+    pub fn parse(mut data: &[u8]) -> Result<Self, CaptureError> {
+        let line = data
+            .iter()
+            .position(|&b| b == b'\n')
+            .ok_or(CaptureError::Truncated)?;
+        let header = serde_json::from_slice(&data[..line])?;
+        data.advance(line + 1);
+
+        let mut records = Vec::new();
+        while data.has_remaining() {
+            if data.remaining() < RECORD_HEADER {
+                return Err(CaptureError::Truncated);
+            }
+            let direction = match data.get_u8() {
+                CLIENT_TO_UPSTREAM => Direction::ClientToUpstream,
+                UPSTREAM_TO_CLIENT => Direction::UpstreamToClient,
+                other => return Err(CaptureError::InvalidDirection(other)),
+            };
+            let offset = Duration::from_micros(data.get_u64());
+            let len = data.get_u32() as usize;
+            if data.remaining() < len {
+                return Err(CaptureError::Truncated);
+            }
+            records.push(CaptureRecord {
+                direction,
+                offset,
+                data: data.copy_to_bytes(len),
+            });
+        }
+        Ok(Self { header, records })
+    }
+
+    pub async fn read(path: impl AsRef<Path>) -> Result<Self> {
+        let path = path.as_ref();
+        let data = fs::read(path)
+            .await
+            .with_context(|| format!("read {}", path.display()))?;
+        Self::parse(&data).with_context(|| format!("parse {}", path.display()))
+    }
+
+    /// 一个方向的全部数据
+    pub fn data(&self, direction: Direction) -> Vec<u8> {
+        self.records
+            .iter()
+            .filter(|record| record.direction == direction)
+            .flat_map(|record| record.data.iter().copied())
+            .collect()
+    }
+}
+
+impl Default for ReplayOptions {
+    fn default() -> Self {
+        Self {
+            keep_timing: true,
+            idle_timeout: Duration::from_secs(1),
+        }
+    }
+}
+
+impl ReplayReport {
+    /// 第一个不同的字节的位置, 完全相同时返回 None
+    pub fn first_mismatch(&self) -> Option<usize> {
+        let common = self.expected.len().min(self.actual.len());
+        (0..common)
+            .find(|&i| self.expected[i] != self.actual[i])
+            .or((self.expected.len() != self.actual.len()).then_some(common))
+    }
+}
+
+/// 连接 `addr`, 按抓包文件发送客户端的数据, 收集回复并和抓包时 upstream 的回复对比
+pub async fn replay(
+    capture: &CaptureFile,
+    addr: &str,
+    options: &ReplayOptions,
+) -> Result<ReplayReport> {
+    let stream = TcpStream::connect(addr)
+        .await
+        .with_context(|| format!("connect to {}", addr))?;
+    let (mut reader, mut writer) = stream.into_split();
+    let sent = Cell::new(false);
+    let send = async {
+        let start = Instant::now();
+        let records = capture
+            .records
+            .iter()
+            .filter(|record| record.direction == Direction::ClientToUpstream);
+        for record in records {
+            if options.keep_timing {
+                sleep_until(start + record.offset).await;
+            }
+            match record.data.is_empty() {
+                true => writer.shutdown().await?,
+                false => writer.write_all(&record.data).await?,
+            }
+        }
+        sent.set(true);
+        Ok::<_, io::Error>(())
+    };
+    let receive = async {
+        let mut actual = Vec::new();
+        let mut buf = vec![0; 16 * 1024];
+        loop {
+            match timeout(options.idle_timeout, reader.read(&mut buf)).await {
+                Ok(Ok(0)) => return Ok(actual),
+                Ok(Ok(n)) => actual.extend_from_slice(&buf[..n]),
+                Ok(Err(e)) => return Err(e),
+                Err(_) if sent.get() => return Ok(actual),
+                Err(_) => {}
+            }
+        }
+    };
+    let (sent, actual) = tokio::join!(send, receive);
+    sent.context("send client data")?;
+    Ok(ReplayReport {
+        expected: capture.data(Direction::UpstreamToClient),
+        actual: actual.context("receive upstream data")?,
+    })
+}
+
+impl Captures {
+    pub(crate) fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    /// 等所有写文件的任务把数据写完, 在连接都关闭之后调用
+    pub(crate) async fn flush(&self) {
+        self.writers.close();
+        self.writers.wait().await;
+    }
+}
+
+impl Recorder {
+    /// 按配置的客户端网段、upstream 和总量决定是否抓这个连接, 抓的话在后台写文件.
+    /// `initial` 是已经从客户端读到的数据, 比如 ClientHello
+    pub(crate) fn start(
+        config: &CaptureConfig,
+        header: CaptureHeader,
+        captures: &Arc<Captures>,
+        initial: &[u8],
+    ) -> Option<Self> {
+        if !selects(config, header.client.ip(), &header.upstream)
+            || captures.total() >= config.max_total_bytes
+        {
+            return None;
+        }
+        let path = config.dir.join(format!(
+            "{}-{}.cap",
+            header.start.format("%Y%m%dT%H%M%S"),
+            header.session
+        ));
+        info!(
+            "Capturing session {} from {} to {}",
+            header.session,
+            header.client,
+            path.display()
+        );
+        let (tx, rx) = mpsc::channel(CAPTURE_QUEUE);
+        let session = header.session;
+        captures.writers.spawn(write(path, header, rx));
+        let mut recorder = Self {
+            session,
+            tx: Some(tx),
+            start: Instant::now(),
+            remaining: config.max_session_bytes,
+            captures: Arc::clone(captures),
+            max_total: config.max_total_bytes,
+        };
+        if !initial.is_empty() {
+            recorder.record(Direction::ClientToUpstream, initial);
+        }
+        Some(recorder)
+    }
+
+    fn record(&mut self, direction: Direction, data: &[u8]) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        let len = data.len().min(self.remaining as usize);
+        let total = self.captures.total.fetch_add(len as u64, Ordering::Relaxed) + len as u64;
+        self.remaining -= len as u64;
+
+        let mut record = BytesMut::with_capacity(RECORD_HEADER + len);
+        record.put_u8(direction.byte());
+        record.put_u64(self.start.elapsed().as_micros() as u64);
+        record.put_u32(len as u32);
+        record.put_slice(&data[..len]);
+        if let Err(e) = tx.try_send(record.freeze()) {
+            if let TrySendError::Full(_) = e {
+                warn!("Capture of session {} is too slow, stopped", self.session);
+            }
+            self.tx = None;
+            return;
+        }
+        // 截断的数据之后不再记录, 否则文件里的字节流不连续
+        if len < data.len() || total >= self.max_total {
+            info!("Capture of session {} reached the size limit", self.session);
+            self.tx = None;
+        }
+    }
+}
+
+fn selects(config: &CaptureConfig, client: IpAddr, upstream: &str) -> bool {
+    let client_matches =
+        config.clients.is_empty() || config.clients.iter().any(|net| net.contains(&client));
+    let upstream_matches =
+        config.upstreams.is_empty() || config.upstreams.iter().any(|name| name == upstream);
+    client_matches && upstream_matches
+}
+
+// 所有记录写完, 也就是连接结束之后关闭文件
+async fn write(path: PathBuf, header: CaptureHeader, mut rx: mpsc::Receiver<Bytes>) {
+    let ret = async {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir).await?;
+        }
+        let mut file = BufWriter::new(File::create(&path).await?);
+        let mut line = serde_json::to_vec(&header)?;
+        line.push(b'\n');
+        file.write_all(&line).await?;
+        while let Some(record) = rx.recv().await {
+            file.write_all(&record).await?;
+        }
+        file.flush().await
+    };
+    if let Err(e) = ret.await {
+        warn!("Failed to write capture {}: {}", path.display(), e);
+    }
+}
+
+impl<S> Captured<S> {
+    pub(crate) fn new(inner: S, recorder: Option<Recorder>) -> Self {
+        Self { inner, recorder }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Captured<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        let eof_possible = buf.remaining() > 0;
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some(recorder) = &mut self.recorder {
+            let data = &buf.filled()[before..];
+            // 有空间却没有读到数据就是 EOF, 记录一条空的
+            if !data.is_empty() || eof_possible {
+                recorder.record(Direction::ClientToUpstream, data);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Captured<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(Direction::UpstreamToClient, &buf[..n]);
+        }
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        ready!(Pin::new(&mut self.inner).poll_shutdown(cx))?;
+        if let Some(recorder) = &mut self.recorder {
+            recorder.record(Direction::UpstreamToClient, &[]);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header() -> CaptureHeader {
+        CaptureHeader {
+            session: 1,
+            start: Utc::now(),
+            client: "10.0.0.1:5000".parse().unwrap(),
+            listener: "127.0.0.1:3000".parse().unwrap(),
+            upstream: "web".into(),
+            backend: "127.0.0.1:3001".into(),
+        }
+    }
+
+    #[test]
+    fn capture_file_should_round_trip() {
+        let header = header();
+        let mut data = serde_json::to_vec(&header).unwrap();
+        data.push(b'\n');
+        for (direction, micros, payload) in [
+            (CLIENT_TO_UPSTREAM, 0u64, &b"ping"[..]),
+            (UPSTREAM_TO_CLIENT, 1500, b"pong"),
+            (CLIENT_TO_UPSTREAM, 2000, b""),
+        ] {
+            data.put_u8(direction);
+            data.put_u64(micros);
+            data.put_u32(payload.len() as u32);
+            data.put_slice(payload);
+        }
+        let capture = CaptureFile::parse(&data).unwrap();
+        assert_eq!(capture.header, header);
+        assert_eq!(capture.records.len(), 3);
+        assert_eq!(capture.records[1].offset, Duration::from_micros(1500));
+        assert_eq!(capture.data(Direction::ClientToUpstream), b"ping");
+        assert_eq!(capture.data(Direction::UpstreamToClient), b"pong");
+
+        data.pop();
+        assert!(matches!(
+            CaptureFile::parse(&data),
+            Err(CaptureError::Truncated)
+        ));
+    }
+
+    #[test]
+    fn capture_should_filter_clients_and_upstreams() {
+        let config = CaptureConfig {
+            dir: "captures".into(),
+            clients: vec!["10.0.0.0/8".parse().unwrap()],
+            upstreams: vec!["web".into()],
+            max_session_bytes: 1024,
+            max_total_bytes: 1024,
+        };
+        assert!(selects(&config, "10.1.2.3".parse().unwrap(), "web"));
+        assert!(!selects(&config, "192.168.1.1".parse().unwrap(), "web"));
+        assert!(!selects(&config, "10.1.2.3".parse().unwrap(), "api"));
+    }
+
+    #[test]
+    fn first_mismatch_should_find_different_or_missing_bytes() {
+        let report = |expected: &[u8], actual: &[u8]| ReplayReport {
+            expected: expected.to_vec(),
+            actual: actual.to_vec(),
+        };
+        assert_eq!(report(b"hello", b"hello").first_mismatch(), None);
+        assert_eq!(report(b"hello", b"help").first_mismatch(), Some(3));
+        assert_eq!(report(b"hello", b"hell").first_mismatch(), Some(4));
+    }
+}
@@ -0,0 +1,1738 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    fs,
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use hyper::{header::HeaderName, Method};
+use ipnet::IpNet;
+use rustls::pki_types::ServerName;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use thiserror::Error;
+
+use super::ProxyProtocol;
+
+/// 环境变量覆盖配置项: `MINGINX_UPSTREAMS__WEB__SERVERS__0__ADDR=127.0.0.1:3002`
+pub const ENV_PREFIX: &str = "MINGINX_";
+const ENV_SEPARATOR: &str = "__";
+
+// 一个环境变量覆盖的字段: serde_path_to_error 格式的路径, json pointer 和原始值
+#[derive(Debug)]
+struct EnvOverride {
+    field: String,
+    pointer: String,
+    raw: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    pub listeners: Vec<ListenerConfig>,
+    pub upstreams: BTreeMap<String, UpstreamConfig>,
+    #[serde(default)]
+    pub access_log: AccessLogConfig,
+    /// 不配置时不启动 admin 接口
+    #[serde(default)]
+    pub admin: Option<AdminConfig>,
+    #[serde(default)]
+    pub shutdown: ShutdownConfig,
+    /// 不配置时不抓包
+    #[serde(default)]
+    pub capture: Option<CaptureConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub listen_addr: SocketAddr,
+    /// `upstreams` 中的名字
+    pub upstream: String,
+    /// minginx 在其他负载均衡器后面时, 从连接开头读取 PROXY 头部作为客户端地址
+    #[serde(default)]
+    pub accept_proxy_protocol: bool,
+    /// 配置后在 listener 上终止 TLS, 转发给 upstream 的是明文
+    #[serde(default)]
+    pub tls: Option<ListenerTlsConfig>,
+    /// 不终止 TLS, 按 ClientHello 中的 SNI 选择 upstream, 没有匹配时使用 `upstream`
+    #[serde(default)]
+    pub sni_routing: Option<SniRoutingConfig>,
+    /// HTTP 模式: 解析 HTTP/1.1 和 HTTP/2 请求, 每个请求按 Host 和路径选择 upstream
+    #[serde(default)]
+    pub http: Option<HttpConfig>,
+    /// accept 之后立即按客户端 IP 检查, 拒绝的连接直接关闭
+    #[serde(default)]
+    pub access: AccessConfig,
+    /// UDP 模式: 监听 UDP, 按客户端地址跟踪 flow, 每个 flow 选择一个 backend
+    #[serde(default)]
+    pub udp: Option<UdpConfig>,
+    /// 故障注入, 用于测试服务在网络不好时的表现. 只用于 TCP 转发, 不支持 HTTP 模式和 UDP
+    #[serde(default)]
+    pub faults: Vec<FaultConfig>,
+}
+
+/// 每个新连接按 probability 独立决定是否注入这个故障, 同一种故障有多个时使用第一个选中的
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct FaultConfig {
+    #[serde(flatten)]
+    pub fault: Fault,
+    /// 0 到 1
+    #[serde(default = "default_fault_probability")]
+    pub probability: f64,
+    /// 关闭后保留配置但不生效, 可以通过 admin 接口切换
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+/// 注入的故障, 除了 reset 和 drop_connect 都作用在 upstream 发给客户端的方向
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Fault {
+    /// 每次写给客户端之前等待 delay 加上 0 到 jitter 之间的随机时间
+    Latency {
+        #[serde(with = "humantime_serde")]
+        delay: Duration,
+        #[serde(default, with = "humantime_serde")]
+        jitter: Duration,
+    },
+    /// 限制平均速率
+    Bandwidth { bytes_per_second: u64 },
+    /// 连接建立 after 之后 reset 客户端连接
+    Reset {
+        #[serde(default, with = "humantime_serde")]
+        after: Duration,
+    },
+    /// accept 之后直接关闭, 不连接 upstream
+    DropConnect,
+    /// 发给客户端 bytes 字节之后关闭连接
+    Truncate { bytes: u64 },
+}
+
+/// 一个客户端地址的数据报属于同一个 flow, 使用一个单独的 socket 和 backend 通信.
+/// access 中的连接数和新建连接速率在 UDP 模式下按 flow 计算, 被拒绝的数据报直接丢弃, 每个都计入统计
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct UdpConfig {
+    /// 两个方向都没有数据报的时间超过这个值就回收 flow
+    #[serde(with = "humantime_serde")]
+    pub flow_idle_timeout: Duration,
+}
+
+/// 按 TCP 连接的对端地址检查, 在 PROXY 头部之前; 数量限制为 0 表示不限制
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct AccessConfig {
+    /// 不为空时只允许这些网段
+    pub allow: Vec<IpNet>,
+    /// 优先于 allow
+    pub deny: Vec<IpNet>,
+    /// listener 同时打开的连接数
+    pub max_connections: usize,
+    /// 每个客户端 IP 同时打开的连接数
+    pub max_connections_per_ip: usize,
+    /// 每个客户端 IP 新建连接的速率
+    pub rate_limit_per_ip: Option<RateLimitConfig>,
+}
+
+/// 令牌桶: 每秒补充 `per_second` 个, 最多积攒 `burst` 个
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    pub per_second: u32,
+    pub burst: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerTlsConfig {
+    /// PEM 格式的证书链
+    pub cert: PathBuf,
+    /// PEM 格式的私钥
+    pub key: PathBuf,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SniRoutingConfig {
+    /// 小写的 hostname -> upstream, `*.example.com` 匹配一级子域名
+    pub routes: BTreeMap<String, String>,
+    #[serde(default)]
+    pub missing: MissingSni,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct HttpConfig {
+    /// 按顺序匹配, 使用第一个匹配的路由, 都不匹配时使用 listener 的 `upstream`
+    pub routes: Vec<HttpRouteConfig>,
+    /// 按顺序应用所有匹配的规则
+    pub headers: Vec<HeaderRuleConfig>,
+}
+
+/// 修改匹配的请求发给 backend 的头部, 以及返回给客户端的响应头部
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HeaderRuleConfig {
+    /// 不包括端口, 不区分大小写; 不配置时匹配任意 Host
+    #[serde(default)]
+    pub host: Option<String>,
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    /// 为空时匹配所有方法
+    #[serde(default)]
+    pub methods: Vec<String>,
+    #[serde(default)]
+    pub request: HeaderActions,
+    #[serde(default)]
+    pub response: HeaderActions,
+}
+
+/// 依次执行 remove, set, add. 值是模板, 可用的变量:
+/// $client_ip $request_id $upstream $host $method $path $scheme
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct HeaderActions {
+    pub remove: Vec<String>,
+    /// 替换已有的同名头部
+    pub set: BTreeMap<String, String>,
+    /// 追加, 保留已有的同名头部
+    pub add: BTreeMap<String, String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HttpRouteConfig {
+    /// 不包括端口, 不区分大小写; 不配置时匹配任意 Host
+    #[serde(default)]
+    pub host: Option<String>,
+    /// 不配置时匹配所有路径
+    #[serde(default = "default_path_prefix")]
+    pub path_prefix: String,
+    pub upstream: String,
+}
+
+/// ClientHello 中没有 SNI 时的处理方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MissingSni {
+    /// 使用 listener 的 `upstream`
+    #[default]
+    Default,
+    /// 关闭连接
+    Reject,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct UpstreamConfig {
+    #[serde(default)]
+    pub strategy: Strategy,
+    pub servers: Vec<ServerConfig>,
+    #[serde(default)]
+    pub health_check: HealthCheckConfig,
+    #[serde(default)]
+    pub timeouts: TimeoutConfig,
+    #[serde(default)]
+    pub forwarding: Forwarding,
+    /// connect 之后先发送 PROXY 头部, 把客户端的真实地址告诉 backend
+    #[serde(default)]
+    pub send_proxy_protocol: Option<ProxyProtocol>,
+    /// 一个客户端连接最多尝试 connect 几个不同的 backend
+    #[serde(default = "default_max_attempts")]
+    pub max_attempts: u32,
+    /// 配置后用 TLS 连接 backend
+    #[serde(default)]
+    pub tls: Option<UpstreamTlsConfig>,
+    /// 把一部分 TCP 连接中客户端发来的数据复制一份发给 shadow upstream, 丢弃它的回复.
+    /// HTTP 模式和 UDP 不镜像
+    #[serde(default)]
+    pub mirror: Option<MirrorConfig>,
+    /// 连续失败之后暂时拒绝新连接, 不配置时不熔断. UDP 不熔断
+    #[serde(default)]
+    pub circuit_breaker: Option<CircuitBreakerConfig>,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MirrorConfig {
+    /// `upstreams` 中的名字, 它自己的 mirror 不生效
+    pub upstream: String,
+    /// 镜像的连接比例, 0 到 1
+    #[serde(default = "default_mirror_fraction")]
+    pub fraction: f64,
+}
+
+/// 一个客户端连接所有 backend 都连不上, 或者连上之后很快出错结束 (比如被 reset) 算一次失败.
+/// 连续失败达到阈值后打开, 拒绝新连接; open_duration 之后半开, 放行几个试探连接,
+/// 都成功才关闭, 任何一个失败重新打开
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct CircuitBreakerConfig {
+    /// 连续失败多少次后打开
+    pub failure_threshold: u32,
+    /// 打开之后多久进入半开
+    #[serde(with = "humantime_serde")]
+    pub open_duration: Duration,
+    /// 半开时放行的试探连接数
+    pub half_open_requests: u32,
+    /// 连接建立后这段时间内出错结束算失败, 超过之后算成功
+    #[serde(with = "humantime_serde")]
+    pub early_reset: Duration,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct UpstreamTlsConfig {
+    /// 校验 backend 证书的 CA (PEM), 不配置时使用内置的公共根证书
+    pub ca: Option<PathBuf>,
+    /// 不配置时使用 backend 地址中的 host
+    pub sni: Option<String>,
+}
+
+/// 主动探测: 定期 TCP connect 每个 backend, 只被 UDP listener 使用的 upstream 改为定期向摘除的
+/// backend 发送空数据报; 被动探测: 代理流量时 connect 的结果.
+/// 两者共用连续失败/成功计数, 达到阈值时切换 backend 的健康状态
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct HealthCheckConfig {
+    #[serde(with = "humantime_serde")]
+    pub interval: Duration,
+    #[serde(with = "humantime_serde")]
+    pub timeout: Duration,
+    /// 连续失败多少次后摘除
+    pub unhealthy_threshold: u32,
+    /// 连续成功多少次后恢复
+    pub healthy_threshold: u32,
+}
+
+/// 代理连接的超时, idle 和 max_session 为 0 表示不限制
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct TimeoutConfig {
+    /// connect backend 的超时, 超时后换下一个 backend 重试
+    #[serde(with = "humantime_serde")]
+    pub connect: Duration,
+    /// 两个方向都没有数据的时间超过 idle 就断开
+    #[serde(with = "humantime_serde")]
+    pub idle: Duration,
+    /// 连接建立之后最长的存活时间
+    #[serde(with = "humantime_serde")]
+    pub max_session: Duration,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ServerConfig {
+    /// `host:port`, host 可以是域名
+    pub addr: String,
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+}
+
+/// 连接建立之后转发数据的方式
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Forwarding {
+    /// 读到用户态的 buffer 再写出去
+    #[default]
+    Copy,
+    /// 通过 pipe 用 splice(2) 零拷贝转发, 只支持 Linux
+    Splice,
+}
+
+/// 每个连接结束时写一行访问日志
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct AccessLogConfig {
+    /// 不配置时写到 stdout
+    pub path: Option<PathBuf>,
+    pub format: AccessLogFormat,
+}
+
+/// `json`, 或者包含 `$client`、`$bytes_in` 等变量的模板
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(from = "String", into = "String")]
+pub enum AccessLogFormat {
+    #[default]
+    Json,
+    Template(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminConfig {
+    pub listen_addr: SocketAddr,
+}
+
+/// 停止时先不再 accept, 等已有的连接结束, 超过 `grace_period` 后强制关闭剩下的连接
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields, default)]
+pub struct ShutdownConfig {
+    #[serde(with = "humantime_serde")]
+    pub grace_period: Duration,
+    /// 配置后新进程启动时通过这个 unix socket 从旧进程接过 listener, 旧进程随后停止.
+    /// 两个进程都在运行的一小段时间里同时 accept, 重启期间不会拒绝连接
+    pub handover_socket: Option<PathBuf>,
+}
+
+/// 把选中连接两个方向的数据和时间写到 `dir` 中, 每个连接一个文件, 可以用 minginx_replay 重放.
+/// 只抓 TCP 转发的连接, 不包括 HTTP 模式和 UDP
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CaptureConfig {
+    pub dir: PathBuf,
+    /// 不为空时只抓这些网段的客户端
+    #[serde(default)]
+    pub clients: Vec<IpNet>,
+    /// 不为空时只抓这些 upstream 的连接
+    #[serde(default)]
+    pub upstreams: Vec<String>,
+    /// 每个连接最多记录的数据字节数, 超过后这个连接不再记录
+    #[serde(default = "default_capture_session_bytes")]
+    pub max_session_bytes: u64,
+    /// 启动以来所有连接记录的数据字节数上限, 超过后不再抓包
+    #[serde(default = "default_capture_total_bytes")]
+    pub max_total_bytes: u64,
+}
+
+/// upstream 选择 backend 的负载均衡策略
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Strategy {
+    #[default]
+    RoundRobin,
+    WeightedRoundRobin,
+    LeastConnections,
+    RandomTwoChoices,
+    /// 按客户端 IP 做一致性哈希, 同一个客户端总是落到同一个 backend
+    ConsistentHash,
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("{}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("{}: unsupported config format, expect .yaml, .yml or .toml", path.display())]
+    Format { path: PathBuf },
+    #[error("{}: parse error: {message}", path.display())]
+    Parse { path: PathBuf, message: String },
+    #[error("{}: {key}: {message}", path.display())]
+    Env {
+        path: PathBuf,
+        key: String,
+        message: String,
+    },
+    #[error("{}: {field}: {message}", path.display())]
+    Invalid {
+        path: PathBuf,
+        field: String,
+        message: String,
+    },
+}
+
+impl Config {
+    /// 读取 yaml 或 toml 配置文件, 应用 `MINGINX_` 环境变量覆盖, 然后校验
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ConfigError> {
+        Self::load_with_env(path, std::env::vars())
+    }
+
+    pub fn load_with_env(
+        path: impl AsRef<Path>,
+        env: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|source| ConfigError::Io {
+            path: path.to_path_buf(),
+            source,
+        })?;
+        let mut value = parse(path, &content)?;
+        let overrides = apply_env(path, &mut value, env)?;
+
+        // serde_path_to_error 记录出错字段的路径, 比如 listeners[0].listen_addr
+        let config: Config = loop {
+            let e = match serde_path_to_error::deserialize(&value) {
+                Ok(config) => break config,
+                Err(e) => e,
+            };
+            // 环境变量的值先按 json 解析, 目标字段是字符串时 (比如纯数字的 addr) 改回字符串重试
+            let field = e.path().to_string();
+            let retry = overrides.iter().find(|o| o.field == field).and_then(|o| {
+                let node = value.pointer_mut(&o.pointer)?;
+                (!node.is_string()).then(|| *node = Value::String(o.raw.clone()))
+            });
+            if retry.is_none() {
+                return Err(ConfigError::Invalid {
+                    path: path.to_path_buf(),
+                    field,
+                    message: e.into_inner().to_string(),
+                });
+            }
+        };
+        config
+            .validate()
+            .map_err(|(field, message)| ConfigError::Invalid {
+                path: path.to_path_buf(),
+                field,
+                message,
+            })?;
+        Ok(config)
+    }
+
+    // 返回出错的字段和原因
+    pub(crate) fn validate(&self) -> Result<(), (String, String)> {
+        if self.listeners.is_empty() {
+            return Err((
+                "listeners".into(),
+                "at least one listener is required".into(),
+            ));
+        }
+
+        // TCP 和 UDP 可以使用同一个端口; 同一个端口上的每个地址都要比较
+        let mut seen: HashMap<(u16, bool), Vec<(usize, SocketAddr)>> = HashMap::new();
+        for (i, listener) in self.listeners.iter().enumerate() {
+            let addr = listener.listen_addr;
+            let same_port = seen
+                .entry((addr.port(), listener.udp.is_some()))
+                .or_default();
+            if let Some((j, prev)) = same_port.iter().find(|(_, prev)| conflicts(*prev, addr)) {
+                return Err((
+                    format!("listeners[{}].listen_addr", i),
+                    format!("{} conflicts with listeners[{}] ({})", addr, j, prev),
+                ));
+            }
+            same_port.push((i, addr));
+
+            let Some(upstream) = self.upstreams.get(&listener.upstream) else {
+                return Err((
+                    format!("listeners[{}].upstream", i),
+                    format!("unknown upstream {:?}", listener.upstream),
+                ));
+            };
+            if let Some(sni) = &listener.sni_routing {
+                let field = |rest: String| format!("listeners[{}].sni_routing{}", i, rest);
+                if listener.tls.is_some() {
+                    return Err((field("".into()), "cannot be used together with tls".into()));
+                }
+                for (host, upstream) in &sni.routes {
+                    let name = host.strip_prefix("*.").unwrap_or(host);
+                    if ServerName::try_from(name).is_err() || host.to_lowercase() != *host {
+                        return Err((
+                            field(".routes".into()),
+                            format!("{:?} is not a lowercase hostname", host),
+                        ));
+                    }
+                    if !self.upstreams.contains_key(upstream) {
+                        return Err((
+                            field(format!(".routes.{}", host)),
+                            format!("unknown upstream {:?}", upstream),
+                        ));
+                    }
+                }
+            }
+            if let Some(rate) = &listener.access.rate_limit_per_ip {
+                if rate.per_second == 0 || rate.burst == 0 {
+                    return Err((
+                        format!("listeners[{}].access.rate_limit_per_ip", i),
+                        "per_second and burst must be greater than 0".into(),
+                    ));
+                }
+            }
+            if let Some(http) = &listener.http {
+                let field = |rest: String| format!("listeners[{}].http{}", i, rest);
+                if listener.sni_routing.is_some() {
+                    return Err((
+                        field("".into()),
+                        "cannot be used together with sni_routing".into(),
+                    ));
+                }
+                let mut upstreams = vec![&listener.upstream];
+                for (j, route) in http.routes.iter().enumerate() {
+                    if !route.path_prefix.starts_with('/') {
+                        return Err((
+                            field(format!(".routes[{}].path_prefix", j)),
+                            "path prefix must start with /".into(),
+                        ));
+                    }
+                    if !self.upstreams.contains_key(&route.upstream) {
+                        return Err((
+                            field(format!(".routes[{}].upstream", j)),
+                            format!("unknown upstream {:?}", route.upstream),
+                        ));
+                    }
+                    upstreams.push(&route.upstream);
+                }
+                for (j, rule) in http.headers.iter().enumerate() {
+                    let field = |rest: &str| field(format!(".headers[{}]{}", j, rest));
+                    if !rule.path_prefix.starts_with('/') {
+                        return Err((
+                            field(".path_prefix"),
+                            "path prefix must start with /".into(),
+                        ));
+                    }
+                    if let Some(method) = rule
+                        .methods
+                        .iter()
+                        .find(|method| Method::from_bytes(method.as_bytes()).is_err())
+                    {
+                        return Err((field(".methods"), format!("invalid method {:?}", method)));
+                    }
+                    for (part, actions) in
+                        [("request", &rule.request), ("response", &rule.response)]
+                    {
+                        let names = actions
+                            .remove
+                            .iter()
+                            .chain(actions.set.keys())
+                            .chain(actions.add.keys());
+                        for name in names {
+                            if HeaderName::from_bytes(name.as_bytes()).is_err() {
+                                return Err((
+                                    field(&format!(".{}", part)),
+                                    format!("invalid header name {:?}", name),
+                                ));
+                            }
+                        }
+                    }
+                }
+                // 到 backend 的连接被不同客户端的请求复用, 没法携带客户端地址
+                if let Some(name) = upstreams
+                    .into_iter()
+                    .find(|name| self.upstreams[*name].send_proxy_protocol.is_some())
+                {
+                    return Err((
+                        field("".into()),
+                        format!(
+                            "upstream {:?} sends PROXY protocol, which cannot be used with pooled HTTP connections",
+                            name
+                        ),
+                    ));
+                }
+            }
+            if let Some(udp) = &listener.udp {
+                let field = |rest: &str| format!("listeners[{}]{}", i, rest);
+                let tcp_only = [
+                    ("tls", listener.tls.is_some()),
+                    ("sni_routing", listener.sni_routing.is_some()),
+                    ("http", listener.http.is_some()),
+                    ("accept_proxy_protocol", listener.accept_proxy_protocol),
+                ];
+                if let Some((name, _)) = tcp_only.iter().find(|(_, set)| *set) {
+                    return Err((
+                        field(".udp"),
+                        format!("cannot be used together with {}", name),
+                    ));
+                }
+                if udp.flow_idle_timeout.is_zero() {
+                    return Err((
+                        field(".udp.flow_idle_timeout"),
+                        "flow idle timeout must be greater than 0".into(),
+                    ));
+                }
+                if upstream.tls.is_some() || upstream.send_proxy_protocol.is_some() {
+                    return Err((
+                        field(".udp"),
+                        format!(
+                            "upstream {:?} uses TLS or PROXY protocol, which cannot forward UDP",
+                            listener.upstream
+                        ),
+                    ));
+                }
+            }
+            for (j, fault) in listener.faults.iter().enumerate() {
+                let field = |rest: &str| format!("listeners[{}].faults[{}]{}", i, j, rest);
+                if listener.http.is_some() || listener.udp.is_some() {
+                    return Err((field(""), "faults only apply to TCP forwarding".into()));
+                }
+                if !(0.0..=1.0).contains(&fault.probability) {
+                    return Err((
+                        field(".probability"),
+                        "probability must be between 0 and 1".into(),
+                    ));
+                }
+                if fault.fault
+                    == (Fault::Bandwidth {
+                        bytes_per_second: 0,
+                    })
+                {
+                    return Err((
+                        field(".bytes_per_second"),
+                        "bandwidth must be greater than 0".into(),
+                    ));
+                }
+            }
+            if listener.tls.is_some() && upstream.forwarding == Forwarding::Splice {
+                return Err((
+                    format!("listeners[{}].tls", i),
+                    format!(
+                        "upstream {:?} uses splice, which cannot forward TLS",
+                        listener.upstream
+                    ),
+                ));
+            }
+        }
+
+        if let Some(admin) = &self.admin {
+            let conflict = seen
+                .get(&(admin.listen_addr.port(), false))
+                .into_iter()
+                .flatten()
+                .find(|(_, prev)| conflicts(*prev, admin.listen_addr));
+            if let Some((j, prev)) = conflict {
+                return Err((
+                    "admin.listen_addr".into(),
+                    format!(
+                        "{} conflicts with listeners[{}] ({})",
+                        admin.listen_addr, j, prev
+                    ),
+                ));
+            }
+        }
+
+        for (name, upstream) in &self.upstreams {
+            let field = |rest: String| format!("upstreams.{}{}", name, rest);
+            if upstream.servers.is_empty() {
+                return Err((
+                    field(".servers".into()),
+                    "at least one server is required".into(),
+                ));
+            }
+            let mut addrs = HashSet::new();
+            for (i, server) in upstream.servers.iter().enumerate() {
+                validate_host_port(&server.addr)
+                    .map_err(|message| (field(format!(".servers[{}].addr", i)), message))?;
+                if !addrs.insert(&server.addr) {
+                    return Err((
+                        field(format!(".servers[{}].addr", i)),
+                        format!("duplicate server {}", server.addr),
+                    ));
+                }
+                if server.weight == 0 {
+                    return Err((
+                        field(format!(".servers[{}].weight", i)),
+                        "weight must be at least 1".into(),
+                    ));
+                }
+            }
+
+            let health = &upstream.health_check;
+            if health.interval.is_zero() || health.timeout.is_zero() {
+                return Err((
+                    field(".health_check".into()),
+                    "interval and timeout must be greater than 0".into(),
+                ));
+            }
+            if health.unhealthy_threshold == 0 || health.healthy_threshold == 0 {
+                return Err((
+                    field(".health_check".into()),
+                    "thresholds must be at least 1".into(),
+                ));
+            }
+
+            if upstream.timeouts.connect.is_zero() {
+                return Err((
+                    field(".timeouts.connect".into()),
+                    "connect timeout must be greater than 0".into(),
+                ));
+            }
+            if upstream.forwarding == Forwarding::Splice && !cfg!(target_os = "linux") {
+                return Err((
+                    field(".forwarding".into()),
+                    "splice is only supported on Linux".into(),
+                ));
+            }
+            if let Some(tls) = &upstream.tls {
+                if upstream.forwarding == Forwarding::Splice {
+                    return Err((
+                        field(".forwarding".into()),
+                        "splice cannot forward TLS, use copy".into(),
+                    ));
+                }
+                if let Some(sni) = &tls.sni {
+                    if ServerName::try_from(sni.as_str()).is_err() {
+                        return Err((
+                            field(".tls.sni".into()),
+                            format!("invalid server name {:?}", sni),
+                        ));
+                    }
+                }
+            }
+            if upstream.max_attempts == 0 {
+                return Err((
+                    field(".max_attempts".into()),
+                    "max_attempts must be at least 1".into(),
+                ));
+            }
+            if let Some(mirror) = &upstream.mirror {
+                if !self.upstreams.contains_key(&mirror.upstream) || mirror.upstream == *name {
+                    return Err((
+                        field(".mirror.upstream".into()),
+                        format!("{:?} is not another upstream", mirror.upstream),
+                    ));
+                }
+                if !(0.0..=1.0).contains(&mirror.fraction) {
+                    return Err((
+                        field(".mirror.fraction".into()),
+                        "fraction must be between 0 and 1".into(),
+                    ));
+                }
+            }
+            if let Some(breaker) = &upstream.circuit_breaker {
+                if breaker.failure_threshold == 0 || breaker.half_open_requests == 0 {
+                    return Err((
+                        field(".circuit_breaker".into()),
+                        "failure_threshold and half_open_requests must be at least 1".into(),
+                    ));
+                }
+                if breaker.open_duration.is_zero() {
+                    return Err((
+                        field(".circuit_breaker.open_duration".into()),
+                        "open_duration must be greater than 0".into(),
+                    ));
+                }
+            }
+        }
+
+        if let Some(capture) = &self.capture {
+            if let Some(name) = capture
+                .upstreams
+                .iter()
+                .find(|name| !self.upstreams.contains_key(*name))
+            {
+                return Err((
+                    "capture.upstreams".into(),
+                    format!("unknown upstream {:?}", name),
+                ));
+            }
+            if capture.max_session_bytes == 0 || capture.max_total_bytes == 0 {
+                return Err((
+                    "capture".into(),
+                    "size limits must be greater than 0".into(),
+                ));
+            }
+        }
+
+        if self.shutdown.handover_socket.is_some() && !cfg!(target_os = "linux") {
+            return Err((
+                "shutdown.handover_socket".into(),
+                "socket handover is only supported on Linux".into(),
+            ));
+        }
+        Ok(())
+    }
+}
+
+impl SniRoutingConfig {
+    /// SNI 对应的 upstream, 先精确匹配再匹配通配符
+    pub fn route(&self, sni: &str) -> Option<&str> {
+        let sni = sni.to_ascii_lowercase();
+        if let Some(upstream) = self.routes.get(&sni) {
+            return Some(upstream);
+        }
+        let (_, parent) = sni.split_once('.')?;
+        self.routes
+            .get(&format!("*.{}", parent))
+            .map(String::as_str)
+    }
+}
+
+impl HttpConfig {
+    /// 第一个匹配 Host 和路径的路由的 upstream, `host` 不包括端口
+    pub fn route(&self, host: Option<&str>, path: &str) -> Option<&str> {
+        self.routes
+            .iter()
+            .find(|route| {
+                let host_matches = match (&route.host, host) {
+                    (None, _) => true,
+                    (Some(expected), Some(host)) => expected.eq_ignore_ascii_case(host),
+                    (Some(_), None) => false,
+                };
+                host_matches && path.starts_with(&route.path_prefix)
+            })
+            .map(|route| route.upstream.as_str())
+    }
+}
+
+impl HeaderRuleConfig {
+    /// `host` 不包括端口
+    pub fn matches(&self, host: Option<&str>, method: &str, path: &str) -> bool {
+        let host_matches = match (&self.host, host) {
+            (None, _) => true,
+            (Some(expected), Some(host)) => expected.eq_ignore_ascii_case(host),
+            (Some(_), None) => false,
+        };
+        let method_matches = self.methods.is_empty()
+            || self
+                .methods
+                .iter()
+                .any(|expected| expected.eq_ignore_ascii_case(method));
+        host_matches && method_matches && path.starts_with(&self.path_prefix)
+    }
+}
+
+impl From<String> for AccessLogFormat {
+    fn from(format: String) -> Self {
+        match format.as_str() {
+            "json" => Self::Json,
+            _ => Self::Template(format),
+        }
+    }
+}
+
+impl From<AccessLogFormat> for String {
+    fn from(format: AccessLogFormat) -> Self {
+        match format {
+            AccessLogFormat::Json => "json".into(),
+            AccessLogFormat::Template(template) => template,
+        }
+    }
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+fn default_path_prefix() -> String {
+    "/".into()
+}
+
+fn default_max_attempts() -> u32 {
+    3
+}
+
+fn default_mirror_fraction() -> f64 {
+    1.0
+}
+
+fn default_fault_probability() -> f64 {
+    1.0
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_capture_session_bytes() -> u64 {
+    1024 * 1024
+}
+
+fn default_capture_total_bytes() -> u64 {
+    100 * 1024 * 1024
+}
+
+// 代码中构造配置时使用, listen_addr 和 upstream 需要自己填
+impl Default for ListenerConfig {
+    fn default() -> Self {
+        Self {
+            listen_addr: SocketAddr::from(([127, 0, 0, 1], 0)),
+            upstream: String::new(),
+            accept_proxy_protocol: false,
+            tls: None,
+            sni_routing: None,
+            http: None,
+            access: AccessConfig::default(),
+            udp: None,
+            faults: Vec::new(),
+        }
+    }
+}
+
+impl Default for UpstreamConfig {
+    fn default() -> Self {
+        Self {
+            strategy: Strategy::default(),
+            servers: Vec::new(),
+            health_check: HealthCheckConfig::default(),
+            timeouts: TimeoutConfig::default(),
+            forwarding: Forwarding::default(),
+            send_proxy_protocol: None,
+            max_attempts: default_max_attempts(),
+            tls: None,
+            mirror: None,
+            circuit_breaker: None,
+        }
+    }
+}
+
+impl Default for HealthCheckConfig {
+    fn default() -> Self {
+        Self {
+            interval: Duration::from_secs(5),
+            timeout: Duration::from_secs(1),
+            unhealthy_threshold: 3,
+            healthy_threshold: 2,
+        }
+    }
+}
+
+impl Default for CircuitBreakerConfig {
+    fn default() -> Self {
+        Self {
+            failure_threshold: 5,
+            open_duration: Duration::from_secs(30),
+            half_open_requests: 1,
+            early_reset: Duration::from_secs(1),
+        }
+    }
+}
+
+impl Default for TimeoutConfig {
+    fn default() -> Self {
+        Self {
+            connect: Duration::from_secs(5),
+            idle: Duration::from_secs(600),
+            max_session: Duration::ZERO,
+        }
+    }
+}
+
+impl Default for UdpConfig {
+    fn default() -> Self {
+        Self {
+            flow_idle_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+impl Default for ShutdownConfig {
+    fn default() -> Self {
+        Self {
+            grace_period: Duration::from_secs(30),
+            handover_socket: None,
+        }
+    }
+}
+
+// 0.0.0.0:3000 和 127.0.0.1:3000 同样会冲突, 端口 0 由系统分配, 不会冲突
+fn conflicts(a: SocketAddr, b: SocketAddr) -> bool {
+    a.port() == b.port()
+        && a.port() != 0
+        && (a.ip() == b.ip() || a.ip().is_unspecified() || b.ip().is_unspecified())
+}
+
+fn validate_host_port(addr: &str) -> Result<(), String> {
+    if addr.parse::<SocketAddr>().is_ok() {
+        return Ok(());
+    }
+    match addr.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && !host.contains(':') => port
+            .parse::<u16>()
+            .map(|_| ())
+            .map_err(|_| format!("invalid port in {:?}", addr)),
+        _ => Err(format!("expect host:port, got {:?}", addr)),
+    }
+}
+
+fn parse(path: &Path, content: &str) -> Result<Value, ConfigError> {
+    let parse_error = |message: String| ConfigError::Parse {
+        path: path.to_path_buf(),
+        message,
+    };
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some("yaml" | "yml") => {
+            serde_yaml::from_str(content).map_err(|e| parse_error(e.to_string()))
+        }
+        Some("toml") => toml::from_str(content).map_err(|e| parse_error(e.to_string())),
+        _ => Err(ConfigError::Format {
+            path: path.to_path_buf(),
+        }),
+    }
+}
+
+// MINGINX_UPSTREAMS__WEB__SERVERS__0__ADDR -> ["upstreams", "web", "servers", "0", "addr"]
+// 返回覆盖的字段, 按 json 解析的值类型不对时用来改回字符串
+fn apply_env(
+    path: &Path,
+    root: &mut Value,
+    env: impl IntoIterator<Item = (String, String)>,
+) -> Result<Vec<EnvOverride>, ConfigError> {
+    let mut overrides: Vec<_> = env
+        .into_iter()
+        .filter_map(|(key, value)| {
+            let name = key.strip_prefix(ENV_PREFIX)?.to_string();
+            Some((key, name, value))
+        })
+        .collect();
+    // 保证覆盖顺序与环境变量顺序无关
+    overrides.sort();
+
+    let mut applied = Vec::with_capacity(overrides.len());
+    for (key, name, raw) in overrides {
+        let env_error = |message: String| ConfigError::Env {
+            path: path.to_path_buf(),
+            key: key.clone(),
+            message,
+        };
+        let mut node = &mut *root;
+        let mut field = String::new();
+        let mut pointer = String::new();
+        for segment in name.split(ENV_SEPARATOR) {
+            if node.is_null() {
+                *node = Value::Object(Map::new());
+            }
+            node = match node {
+                Value::Object(map) => {
+                    // 字段名都是小写的; upstream 等名字按配置文件中的大小写匹配, 不区分大小写
+                    let name = map
+                        .keys()
+                        .find(|name| *name == segment)
+                        .or_else(|| map.keys().find(|name| name.eq_ignore_ascii_case(segment)))
+                        .cloned()
+                        .unwrap_or_else(|| segment.to_lowercase());
+                    if !field.is_empty() {
+                        field.push('.');
+                    }
+                    field.push_str(&name);
+                    pointer.push('/');
+                    pointer.push_str(&name.replace('~', "~0").replace('/', "~1"));
+                    map.entry(name).or_insert(Value::Null)
+                }
+                Value::Array(items) => {
+                    let len = items.len();
+                    let index: usize = segment
+                        .parse()
+                        .map_err(|_| env_error(format!("expect array index, got {:?}", segment)))?;
+                    field.push_str(&format!("[{}]", index));
+                    pointer.push_str(&format!("/{}", index));
+                    items.get_mut(index).ok_or_else(|| {
+                        env_error(format!("index {} out of range ({})", index, len))
+                    })?
+                }
+                _ => return Err(env_error(format!("{:?} is not a table", segment))),
+            };
+        }
+        // 数字, 布尔值, 数组按 json 解析, 其他都当作字符串
+        *node = serde_json::from_str(&raw).unwrap_or_else(|_| Value::String(raw.clone()));
+        applied.push(EnvOverride {
+            field,
+            pointer,
+            raw,
+        });
+    }
+    Ok(applied)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Write,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
+
+    use super::*;
+
+    static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+    fn write_config(ext: &str, content: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "minginx-{}-{}.{}",
+            std::process::id(),
+            NEXT_FILE.fetch_add(1, Ordering::Relaxed),
+            ext
+        ));
+        fs::File::create(&path)
+            .and_then(|mut f| f.write_all(content.as_bytes()))
+            .unwrap();
+        path
+    }
+
+    const YAML: &str = r#"
+listeners:
+  - listen_addr: 127.0.0.1:3000
+    upstream: web
+  - listen_addr: 127.0.0.1:3100
+    upstream: api
+upstreams:
+  web:
+    servers:
+      - addr: 127.0.0.1:3001
+  api:
+    strategy: weighted_round_robin
+    servers:
+      - addr: localhost:3101
+        weight: 3
+      - addr: localhost:3102
+    health_check:
+      interval: 500ms
+      unhealthy_threshold: 1
+    timeouts:
+      connect: 1s
+      idle: 0s
+    forwarding: splice
+    max_attempts: 2
+"#;
+
+    #[test]
+    fn load_should_read_yaml_and_toml() {
+        let yaml = Config::load_with_env(write_config("yml", YAML), []).unwrap();
+        let toml = write_config(
+            "toml",
+            r#"
+[[listeners]]
+listen_addr = "127.0.0.1:3000"
+upstream = "web"
+
+[[listeners]]
+listen_addr = "127.0.0.1:3100"
+upstream = "api"
+
+[[upstreams.web.servers]]
+addr = "127.0.0.1:3001"
+
+[upstreams.api]
+strategy = "weighted_round_robin"
+servers = [{ addr = "localhost:3101", weight = 3 }, { addr = "localhost:3102" }]
+health_check = { interval = "500ms", unhealthy_threshold = 1 }
+timeouts = { connect = "1s", idle = "0s" }
+forwarding = "splice"
+max_attempts = 2
+"#,
+        );
+        assert_eq!(Config::load_with_env(toml, []).unwrap(), yaml);
+        let api = &yaml.upstreams["api"];
+        assert_eq!(api.strategy, Strategy::WeightedRoundRobin);
+        assert_eq!((api.servers[0].weight, api.servers[1].weight), (3, 1));
+        assert_eq!(api.health_check.interval, Duration::from_millis(500));
+        assert_eq!(api.health_check.unhealthy_threshold, 1);
+        assert_eq!(api.timeouts.connect, Duration::from_secs(1));
+        assert!(api.timeouts.idle.is_zero());
+        assert_eq!(api.max_attempts, 2);
+        assert_eq!(api.forwarding, Forwarding::Splice);
+        let web = &yaml.upstreams["web"];
+        assert_eq!(web.health_check, HealthCheckConfig::default());
+        assert_eq!(web.timeouts, TimeoutConfig::default());
+        assert_eq!(web.max_attempts, 3);
+    }
+
+    #[test]
+    fn env_should_override_config() {
+        let env = [
+            (
+                "MINGINX_UPSTREAMS__API__SERVERS__1__ADDR".to_string(),
+                "10.0.0.1:80".to_string(),
+            ),
+            (
+                "MINGINX_UPSTREAMS__API__SERVERS__1__WEIGHT".to_string(),
+                "2".to_string(),
+            ),
+        ];
+        let config = Config::load_with_env(write_config("yml", YAML), env).unwrap();
+        let server = &config.upstreams["api"].servers[1];
+        assert_eq!((server.addr.as_str(), server.weight), ("10.0.0.1:80", 2));
+    }
+
+    #[test]
+    fn env_should_keep_name_case_and_string_fields() {
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: Web }]
+upstreams: { Web: { servers: [{ addr: a:1 }] } }
+",
+        );
+        let env = [
+            (
+                "MINGINX_UPSTREAMS__WEB__SERVERS__0__ADDR".to_string(),
+                "3002".to_string(),
+            ),
+            (
+                "MINGINX_UPSTREAMS__WEB__MAX_ATTEMPTS".to_string(),
+                "5".to_string(),
+            ),
+        ];
+        // addr 是字符串, 即使看起来像数字; 校验时才报告格式不对
+        let err = Config::load_with_env(&path, env.clone())
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("upstreams.Web.servers[0].addr"), "{}", err);
+        assert!(!err.contains("invalid type"), "{}", err);
+
+        let env = [
+            (env[0].0.clone(), "localhost:3002".to_string()),
+            env[1].clone(),
+        ];
+        let config = Config::load_with_env(&path, env).unwrap();
+        let upstream = &config.upstreams["Web"];
+        assert_eq!(upstream.servers[0].addr, "localhost:3002");
+        assert_eq!(upstream.max_attempts, 5);
+    }
+
+    #[test]
+    fn invalid_config_should_report_path_and_field() {
+        let path = write_config(
+            "yaml",
+            "listeners:\n  - listen_addr: localhost\n    upstream: a\nupstreams: {}\n",
+        );
+        let err = Config::load_with_env(&path, []).unwrap_err().to_string();
+        assert!(err.starts_with(&path.display().to_string()), "{}", err);
+        assert!(err.contains("listeners[0].listen_addr"), "{}", err);
+    }
+
+    #[test]
+    fn duplicate_listeners_should_be_rejected() {
+        let path = write_config(
+            "yaml",
+            r#"
+listeners:
+  - listen_addr: 0.0.0.0:3000
+    upstream: a
+  - listen_addr: 127.0.0.1:3000
+    upstream: a
+upstreams:
+  a:
+    servers: [{ addr: "a:1" }]
+"#,
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("listeners[1].listen_addr"), "{}", err);
+        assert!(err.contains("conflicts with listeners[0]"), "{}", err);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:3000, upstream: a }]\nupstreams: { a: { servers: [{ addr: a:1 }] } }\nadmin: { listen_addr: 0.0.0.0:3000 }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("admin.listen_addr"), "{}", err);
+    }
+
+    #[test]
+    fn duplicate_listeners_should_be_rejected_across_other_addresses() {
+        // 中间隔着同一端口的其他地址
+        let path = write_config(
+            "yaml",
+            r#"
+listeners:
+  - listen_addr: 127.0.0.1:3000
+    upstream: a
+  - listen_addr: 127.0.0.2:3000
+    upstream: a
+  - listen_addr: 127.0.0.1:3000
+    upstream: a
+upstreams:
+  a:
+    servers: [{ addr: "a:1" }]
+"#,
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("listeners[2].listen_addr"), "{}", err);
+        assert!(err.contains("conflicts with listeners[0]"), "{}", err);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:3000, upstream: a }, { listen_addr: 127.0.0.2:3000, upstream: a }]\nupstreams: { a: { servers: [{ addr: a:1 }] } }\nadmin: { listen_addr: 127.0.0.1:3000 }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("admin.listen_addr"), "{}", err);
+        assert!(err.contains("conflicts with listeners[0]"), "{}", err);
+    }
+
+    #[test]
+    fn unknown_upstream_and_bad_servers_should_be_rejected() {
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: b }]\nupstreams: { a: { servers: [{ addr: a:1 }] } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(
+            err.contains("listeners[0].upstream: unknown upstream"),
+            "{}",
+            err
+        );
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]\nupstreams: { a: { servers: [{ addr: a:1, weight: 0 }] } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("upstreams.a.servers[0].weight"), "{}", err);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]\nupstreams: { a: { servers: [{ addr: a:1 }], max_attempts: 0 } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("upstreams.a.max_attempts"), "{}", err);
+    }
+
+    #[test]
+    fn tls_should_not_be_forwarded_by_splice() {
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, tls: { cert: a.pem, key: a.key } }]\nupstreams: { a: { servers: [{ addr: a:1 }], forwarding: splice } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("listeners[0].tls"), "{}", err);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]\nupstreams: { a: { servers: [{ addr: a:1 }], forwarding: splice, tls: {} } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("upstreams.a.forwarding"), "{}", err);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]\nupstreams: { a: { servers: [{ addr: a:1 }], tls: { sni: \"bad name\" } } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("upstreams.a.tls.sni"), "{}", err);
+    }
+
+    #[test]
+    fn sni_routes_should_match_exact_and_wildcard_hosts() {
+        let path = write_config(
+            "yaml",
+            r#"
+listeners:
+  - listen_addr: 127.0.0.1:0
+    upstream: a
+    sni_routing:
+      routes:
+        api.example.com: b
+        "*.example.com": a
+      missing: reject
+upstreams:
+  a: { servers: [{ addr: a:1 }] }
+  b: { servers: [{ addr: b:1 }] }
+"#,
+        );
+        let config = Config::load_with_env(path, []).unwrap();
+        let sni = config.listeners[0].sni_routing.as_ref().unwrap();
+        assert_eq!(sni.missing, MissingSni::Reject);
+        assert_eq!(sni.route("API.example.com"), Some("b"));
+        assert_eq!(sni.route("www.example.com"), Some("a"));
+        assert_eq!(sni.route("a.www.example.com"), None);
+        assert_eq!(sni.route("example.com"), None);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, sni_routing: { routes: { x.com: c } } }]\nupstreams: { a: { servers: [{ addr: a:1 }] } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(
+            err.contains("listeners[0].sni_routing.routes.x.com"),
+            "{}",
+            err
+        );
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, sni_routing: { routes: { X.com: a } } }]\nupstreams: { a: { servers: [{ addr: a:1 }] } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("not a lowercase hostname"), "{}", err);
+    }
+
+    #[test]
+    fn http_routes_should_match_in_order() {
+        let path = write_config(
+            "yaml",
+            r#"
+listeners:
+  - listen_addr: 127.0.0.1:0
+    upstream: web
+    http:
+      routes:
+        - host: api.example.com
+          path_prefix: /v2
+          upstream: api2
+        - host: api.example.com
+          upstream: api
+        - path_prefix: /static
+          upstream: static
+upstreams:
+  web: { servers: [{ addr: a:1 }] }
+  api: { servers: [{ addr: b:1 }] }
+  api2: { servers: [{ addr: c:1 }] }
+  static: { servers: [{ addr: d:1 }] }
+"#,
+        );
+        let config = Config::load_with_env(path, []).unwrap();
+        let http = config.listeners[0].http.as_ref().unwrap();
+        assert_eq!(
+            http.route(Some("API.example.com"), "/v2/users"),
+            Some("api2")
+        );
+        assert_eq!(
+            http.route(Some("api.example.com"), "/v1/users"),
+            Some("api")
+        );
+        assert_eq!(
+            http.route(Some("api.example.com"), "/static/a.css"),
+            Some("api")
+        );
+        assert_eq!(http.route(None, "/static/a.css"), Some("static"));
+        assert_eq!(http.route(Some("www.example.com"), "/"), None);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, http: { routes: [{ path_prefix: v1, upstream: a }] } }]\nupstreams: { a: { servers: [{ addr: a:1 }] } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(
+            err.contains("listeners[0].http.routes[0].path_prefix"),
+            "{}",
+            err
+        );
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, http: {} }]\nupstreams: { a: { servers: [{ addr: a:1 }], send_proxy_protocol: v1 } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("pooled HTTP connections"), "{}", err);
+    }
+
+    #[test]
+    fn header_rules_should_match_host_method_and_path() {
+        let path = write_config(
+            "yaml",
+            r#"
+listeners:
+  - listen_addr: 127.0.0.1:0
+    upstream: web
+    http:
+      headers:
+        - host: api.example.com
+          path_prefix: /admin
+          methods: [POST, delete]
+          request:
+            remove: [cookie]
+            set: { x-user: $client_ip }
+upstreams:
+  web: { servers: [{ addr: a:1 }] }
+"#,
+        );
+        let config = Config::load_with_env(path, []).unwrap();
+        let rule = &config.listeners[0].http.as_ref().unwrap().headers[0];
+        assert!(rule.matches(Some("api.example.com"), "POST", "/admin/users"));
+        assert!(rule.matches(Some("API.example.com"), "DELETE", "/admin"));
+        assert!(!rule.matches(Some("api.example.com"), "GET", "/admin"));
+        assert!(!rule.matches(Some("api.example.com"), "POST", "/users"));
+        assert!(!rule.matches(None, "POST", "/admin"));
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, http: { headers: [{ response: { add: { \"bad name\": x } } }] } }]\nupstreams: { a: { servers: [{ addr: a:1 }] } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(
+            err.contains("listeners[0].http.headers[0].response"),
+            "{}",
+            err
+        );
+        assert!(err.contains("invalid header name"), "{}", err);
+    }
+
+    #[test]
+    fn access_should_parse_cidrs_and_reject_zero_rate() {
+        let path = write_config(
+            "yaml",
+            r#"
+listeners:
+  - listen_addr: 127.0.0.1:0
+    upstream: web
+    access:
+      allow: [10.0.0.0/8, "::1/128"]
+      deny: [10.0.1.0/24]
+      max_connections_per_ip: 10
+      rate_limit_per_ip: { per_second: 5, burst: 20 }
+upstreams:
+  web: { servers: [{ addr: a:1 }] }
+"#,
+        );
+        let config = Config::load_with_env(path, []).unwrap();
+        let access = &config.listeners[0].access;
+        assert_eq!(
+            access.allow,
+            ["10.0.0.0/8".parse().unwrap(), "::1/128".parse().unwrap()]
+        );
+        assert_eq!(access.max_connections, 0);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, access: { deny: [10.0.0.1] } }]\nupstreams: { a: { servers: [{ addr: a:1 }] } }\n",
+        );
+        assert!(Config::load_with_env(path, []).is_err());
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, access: { rate_limit_per_ip: { per_second: 0, burst: 1 } } }]\nupstreams: { a: { servers: [{ addr: a:1 }] } }\n",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(
+            err.contains("listeners[0].access.rate_limit_per_ip"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    fn udp_listener_should_share_port_with_tcp_and_reject_tcp_options() {
+        let path = write_config(
+            "yaml",
+            r#"
+listeners:
+  - listen_addr: 127.0.0.1:5300
+    upstream: dns
+  - listen_addr: 0.0.0.0:5300
+    upstream: dns
+    udp: { flow_idle_timeout: 10s }
+upstreams:
+  dns: { servers: [{ addr: a:53 }] }
+"#,
+        );
+        let config = Config::load_with_env(path, []).unwrap();
+        assert_eq!(
+            config.listeners[1].udp.as_ref().unwrap().flow_idle_timeout,
+            Duration::from_secs(10)
+        );
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, udp: {}, accept_proxy_protocol: true }]
+upstreams: { a: { servers: [{ addr: a:1 }] } }
+",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("listeners[0].udp"), "{}", err);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, udp: {} }]
+upstreams: { a: { servers: [{ addr: a:1 }], send_proxy_protocol: v1 } }
+",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("cannot forward UDP"), "{}", err);
+    }
+
+    #[test]
+    fn capture_should_reference_known_upstreams() {
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]
+upstreams: { a: { servers: [{ addr: a:1 }] } }
+capture: { dir: /tmp/captures, clients: [10.0.0.0/8] }
+",
+        );
+        let config = Config::load_with_env(path, []).unwrap();
+        let capture = config.capture.unwrap();
+        assert_eq!(capture.max_session_bytes, 1024 * 1024);
+        assert!(capture.upstreams.is_empty());
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]
+upstreams: { a: { servers: [{ addr: a:1 }] } }
+capture: { dir: /tmp/captures, upstreams: [b] }
+",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("capture.upstreams"), "{}", err);
+    }
+
+    #[test]
+    fn mirror_should_point_to_another_upstream() {
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]
+upstreams:
+  a: { servers: [{ addr: a:1 }], mirror: { upstream: b } }
+  b: { servers: [{ addr: b:1 }] }
+",
+        );
+        let config = Config::load_with_env(path, []).unwrap();
+        assert_eq!(config.upstreams["a"].mirror.as_ref().unwrap().fraction, 1.0);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]
+upstreams: { a: { servers: [{ addr: a:1 }], mirror: { upstream: a } } }
+",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("upstreams.a.mirror.upstream"), "{}", err);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]
+upstreams:
+  a: { servers: [{ addr: a:1 }], mirror: { upstream: b, fraction: 1.5 } }
+  b: { servers: [{ addr: b:1 }] }
+",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("upstreams.a.mirror.fraction"), "{}", err);
+    }
+
+    #[test]
+    fn circuit_breaker_should_use_defaults_and_reject_zero_threshold() {
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]
+upstreams: { a: { servers: [{ addr: a:1 }], circuit_breaker: { open_duration: 5s } } }
+",
+        );
+        let config = Config::load_with_env(path, []).unwrap();
+        let breaker = config.upstreams["a"].circuit_breaker.as_ref().unwrap();
+        assert_eq!(breaker.open_duration, Duration::from_secs(5));
+        assert_eq!(breaker.failure_threshold, 5);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a }]
+upstreams: { a: { servers: [{ addr: a:1 }], circuit_breaker: { failure_threshold: 0 } } }
+",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("upstreams.a.circuit_breaker"), "{}", err);
+    }
+
+    #[test]
+    fn faults_should_parse_and_only_apply_to_tcp() {
+        let path = write_config(
+            "yaml",
+            r#"
+listeners:
+  - listen_addr: 127.0.0.1:0
+    upstream: a
+    faults:
+      - { type: latency, delay: 100ms, jitter: 50ms, probability: 0.5 }
+      - { type: drop_connect, enabled: false }
+upstreams: { a: { servers: [{ addr: a:1 }] } }
+"#,
+        );
+        let config = Config::load_with_env(path, []).unwrap();
+        let faults = &config.listeners[0].faults;
+        assert_eq!(
+            faults[0].fault,
+            Fault::Latency {
+                delay: Duration::from_millis(100),
+                jitter: Duration::from_millis(50),
+            }
+        );
+        assert_eq!(faults[0].probability, 0.5);
+        assert!(!faults[1].enabled);
+
+        let path = write_config(
+            "yaml",
+            "listeners: [{ listen_addr: 127.0.0.1:0, upstream: a, udp: {}, faults: [{ type: drop_connect }] }]
+upstreams: { a: { servers: [{ addr: a:1 }] } }
+",
+        );
+        let err = Config::load_with_env(path, []).unwrap_err().to_string();
+        assert!(err.contains("listeners[0].faults[0]"), "{}", err);
+    }
+
+    #[test]
+    fn upstream_without_port_should_be_rejected() {
+        assert!(validate_host_port("example.com:80").is_ok());
+        assert!(validate_host_port("[::1]:80").is_ok());
+        assert!(validate_host_port("example.com").is_err());
+        assert!(validate_host_port("example.com:http").is_err());
+    }
+}
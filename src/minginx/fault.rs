@@ -0,0 +1,270 @@
+use std::{
+    future::{self, Future},
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use rand::Rng;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, ReadBuf},
+    sync::Notify,
+    time::{sleep, sleep_until, Instant, Sleep},
+};
+
+use super::{Fault, FaultConfig};
+
+/// 一个连接选中的故障, 在 accept 时按概率决定, 之后不变
+#[derive(Debug, Default)]
+pub(crate) struct Faults {
+    // (delay, jitter)
+    latency: Option<(Duration, Duration)>,
+    bytes_per_second: Option<u64>,
+    reset_after: Option<Duration>,
+    drop_connect: bool,
+    truncate: Option<u64>,
+    // 注入的故障关闭了连接
+    tripped: AtomicBool,
+    // 截断之后另一个方向也要结束
+    closed: Notify,
+}
+
+/// 写给客户端时注入延迟、限速和截断的连接
+#[derive(Debug)]
+pub(crate) struct Faulty<'a, S> {
+    inner: S,
+    faults: &'a Faults,
+    // 当前这块数据的延迟, 写出之后清掉
+    delay: Option<Pin<Box<Sleep>>>,
+    throttle: Option<Pin<Box<Sleep>>>,
+    start: Instant,
+    written: u64,
+}
+
+impl Faults {
+    pub(crate) fn pick(configs: &[FaultConfig]) -> Self {
+        let mut faults = Self::default();
+        let mut rng = rand::thread_rng();
+        for config in configs {
+            if !config.enabled || !rng.gen_bool(config.probability) {
+                continue;
+            }
+            match config.fault {
+                Fault::Latency { delay, jitter } => {
+                    faults.latency.get_or_insert((delay, jitter));
+                }
+                Fault::Bandwidth { bytes_per_second } => {
+                    faults.bytes_per_second.get_or_insert(bytes_per_second);
+                }
+                Fault::Reset { after } => {
+                    faults.reset_after.get_or_insert(after);
+                }
+                Fault::DropConnect => faults.drop_connect = true,
+                Fault::Truncate { bytes } => {
+                    faults.truncate.get_or_insert(bytes);
+                }
+            }
+        }
+        faults
+    }
+
+    /// 是否需要经过 `run` 和 `wrap` 转发, 为 true 时不能使用 splice
+    pub(crate) fn needs_copy(&self) -> bool {
+        self.latency.is_some()
+            || self.bytes_per_second.is_some()
+            || self.reset_after.is_some()
+            || self.truncate.is_some()
+    }
+
+    pub(crate) fn drop_connect(&self) -> bool {
+        self.drop_connect
+    }
+
+    pub(crate) fn reset_after(&self) -> Option<Duration> {
+        self.reset_after
+    }
+
+    pub(crate) fn tripped(&self) -> bool {
+        self.tripped.load(Ordering::Relaxed)
+    }
+
+    fn trip(&self, kind: io::ErrorKind, message: &str) -> io::Error {
+        self.tripped.store(true, Ordering::Relaxed);
+        self.closed.notify_one();
+        io::Error::new(kind, message)
+    }
+
+    /// 运行转发, 到了 reset 的时间或者被截断时返回错误, 客户端连接随后被关闭
+    pub(crate) async fn run(
+        &self,
+        session: impl Future<Output = io::Result<()>>,
+    ) -> io::Result<()> {
+        let reset = async {
+            match self.reset_after {
+                Some(after) => sleep(after).await,
+                None => future::pending().await,
+            }
+        };
+        tokio::select! {
+            ret = session => ret,
+            _ = reset => Err(self.trip(
+                io::ErrorKind::ConnectionReset,
+                "connection reset by fault injection",
+            )),
+            _ = self.closed.notified() => Err(io::ErrorKind::ConnectionAborted.into()),
+        }
+    }
+
+    pub(crate) fn wrap<S>(&self, inner: S) -> Faulty<'_, S> {
+        Faulty {
+            inner,
+            faults: self,
+            delay: None,
+            throttle: None,
+            start: Instant::now(),
+            written: 0,
+        }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Faulty<'_, S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Faulty<'_, S> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        let faults = this.faults;
+        let mut len = buf.len();
+        if let Some(limit) = faults.truncate {
+            if this.written >= limit {
+                return Poll::Ready(Err(faults.trip(
+                    io::ErrorKind::ConnectionAborted,
+                    "stream truncated by fault injection",
+                )));
+            }
+            len = len.min((limit - this.written) as usize);
+        }
+        if let Some((delay, jitter)) = faults.latency {
+            let delay = this.delay.get_or_insert_with(|| {
+                let jitter = jitter.mul_f64(rand::thread_rng().gen());
+                Box::pin(sleep(delay + jitter))
+            });
+            ready!(delay.as_mut().poll(cx));
+        }
+        // 按已经写出的字节数计算下一块数据最早什么时候可以写, 每块最多 100ms 的量
+        if let Some(rate) = faults.bytes_per_second {
+            let due = this.start + Duration::from_secs_f64(this.written as f64 / rate as f64);
+            if Instant::now() < due {
+                let throttle = this
+                    .throttle
+                    .get_or_insert_with(|| Box::pin(sleep_until(due)));
+                ready!(throttle.as_mut().poll(cx));
+            }
+            len = len.min((rate / 10).max(1) as usize);
+        }
+        let n = ready!(Pin::new(&mut this.inner).poll_write(cx, &buf[..len]))?;
+        this.delay = None;
+        this.throttle = None;
+        this.written += n as u64;
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    fn faults(configs: &[Fault]) -> Faults {
+        let configs: Vec<_> = configs
+            .iter()
+            .map(|fault| FaultConfig {
+                fault: fault.clone(),
+                probability: 1.0,
+                enabled: true,
+            })
+            .collect();
+        Faults::pick(&configs)
+    }
+
+    #[test]
+    fn pick_should_skip_disabled_and_unlikely_faults() {
+        let configs = [
+            FaultConfig {
+                fault: Fault::DropConnect,
+                probability: 1.0,
+                enabled: false,
+            },
+            FaultConfig {
+                fault: Fault::Truncate { bytes: 1 },
+                probability: 0.0,
+                enabled: true,
+            },
+            FaultConfig {
+                fault: Fault::Truncate { bytes: 2 },
+                probability: 1.0,
+                enabled: true,
+            },
+        ];
+        let faults = Faults::pick(&configs);
+        assert!(!faults.drop_connect());
+        assert_eq!(faults.truncate, Some(2));
+    }
+
+    #[tokio::test]
+    async fn truncate_should_stop_after_limit() {
+        let faults = faults(&[Fault::Truncate { bytes: 5 }]);
+        let (client, mut peer) = io::duplex(64);
+        let mut client = faults.wrap(client);
+        let err = client.write_all(b"hello world").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionAborted);
+        assert!(faults.tripped());
+        drop(client);
+        let mut received = Vec::new();
+        peer.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received, b"hello");
+    }
+
+    #[tokio::test]
+    async fn bandwidth_and_latency_should_slow_down_writes() {
+        let faults = faults(&[
+            Fault::Bandwidth {
+                bytes_per_second: 1000,
+            },
+            Fault::Latency {
+                delay: Duration::from_millis(20),
+                jitter: Duration::ZERO,
+            },
+        ]);
+        let (client, mut peer) = io::duplex(1024);
+        let mut client = faults.wrap(client);
+        let start = Instant::now();
+        // 每块 100 字节, 一共 3 块: 限速要求第 3 块在 200ms 之后写
+        client.write_all(&[0; 300]).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(200));
+        let mut received = vec![0; 300];
+        peer.read_exact(&mut received).await.unwrap();
+        assert!(!faults.tripped());
+    }
+}
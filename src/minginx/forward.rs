@@ -0,0 +1,336 @@
+use std::{
+    future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf},
+    time::{sleep_until, Instant},
+};
+
+use super::{CloseReason, Transfer};
+
+const BUF_SIZE: usize = 8 * 1024;
+
+// 转发的字节数和最近一次转发数据的时间, 两个方向共用.
+// 超时的时候转发被取消, 字节数需要在转发过程中记录
+#[derive(Debug)]
+pub(crate) struct Meter {
+    start: Instant,
+    // 距离 start 的毫秒数
+    last: AtomicU64,
+    // client -> upstream
+    bytes_in: AtomicU64,
+    // upstream -> client
+    bytes_out: AtomicU64,
+    // UDP 模式下两个方向的数据报个数
+    datagrams_in: AtomicU64,
+    datagrams_out: AtomicU64,
+}
+
+// 读写时计入 meter 的连接, 用于 HTTP 模式下统计客户端连接的流量
+#[derive(Debug)]
+pub(crate) struct Metered<S> {
+    inner: S,
+    meter: Arc<Meter>,
+}
+
+impl Meter {
+    pub(crate) fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            last: AtomicU64::new(0),
+            bytes_in: AtomicU64::new(0),
+            bytes_out: AtomicU64::new(0),
+            datagrams_in: AtomicU64::new(0),
+            datagrams_out: AtomicU64::new(0),
+        }
+    }
+
+    /// client -> upstream 的一个数据报
+    pub(crate) fn datagram_in(&self, n: usize) {
+        self.record(&self.bytes_in, n);
+        self.datagrams_in.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// upstream -> client 的一个数据报
+    pub(crate) fn datagram_out(&self, n: usize) {
+        self.record(&self.bytes_out, n);
+        self.datagrams_out.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn record(&self, bytes: &AtomicU64, n: usize) {
+        let elapsed = self.start.elapsed().as_millis() as u64;
+        self.last.store(elapsed, Ordering::Relaxed);
+        bytes.fetch_add(n as u64, Ordering::Relaxed);
+    }
+
+    // 超过 idle 没有数据时返回, idle 为 0 时永远不返回
+    pub(crate) async fn idle(&self, idle: Duration) {
+        if idle.is_zero() {
+            return future::pending().await;
+        }
+        loop {
+            let last = Duration::from_millis(self.last.load(Ordering::Relaxed));
+            let deadline = self.start + last + idle;
+            if Instant::now() >= deadline {
+                return;
+            }
+            sleep_until(deadline).await;
+        }
+    }
+
+    /// (bytes_in, bytes_out)
+    pub(crate) fn bytes(&self) -> (u64, u64) {
+        (
+            self.bytes_in.load(Ordering::Relaxed),
+            self.bytes_out.load(Ordering::Relaxed),
+        )
+    }
+
+    /// (datagrams_in, datagrams_out)
+    pub(crate) fn datagrams(&self) -> (u64, u64) {
+        (
+            self.datagrams_in.load(Ordering::Relaxed),
+            self.datagrams_out.load(Ordering::Relaxed),
+        )
+    }
+
+    pub(crate) fn transfer(&self, reason: CloseReason) -> Transfer {
+        Transfer {
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            reason,
+        }
+    }
+}
+
+impl<S> Metered<S> {
+    pub(crate) fn new(inner: S, meter: Arc<Meter>) -> Self {
+        Self { inner, meter }
+    }
+}
+
+// 从客户端读到的是 bytes_in, 写给客户端的是 bytes_out
+impl<S: AsyncRead + Unpin> AsyncRead for Metered<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        let n = buf.filled().len() - before;
+        if n > 0 {
+            self.meter.record(&self.meter.bytes_in, n);
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Metered<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let n = ready!(Pin::new(&mut self.inner).poll_write(cx, buf))?;
+        self.meter.record(&self.meter.bytes_out, n);
+        Poll::Ready(Ok(n))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+/// 两个方向各自转发, 一个方向读到 EOF 后只关闭对端的写, 另一个方向继续,
+/// 直到两个方向都结束. 返回遇到的第一个错误
+pub(crate) async fn copy_bidirectional<C, U>(
+    client: &mut C,
+    upstream: &mut U,
+    meter: &Meter,
+) -> io::Result<()>
+where
+    C: AsyncRead + AsyncWrite + Unpin,
+    U: AsyncRead + AsyncWrite + Unpin,
+{
+    let (mut client_reader, mut client_writer) = io::split(client);
+    let (mut upstream_reader, mut upstream_writer) = io::split(upstream);
+    let (client_to_upstream, upstream_to_client) = tokio::join!(
+        copy_half(
+            &mut client_reader,
+            &mut upstream_writer,
+            meter,
+            &meter.bytes_in
+        ),
+        copy_half(
+            &mut upstream_reader,
+            &mut client_writer,
+            meter,
+            &meter.bytes_out
+        ),
+    );
+    client_to_upstream.and(upstream_to_client)
+}
+
+async fn copy_half<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    meter: &Meter,
+    bytes: &AtomicU64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let ret = copy(reader, writer, meter, bytes).await;
+    // 把 FIN 传给对端; 出错时同样关闭, 对端写的方向不受影响
+    let _ = writer.shutdown().await;
+    ret
+}
+
+async fn copy<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    meter: &Meter,
+    bytes: &AtomicU64,
+) -> io::Result<()>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut buf = vec![0; BUF_SIZE];
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        writer.write_all(&buf[..n]).await?;
+        meter.record(bytes, n);
+    }
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) mod splice {
+    use std::{
+        os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        ptr,
+        sync::atomic::AtomicU64,
+    };
+
+    use tokio::{
+        io::{self, Interest},
+        net::TcpStream,
+    };
+
+    use super::Meter;
+
+    // 每次最多搬运的字节数, 和 pipe 的默认容量一致
+    const PIPE_SIZE: usize = 64 * 1024;
+
+    struct Pipe {
+        read: OwnedFd,
+        write: OwnedFd,
+    }
+
+    /// 和 `copy_bidirectional` 一样, 但是通过 pipe 用 splice(2) 转发, 数据不经过用户态
+    pub(crate) async fn bidirectional(
+        client: &TcpStream,
+        upstream: &TcpStream,
+        meter: &Meter,
+    ) -> io::Result<()> {
+        let (client_to_upstream, upstream_to_client) = tokio::join!(
+            half(client, upstream, meter, &meter.bytes_in),
+            half(upstream, client, meter, &meter.bytes_out),
+        );
+        client_to_upstream.and(upstream_to_client)
+    }
+
+    async fn half(
+        from: &TcpStream,
+        to: &TcpStream,
+        meter: &Meter,
+        bytes: &AtomicU64,
+    ) -> io::Result<()> {
+        let ret = forward(from, to, meter, bytes).await;
+        // SAFETY: fd 在 to 的生命周期内有效
+        unsafe { libc::shutdown(to.as_raw_fd(), libc::SHUT_WR) };
+        ret
+    }
+
+    async fn forward(
+        from: &TcpStream,
+        to: &TcpStream,
+        meter: &Meter,
+        bytes: &AtomicU64,
+    ) -> io::Result<()> {
+        let pipe = Pipe::new()?;
+        loop {
+            // 每轮开始时 pipe 是空的, 不会因为 pipe 满了而返回 EAGAIN
+            let n = from
+                .async_io(Interest::READABLE, || {
+                    splice(from.as_raw_fd(), pipe.write.as_raw_fd(), PIPE_SIZE)
+                })
+                .await?;
+            if n == 0 {
+                return Ok(());
+            }
+            let mut left = n;
+            while left > 0 {
+                left -= to
+                    .async_io(Interest::WRITABLE, || {
+                        splice(pipe.read.as_raw_fd(), to.as_raw_fd(), left)
+                    })
+                    .await?;
+            }
+            meter.record(bytes, n);
+        }
+    }
+
+    impl Pipe {
+        fn new() -> io::Result<Self> {
+            let mut fds = [0; 2];
+            // SAFETY: fds 有两个元素, 成功时 pipe2 写入两个新的 fd
+            if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_NONBLOCK | libc::O_CLOEXEC) } < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            // SAFETY: 两个 fd 刚刚创建, 没有其他所有者
+            Ok(unsafe {
+                Self {
+                    read: OwnedFd::from_raw_fd(fds[0]),
+                    write: OwnedFd::from_raw_fd(fds[1]),
+                }
+            })
+        }
+    }
+
+    fn splice(from: RawFd, to: RawFd, len: usize) -> io::Result<usize> {
+        // SAFETY: 两个 fd 在调用期间有效, offset 为空表示使用 fd 当前的位置
+        let n = unsafe {
+            libc::splice(
+                from,
+                ptr::null_mut(),
+                to,
+                ptr::null_mut(),
+                len,
+                libc::SPLICE_F_MOVE | libc::SPLICE_F_NONBLOCK,
+            )
+        };
+        if n < 0 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(n as usize)
+        }
+    }
+}
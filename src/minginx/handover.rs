@@ -0,0 +1,208 @@
+use std::{
+    fs,
+    io::{self, Write},
+    mem,
+    net::{TcpListener, UdpSocket},
+    os::{
+        fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+        unix::net::UnixStream,
+    },
+    path::{Path, PathBuf},
+    ptr,
+    time::Duration,
+};
+
+use anyhow::{Context, Result};
+use tokio::{io::AsyncReadExt, net::UnixListener, time::timeout};
+use tracing::{info, warn};
+
+use super::proxy::Socket;
+
+// 一条消息最多能传递的 fd 数 (SCM_MAX_FD)
+const MAX_LISTENERS: usize = 253;
+// 新旧进程等待对方的时间
+const HANDOVER_TIMEOUT: Duration = Duration::from_secs(5);
+// 新进程用收到的 listener 启动成功之后回复, 旧进程收到后才停止
+const ACK: u8 = 1;
+// 数据部分每个 fd 一个字节, 表示 socket 的类型
+const KIND_TCP: u8 = b't';
+const KIND_UDP: u8 = b'u';
+
+const FD_SIZE: usize = mem::size_of::<RawFd>();
+
+/// 从旧进程收到了 listener, 还没有回复. drop 时关闭连接, 旧进程继续运行
+#[derive(Debug)]
+pub(crate) struct Takeover {
+    stream: UnixStream,
+    path: PathBuf,
+    listeners: usize,
+}
+
+/// 新进程启动时从旧进程接过所有 listener, 没有旧进程在运行时返回空.
+/// 新进程准备好之后调用 `Takeover::ack`, 旧进程收到后才停止
+pub(crate) fn receive(path: &Path) -> Result<(Vec<Socket>, Option<Takeover>)> {
+    let stream = match UnixStream::connect(path) {
+        Ok(stream) => stream,
+        // 第一次启动, 或者旧进程已经退出, 只留下了 socket 文件
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused
+            ) =>
+        {
+            return Ok((Vec::new(), None))
+        }
+        Err(e) => return Err(e).with_context(|| format!("connect to {}", path.display())),
+    };
+    stream.set_read_timeout(Some(HANDOVER_TIMEOUT))?;
+    let sockets = recv_fds(stream.as_raw_fd())
+        .with_context(|| format!("receive listeners from {}", path.display()))?;
+    let takeover = Takeover {
+        stream,
+        path: path.to_path_buf(),
+        listeners: sockets.len(),
+    };
+    Ok((sockets, Some(takeover)))
+}
+
+impl Takeover {
+    /// 回复旧进程, 让它停止 accept
+    pub(crate) fn ack(mut self) -> Result<()> {
+        // 只有一个字节, 不会阻塞
+        self.stream
+            .write_all(&[ACK])
+            .with_context(|| format!("reply to {}", self.path.display()))?;
+        info!(
+            "Took over {} listeners from {}",
+            self.listeners,
+            self.path.display()
+        );
+        Ok(())
+    }
+}
+
+/// 在 `path` 上等待新进程, 把 listener 交给它之后返回
+pub(crate) async fn serve(path: PathBuf, sockets: Vec<Socket>) -> Result<()> {
+    // 上一个进程的 socket 文件, 这时已经从它那里接过了 listener
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e).with_context(|| format!("remove {}", path.display())),
+    }
+    let listener = UnixListener::bind(&path).with_context(|| format!("bind {}", path.display()))?;
+    info!("Waiting for listener handover on {}", path.display());
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        // 消息只有几个字节, 新连接的发送缓冲区一定放得下, 不会阻塞
+        if let Err(e) = send_fds(stream.as_raw_fd(), &sockets) {
+            warn!("Failed to hand over listeners: {}", e);
+            continue;
+        }
+        match timeout(HANDOVER_TIMEOUT, stream.read_u8()).await {
+            Ok(Ok(ACK)) => {
+                info!("Handed over {} listeners", sockets.len());
+                return Ok(());
+            }
+            Ok(Ok(_)) => warn!("Unexpected reply to listener handover"),
+            Ok(Err(e)) => warn!("Failed to hand over listeners: {}", e),
+            Err(_) => warn!("Timed out waiting for the new process to take over listeners"),
+        }
+    }
+}
+
+// 通过 SCM_RIGHTS 发送 fd
+fn send_fds(socket: RawFd, sockets: &[Socket]) -> io::Result<()> {
+    let (kinds, fds): (Vec<_>, Vec<_>) = sockets
+        .iter()
+        .map(|socket| match socket {
+            Socket::Tcp(listener) => (KIND_TCP, listener.as_raw_fd()),
+            Socket::Udp(socket) => (KIND_UDP, socket.as_raw_fd()),
+        })
+        .unzip();
+    let mut iov = libc::iovec {
+        iov_base: kinds.as_ptr() as *mut _,
+        iov_len: kinds.len(),
+    };
+    // SAFETY: 只计算长度
+    let space = unsafe { libc::CMSG_SPACE((fds.len() * FD_SIZE) as u32) } as usize;
+    let mut control = vec![0u8; space];
+    // SAFETY: msghdr 全部为 0 是合法的初始值
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+    // SAFETY: control 的长度按 fd 数量计算, 放得下一个 cmsghdr 和所有 fd;
+    // iov 和 control 在 sendmsg 返回前有效
+    let n = unsafe {
+        let cmsg = libc::CMSG_FIRSTHDR(&msg);
+        (*cmsg).cmsg_level = libc::SOL_SOCKET;
+        (*cmsg).cmsg_type = libc::SCM_RIGHTS;
+        (*cmsg).cmsg_len = libc::CMSG_LEN((fds.len() * FD_SIZE) as u32) as _;
+        ptr::copy_nonoverlapping(fds.as_ptr(), libc::CMSG_DATA(cmsg).cast(), fds.len());
+        libc::sendmsg(socket, &msg, libc::MSG_NOSIGNAL)
+    };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn recv_fds(socket: RawFd) -> io::Result<Vec<Socket>> {
+    let mut kinds = [0u8; MAX_LISTENERS];
+    let mut iov = libc::iovec {
+        iov_base: kinds.as_mut_ptr().cast(),
+        iov_len: kinds.len(),
+    };
+    // SAFETY: 只计算长度
+    let space = unsafe { libc::CMSG_SPACE((MAX_LISTENERS * FD_SIZE) as u32) } as usize;
+    let mut control = vec![0u8; space];
+    // SAFETY: msghdr 全部为 0 是合法的初始值
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = control.as_mut_ptr().cast();
+    msg.msg_controllen = space as _;
+    // SAFETY: iov 和 control 在 recvmsg 返回前有效
+    let n = unsafe { libc::recvmsg(socket, &mut msg, libc::MSG_CMSG_CLOEXEC) };
+    if n < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    // 先接管所有收到的 fd, 出错返回时一起关闭
+    let mut fds = Vec::new();
+    // SAFETY: 按 CMSG 宏遍历内核填充的 control, 收到的 fd 是新的, 没有其他所有者
+    unsafe {
+        let mut cmsg = libc::CMSG_FIRSTHDR(&msg);
+        while !cmsg.is_null() {
+            if (*cmsg).cmsg_level == libc::SOL_SOCKET && (*cmsg).cmsg_type == libc::SCM_RIGHTS {
+                let data = libc::CMSG_DATA(cmsg).cast::<RawFd>();
+                let len = ((*cmsg).cmsg_len as usize - libc::CMSG_LEN(0) as usize) / FD_SIZE;
+                for i in 0..len {
+                    fds.push(OwnedFd::from_raw_fd(ptr::read_unaligned(data.add(i))));
+                }
+            }
+            cmsg = libc::CMSG_NXTHDR(&msg, cmsg);
+        }
+    }
+    if n == 0 {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    if msg.msg_flags & libc::MSG_CTRUNC != 0 || fds.len() != n as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "incomplete listener handover",
+        ));
+    }
+    fds.into_iter()
+        .zip(kinds)
+        .map(|(fd, kind)| match kind {
+            KIND_TCP => Ok(Socket::Tcp(TcpListener::from(fd))),
+            KIND_UDP => Ok(Socket::Udp(UdpSocket::from(fd))),
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unknown socket kind {}", kind),
+            )),
+        })
+        .collect()
+}
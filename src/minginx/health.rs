@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use futures::future::join_all;
+use tokio::{
+    io::{self, Interest},
+    net::TcpStream,
+    time::{interval, timeout, Duration, MissedTickBehavior},
+};
+use tracing::debug;
+
+use super::{udp, UpstreamPool};
+
+/// 定期对 pool 中所有 backend 做 TCP connect 探测
+pub(crate) async fn probe_loop(pool: Arc<UpstreamPool>) -> Result<()> {
+    let config = pool.health_check().clone();
+    let mut ticker = interval(config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let probes = pool.backends().iter().map(|backend| async {
+            let ok = matches!(
+                timeout(config.timeout, TcpStream::connect(backend.addr())).await,
+                Ok(Ok(_))
+            );
+            debug!(
+                "Probe {} in upstream {}: {}",
+                backend.addr(),
+                pool.name(),
+                ok
+            );
+            pool.report(backend, ok);
+        });
+        join_all(probes).await;
+    }
+}
+
+/// 只被 UDP listener 使用的 pool 不能 TCP connect, 被动探测摘除的 backend 也不会再被选中,
+/// 所以定期探测已经摘除的 backend: 发送一个空的数据报, timeout 内收到 ICMP port unreachable
+/// 算失败, 收到回复或者没有回应都算成功. 恢复之后仍由代理流量做被动探测
+pub(crate) async fn udp_probe_loop(pool: Arc<UpstreamPool>) -> Result<()> {
+    let config = pool.health_check().clone();
+    let mut ticker = interval(config.interval);
+    ticker.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        ticker.tick().await;
+        let probes = pool
+            .backends()
+            .iter()
+            .filter(|backend| !backend.is_healthy())
+            .map(|backend| async {
+                let ok = probe_udp(backend.addr(), config.timeout).await.is_ok();
+                debug!(
+                    "UDP probe {} in upstream {}: {}",
+                    backend.addr(),
+                    pool.name(),
+                    ok
+                );
+                pool.report(backend, ok);
+            });
+        join_all(probes).await;
+    }
+}
+
+async fn probe_udp(addr: &str, wait: Duration) -> io::Result<()> {
+    let socket = udp::connect(addr).await?;
+    socket.send(&[]).await?;
+    // 等待中的 recv 不会被 ICMP 的错误唤醒, 需要单独关注 ERROR
+    if let Ok(ready) = timeout(wait, socket.ready(Interest::READABLE | Interest::ERROR)).await {
+        ready?;
+        if let Some(e) = socket.take_error()? {
+            return Err(e);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,639 @@
+use std::{
+    collections::HashMap,
+    convert::Infallible,
+    mem,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
+
+use bytes::Bytes;
+use http_body_util::{combinators::BoxBody, BodyExt, Empty, Full};
+use hyper::{
+    body::{Body as _, Incoming},
+    client::conn::http1::{self, SendRequest},
+    header::{self, HeaderMap, HeaderName, HeaderValue},
+    service::service_fn,
+    upgrade::OnUpgrade,
+    Request, Response, StatusCode, Uri, Version,
+};
+use hyper_util::{
+    rt::{TokioExecutor, TokioIo},
+    server::conn::auto,
+};
+use tokio::{
+    io,
+    task::JoinSet,
+    time::{timeout, Instant},
+};
+use tracing::{debug, info, warn};
+
+use super::{
+    access_log::expand,
+    forward::{self, Meter, Metered},
+    proxy::{connect, watch},
+    reload::Snapshot,
+    sessions::LiveSession,
+    BackendGuard, BackendState, HeaderActions, MaybeTlsStream, TimeoutConfig, Transfer,
+    UpstreamPool,
+};
+
+/// HTTP 模式的 TLS listener 通过 ALPN 协商的协议
+pub(crate) const ALPN: &[&[u8]] = &[b"h2", b"http/1.1"];
+
+// 每个 backend 最多保留的空闲连接数
+const MAX_IDLE_PER_BACKEND: usize = 32;
+
+const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+const X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+const X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+// 只对一跳有效的头部, 不转发 (RFC 9110 7.6.1)
+const HOP_BY_HOP: [HeaderName; 7] = [
+    header::CONNECTION,
+    HeaderName::from_static("keep-alive"),
+    HeaderName::from_static("proxy-connection"),
+    header::TE,
+    header::TRAILER,
+    header::TRANSFER_ENCODING,
+    header::UPGRADE,
+];
+
+type Body = BoxBody<Bytes, hyper::Error>;
+
+/// 到 backend 的空闲 HTTP/1.1 连接, 响应结束后放回来给之后的请求复用
+#[derive(Debug, Default)]
+pub(crate) struct IdleConnections {
+    // backend 地址 -> 连接
+    idle: Mutex<HashMap<String, Vec<SendRequest<Body>>>>,
+}
+
+// 头部模板中可以使用的变量
+struct Vars {
+    client_ip: String,
+    request_id: String,
+    upstream: String,
+    host: String,
+    method: String,
+    path: String,
+    scheme: &'static str,
+}
+
+// 一个客户端连接上所有请求共用的信息
+struct Client {
+    snapshot: Arc<Snapshot>,
+    index: usize,
+    source: SocketAddr,
+    destination: SocketAddr,
+    proto: &'static str,
+    // upgrade 之后的隧道, 客户端连接结束前等待它们结束, 取消时一起取消
+    tunnels: Mutex<JoinSet<()>>,
+}
+
+impl IdleConnections {
+    fn take(&self, addr: &str) -> Option<SendRequest<Body>> {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.get_mut(addr)?;
+        // backend 可能已经关闭了空闲的连接
+        while let Some(conn) = conns.pop() {
+            if conn.is_ready() {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    fn put(&self, addr: &str, conn: SendRequest<Body>) {
+        let mut idle = self.idle.lock().unwrap();
+        let conns = idle.entry(addr.to_string()).or_default();
+        if conns.len() < MAX_IDLE_PER_BACKEND {
+            conns.push(conn);
+        }
+    }
+
+    /// 丢掉 backend 的所有空闲连接, 连接随之关闭
+    pub(crate) fn remove(&self, addr: &str) {
+        self.idle.lock().unwrap().remove(addr);
+    }
+}
+
+/// 在客户端连接上处理 HTTP/1.1 和 HTTP/2 请求, 每个请求按 Host 和路径选择 upstream.
+/// 统计的是客户端连接上的流量, 空闲超时和最长时间使用 listener 默认 upstream 的配置.
+/// 开始停止之后处理完当前的请求就关闭连接
+pub(crate) async fn serve(
+    client: MaybeTlsStream,
+    snapshot: Arc<Snapshot>,
+    index: usize,
+    source: SocketAddr,
+    destination: SocketAddr,
+    live: &LiveSession,
+) -> Transfer {
+    let meter = live.meter();
+    let timeouts = snapshot.listener_pool(index).timeouts().clone();
+    let proto = match snapshot.tls[index] {
+        Some(_) => "https",
+        None => "http",
+    };
+    let client_ctx = Arc::new(Client {
+        snapshot,
+        index,
+        source,
+        destination,
+        proto,
+        tunnels: Mutex::new(JoinSet::new()),
+    });
+    let io = TokioIo::new(Metered::new(client, Arc::clone(meter)));
+    let service = {
+        let client_ctx = Arc::clone(&client_ctx);
+        service_fn(move |req| {
+            let client_ctx = Arc::clone(&client_ctx);
+            async move { Ok::<_, Infallible>(client_ctx.forward(req).await) }
+        })
+    };
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let session = async {
+        let conn = builder.serve_connection_with_upgrades(io, service);
+        tokio::pin!(conn);
+        tokio::select! {
+            ret = conn.as_mut() => ret,
+            _ = live.draining().cancelled() => {
+                // HTTP/1.1 关闭 keep-alive, HTTP/2 发送 GOAWAY, 正在处理的请求继续
+                conn.as_mut().graceful_shutdown();
+                conn.await
+            }
+        }
+        .map_err(io::Error::other)?;
+        let mut tunnels = mem::take(&mut *client_ctx.tunnels.lock().unwrap());
+        while tunnels.join_next().await.is_some() {}
+        Ok(())
+    };
+    watch(meter, session, &timeouts).await
+}
+
+impl Client {
+    async fn forward(&self, mut req: Request<Incoming>) -> Response<Body> {
+        let host = request_host(&req);
+        let path = req.uri().path().to_string();
+        let pool = self.snapshot.http_pool(self.index, host.as_deref(), &path);
+        let rules: Vec<_> = self.snapshot.config.listeners[self.index]
+            .http
+            .iter()
+            .flat_map(|http| &http.headers)
+            .filter(|rule| rule.matches(host.as_deref(), req.method().as_str(), &path))
+            .collect();
+        let vars = Vars {
+            client_ip: self.source.ip().to_string(),
+            request_id: request_id(req.headers()),
+            upstream: pool.name().to_string(),
+            host: host.unwrap_or_else(|| "-".into()),
+            method: req.method().to_string(),
+            path,
+            scheme: self.proto,
+        };
+
+        let upgrade = upgrade_protocol(req.headers());
+        let client_upgrade = upgrade.is_some().then(|| hyper::upgrade::on(&mut req));
+        prepare_request(&mut req, self.source, self.proto, upgrade);
+        if let Ok(request_id) = HeaderValue::from_str(&vars.request_id) {
+            req.headers_mut().insert(X_REQUEST_ID, request_id);
+        }
+        for rule in &rules {
+            rewrite_headers(req.headers_mut(), &rule.request, &vars);
+        }
+
+        let mut resp = self.send(req, pool, client_upgrade, &vars).await;
+        for rule in &rules {
+            rewrite_headers(resp.headers_mut(), &rule.response, &vars);
+        }
+        resp
+    }
+
+    async fn send(
+        &self,
+        req: Request<Incoming>,
+        pool: Arc<UpstreamPool>,
+        client_upgrade: Option<OnUpgrade>,
+        vars: &Vars,
+    ) -> Response<Body> {
+        let start = Instant::now();
+        // HTTP 模式下每个请求是一次尝试, 收到响应头部算成功
+        let Ok(attempt) = pool.admit() else {
+            warn!(
+                "Circuit breaker of upstream {} is open, rejecting {} {} ({})",
+                pool.name(),
+                vars.method,
+                vars.path,
+                vars.request_id
+            );
+            return error(StatusCode::SERVICE_UNAVAILABLE);
+        };
+        let Some((backend, mut sender, reused)) = self.connect(&pool).await else {
+            warn!(
+                "No backend available in upstream {} for {} {} ({})",
+                pool.name(),
+                vars.method,
+                vars.path,
+                vars.request_id
+            );
+            if let Some(attempt) = attempt {
+                attempt.failure();
+            }
+            return error(StatusCode::BAD_GATEWAY);
+        };
+        // backend 可能刚好关闭了空闲的连接, 没有请求体的幂等请求可以在新连接上重发一次
+        let replay = reused.then(|| replayable(&req)).flatten();
+        let mut result = sender.send_request(req.map(BodyExt::boxed)).await;
+        if let (Err(e), Some(req)) = (&result, replay) {
+            debug!(
+                "Request {} on reused connection to {} failed: {}, retrying on a new connection",
+                vars.request_id,
+                backend.addr(),
+                e
+            );
+            result = match self.dial(&pool, &backend).await {
+                Some(new_sender) => {
+                    sender = new_sender;
+                    sender.send_request(req).await
+                }
+                None => {
+                    if let Some(attempt) = attempt {
+                        attempt.failure();
+                    }
+                    return error(StatusCode::BAD_GATEWAY);
+                }
+            };
+        }
+        let mut resp = match result {
+            Ok(resp) => resp,
+            Err(e) => {
+                warn!(
+                    "Request {} to {} failed: {}",
+                    vars.request_id,
+                    backend.addr(),
+                    e
+                );
+                if let Some(attempt) = attempt {
+                    attempt.failure();
+                }
+                return error(StatusCode::BAD_GATEWAY);
+            }
+        };
+        if let Some(attempt) = attempt {
+            attempt.success();
+        }
+        info!(
+            "{} {} {} -> {} {} in {:?} ({})",
+            self.source,
+            vars.method,
+            vars.path,
+            backend.addr(),
+            resp.status(),
+            start.elapsed(),
+            vars.request_id
+        );
+
+        let upgraded = resp.status() == StatusCode::SWITCHING_PROTOCOLS;
+        match client_upgrade {
+            Some(client_upgrade) if upgraded => {
+                let upstream_upgrade = hyper::upgrade::on(&mut resp);
+                let timeouts = pool.timeouts().clone();
+                self.tunnels.lock().unwrap().spawn(tunnel(
+                    client_upgrade,
+                    upstream_upgrade,
+                    backend,
+                    timeouts,
+                ));
+            }
+            // 响应结束后连接可以发送下一个请求, 放回空闲列表
+            _ => {
+                tokio::spawn(async move {
+                    // 请求期间 backend 被 disable 的话不再复用
+                    if sender.ready().await.is_ok() && backend.state() != BackendState::Disabled {
+                        pool.idle_connections().put(backend.addr(), sender);
+                    }
+                });
+            }
+        }
+        remove_hop_by_hop(resp.headers_mut(), upgraded);
+        resp.map(BodyExt::boxed)
+    }
+
+    // 优先复用空闲连接, 否则和 TCP 模式一样 connect, 失败时换一个 backend.
+    // 最后一项表示是不是复用的连接
+    async fn connect(
+        &self,
+        pool: &UpstreamPool,
+    ) -> Option<(BackendGuard, SendRequest<Body>, bool)> {
+        let mut tried = Vec::new();
+        for _ in 0..pool.max_attempts() {
+            let backend = pool.select_excluding(self.source.ip(), &tried)?;
+            if let Some(sender) = pool.idle_connections().take(backend.addr()) {
+                return Some((backend, sender, true));
+            }
+            if let Some(sender) = self.dial(pool, &backend).await {
+                return Some((backend, sender, false));
+            }
+            tried.push(backend.addr().to_string());
+        }
+        None
+    }
+
+    // 建立到 backend 的新连接, 结果计入被动健康检查
+    async fn dial(&self, pool: &UpstreamPool, backend: &BackendGuard) -> Option<SendRequest<Body>> {
+        let result = timeout(pool.timeouts().connect, async {
+            let stream = connect(pool, backend.addr(), self.source, self.destination).await?;
+            let (sender, conn) = http1::handshake(TokioIo::new(stream))
+                .await
+                .map_err(io::Error::other)?;
+            let addr = backend.addr().to_string();
+            tokio::spawn(async move {
+                if let Err(e) = conn.with_upgrades().await {
+                    debug!("Connection to {} closed: {}", addr, e);
+                }
+            });
+            Ok::<_, io::Error>(sender)
+        })
+        .await;
+        match result {
+            Ok(Ok(sender)) => {
+                pool.report(backend, true);
+                return Some(sender);
+            }
+            Ok(Err(e)) => warn!(
+                "Failed to connect to {} for {}: {}",
+                backend.addr(),
+                self.source,
+                e
+            ),
+            Err(_) => warn!(
+                "Timed out connecting to {} for {}",
+                backend.addr(),
+                self.source
+            ),
+        }
+        pool.report(backend, false);
+        None
+    }
+}
+
+// 双方都切换协议之后, 像 TCP 模式一样双向转发, backend 被 disable 时关闭
+async fn tunnel(
+    client: OnUpgrade,
+    upstream: OnUpgrade,
+    backend: BackendGuard,
+    timeouts: TimeoutConfig,
+) {
+    let (client, upstream) = match tokio::try_join!(client, upstream) {
+        Ok(upgraded) => upgraded,
+        Err(e) => {
+            warn!("Upgrade with {} failed: {}", backend.addr(), e);
+            return;
+        }
+    };
+    let (mut client, mut upstream) = (TokioIo::new(client), TokioIo::new(upstream));
+    let meter = Meter::new();
+    let session = async {
+        tokio::select! {
+            ret = forward::copy_bidirectional(&mut client, &mut upstream, &meter) => ret,
+            _ = backend.disabled() => {
+                info!("Closing tunnel to disabled backend {}", backend.addr());
+                Ok(())
+            }
+        }
+    };
+    let transfer = watch(&meter, session, &timeouts).await;
+    backend.record(&transfer);
+}
+
+impl Vars {
+    fn get(&self, name: &str) -> Option<String> {
+        let value = match name {
+            "client_ip" => &self.client_ip,
+            "request_id" => &self.request_id,
+            "upstream" => &self.upstream,
+            "host" => &self.host,
+            "method" => &self.method,
+            "path" => &self.path,
+            "scheme" => self.scheme,
+            _ => return None,
+        };
+        Some(value.to_string())
+    }
+}
+
+// 沿用客户端或者前一层代理传来的 X-Request-Id, 没有时生成一个
+fn request_id(headers: &HeaderMap) -> String {
+    match headers
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+    {
+        Some(id) if !id.is_empty() => id.to_string(),
+        _ => format!("{:032x}", rand::random::<u128>()),
+    }
+}
+
+// 模板展开后不是合法头部值的跳过
+fn rewrite_headers(headers: &mut HeaderMap, actions: &HeaderActions, vars: &Vars) {
+    for name in &actions.remove {
+        headers.remove(name.as_str());
+    }
+    let render = |name: &str, template: &str| {
+        // 配置校验保证了头部名合法
+        let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+        let value = expand(template, |var| vars.get(var));
+        match HeaderValue::from_str(&value) {
+            Ok(value) => Some((name, value)),
+            Err(_) => {
+                warn!("Invalid value for header {}: {:?}", name, value);
+                None
+            }
+        }
+    };
+    for (name, template) in &actions.set {
+        if let Some((name, value)) = render(name, template) {
+            headers.insert(name, value);
+        }
+    }
+    for (name, template) in &actions.add {
+        if let Some((name, value)) = render(name, template) {
+            headers.append(name, value);
+        }
+    }
+}
+
+// HTTP/2 请求的 Host 在 URI 的 authority 中; 不包括端口, 小写
+// 复制没有请求体的幂等请求, 用于在新连接上重发; upgrade 的回调已经在之前取走了
+fn replayable(req: &Request<Incoming>) -> Option<Request<Body>> {
+    if !req.method().is_idempotent() || !req.body().is_end_stream() {
+        return None;
+    }
+    let mut replay = Request::new(Empty::new().map_err(|never| match never {}).boxed());
+    *replay.method_mut() = req.method().clone();
+    *replay.uri_mut() = req.uri().clone();
+    *replay.version_mut() = req.version();
+    *replay.headers_mut() = req.headers().clone();
+    Some(replay)
+}
+
+fn request_host(req: &Request<Incoming>) -> Option<String> {
+    let host = match req.uri().host() {
+        Some(host) => host,
+        None => {
+            let host = req.headers().get(header::HOST)?.to_str().ok()?;
+            match host.rsplit_once(':') {
+                // IPv6 地址中也有冒号, 端口在 ] 之后
+                Some((host, port)) if !port.contains(']') => host,
+                _ => host,
+            }
+        }
+    };
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    Some(host.to_ascii_lowercase())
+}
+
+// websocket 等升级请求的 Upgrade 头部; HTTP/2 没有 upgrade
+fn upgrade_protocol(headers: &HeaderMap) -> Option<HeaderValue> {
+    let connection_upgrade = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    match connection_upgrade {
+        true => headers.get(header::UPGRADE).cloned(),
+        false => None,
+    }
+}
+
+// 转换成发给 backend 的 HTTP/1.1 请求, 追加客户端地址
+fn prepare_request(
+    req: &mut Request<Incoming>,
+    source: SocketAddr,
+    proto: &'static str,
+    upgrade: Option<HeaderValue>,
+) {
+    if !req.headers().contains_key(header::HOST) {
+        if let Some(authority) = req.uri().authority() {
+            if let Ok(host) = HeaderValue::from_str(authority.as_str()) {
+                req.headers_mut().insert(header::HOST, host);
+            }
+        }
+    }
+    // backend 收到的 URI 只有路径和查询参数
+    let path = req.uri().path_and_query().map_or("/", |path| path.as_str());
+    *req.uri_mut() = Uri::try_from(path).unwrap_or_else(|_| Uri::from_static("/"));
+    *req.version_mut() = Version::HTTP_11;
+
+    let headers = req.headers_mut();
+    remove_hop_by_hop(headers, false);
+    if let Some(protocol) = upgrade {
+        headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+        headers.insert(header::UPGRADE, protocol);
+    }
+
+    let mut forwarded_for: Vec<_> = headers
+        .get_all(&X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .map(str::to_string)
+        .collect();
+    forwarded_for.push(source.ip().to_string());
+    if let Ok(value) = HeaderValue::from_str(&forwarded_for.join(", ")) {
+        headers.insert(X_FORWARDED_FOR, value);
+    }
+    headers.insert(X_FORWARDED_PROTO, HeaderValue::from_static(proto));
+}
+
+// 包括 Connection 中列出的头部; 101 响应需要保留 Connection 和 Upgrade
+fn remove_hop_by_hop(headers: &mut HeaderMap, keep_upgrade: bool) {
+    let listed: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in listed.iter().chain(&HOP_BY_HOP) {
+        let upgrade_header = *name == header::CONNECTION || *name == header::UPGRADE;
+        if !(keep_upgrade && upgrade_header) {
+            headers.remove(name);
+        }
+    }
+}
+
+fn error(status: StatusCode) -> Response<Body> {
+    let reason = status.canonical_reason().unwrap_or_default();
+    let body = Full::new(Bytes::from(reason))
+        .map_err(|never| match never {})
+        .boxed();
+    let mut resp = Response::new(body);
+    *resp.status_mut() = status;
+    resp
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hop_by_hop_headers_should_be_removed() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            header::CONNECTION,
+            HeaderValue::from_static("keep-alive, x-secret"),
+        );
+        headers.insert("keep-alive", HeaderValue::from_static("timeout=5"));
+        headers.insert("x-secret", HeaderValue::from_static("1"));
+        headers.insert(
+            header::TRANSFER_ENCODING,
+            HeaderValue::from_static("chunked"),
+        );
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+        remove_hop_by_hop(&mut headers, false);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key(header::CONTENT_TYPE));
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONNECTION, HeaderValue::from_static("Upgrade"));
+        headers.insert(header::UPGRADE, HeaderValue::from_static("websocket"));
+        assert_eq!(
+            upgrade_protocol(&headers),
+            Some(HeaderValue::from_static("websocket"))
+        );
+        remove_hop_by_hop(&mut headers, true);
+        assert_eq!(headers.len(), 2);
+    }
+
+    #[test]
+    fn header_actions_should_remove_set_and_add_with_variables() {
+        let vars = Vars {
+            client_ip: "10.0.0.1".into(),
+            request_id: "abc".into(),
+            upstream: "api".into(),
+            host: "api.example.com".into(),
+            method: "GET".into(),
+            path: "/users".into(),
+            scheme: "https",
+        };
+        let actions = HeaderActions {
+            remove: vec!["Cookie".into()],
+            set: [("x-upstream".into(), "$upstream $scheme://$host$path".into())].into(),
+            add: [
+                ("via".into(), "minginx $request_id $unknown".into()),
+                ("x-bad".into(), "\n".into()),
+            ]
+            .into(),
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COOKIE, HeaderValue::from_static("a=1"));
+        headers.insert("x-upstream", HeaderValue::from_static("old"));
+        headers.insert(header::VIA, HeaderValue::from_static("1.1 lb"));
+        rewrite_headers(&mut headers, &actions, &vars);
+
+        assert!(!headers.contains_key(header::COOKIE));
+        assert_eq!(headers["x-upstream"], "api https://api.example.com/users");
+        let via: Vec<_> = headers.get_all(header::VIA).iter().collect();
+        assert_eq!(via, ["1.1 lb", "minginx abc $unknown"]);
+        assert!(!headers.contains_key("x-bad"));
+    }
+}
@@ -0,0 +1,159 @@
+use std::{
+    net::SocketAddr,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use bytes::Bytes;
+use tokio::{
+    io::{self, AsyncRead, AsyncWrite, AsyncWriteExt, ReadBuf},
+    sync::mpsc::{self, error::TrySendError},
+    time::timeout,
+};
+use tracing::{debug, warn};
+
+use super::{proxy::connect_upstream, UpstreamPool};
+
+// 等待发给 shadow 的数据块数, 每块是从客户端读到的一次数据
+const MIRROR_QUEUE: usize = 64;
+// 客户端发完之后继续读取并丢弃 shadow 回复的最长时间
+const LINGER: Duration = Duration::from_secs(5);
+
+/// 把客户端发来的数据复制一份给 shadow upstream. 只放进队列, 从不等待;
+/// shadow 跟不上或者出错时停止镜像, 不影响主连接
+#[derive(Debug)]
+pub(crate) struct Mirror {
+    source: SocketAddr,
+    // 停止镜像之后为 None
+    tx: Option<mpsc::Sender<Bytes>>,
+}
+
+// 读取时把数据交给 mirror 的客户端连接, 没有 mirror 时只是转发
+#[derive(Debug)]
+pub(crate) struct Mirrored<S> {
+    inner: S,
+    mirror: Option<Mirror>,
+}
+
+impl Mirror {
+    /// 在后台连接 shadow upstream, `initial` 是已经从客户端读到的数据, 比如 ClientHello
+    pub(crate) fn start(
+        pool: Arc<UpstreamPool>,
+        source: SocketAddr,
+        destination: SocketAddr,
+        initial: &[u8],
+    ) -> Self {
+        let (tx, rx) = mpsc::channel(MIRROR_QUEUE);
+        tokio::spawn(shadow(pool, source, destination, rx));
+        let mut mirror = Self {
+            source,
+            tx: Some(tx),
+        };
+        if !initial.is_empty() {
+            mirror.send(initial);
+        }
+        mirror
+    }
+
+    fn send(&mut self, data: &[u8]) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        if let Err(e) = tx.try_send(Bytes::copy_from_slice(data)) {
+            // 丢掉一部分数据之后 shadow 收到的字节流就不完整了, 直接停止
+            if let TrySendError::Full(_) = e {
+                warn!(
+                    "Shadow upstream is too slow, stop mirroring {}",
+                    self.source
+                );
+            }
+            self.tx = None;
+        }
+    }
+}
+
+impl<S> Mirrored<S> {
+    pub(crate) fn new(inner: S, mirror: Option<Mirror>) -> Self {
+        Self { inner, mirror }
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for Mirrored<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let before = buf.filled().len();
+        ready!(Pin::new(&mut self.inner).poll_read(cx, buf))?;
+        if let Some(mirror) = &mut self.mirror {
+            if buf.filled().len() > before {
+                mirror.send(&buf.filled()[before..]);
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for Mirrored<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+// 把队列中的数据发给 shadow 的一个 backend, 回复读出来丢掉
+async fn shadow(
+    pool: Arc<UpstreamPool>,
+    source: SocketAddr,
+    destination: SocketAddr,
+    mut rx: mpsc::Receiver<Bytes>,
+) {
+    let Some((backend, upstream)) = connect_upstream(&pool, source, destination).await else {
+        warn!(
+            "No backend available in shadow upstream {}, stop mirroring {}",
+            pool.name(),
+            source
+        );
+        return;
+    };
+    let (mut reader, mut writer) = io::split(upstream);
+    // 不读回复的话 backend 可能因为发送缓冲区满而停下来
+    let mut sink = io::sink();
+    let discard = io::copy(&mut reader, &mut sink);
+    tokio::pin!(discard);
+    let forward = async {
+        while let Some(data) = rx.recv().await {
+            writer.write_all(&data).await?;
+        }
+        writer.shutdown().await
+    };
+    let ret = tokio::select! {
+        ret = forward => match ret {
+            Ok(()) => {
+                let _ = timeout(LINGER, &mut discard).await;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        },
+        // shadow 提前关闭了连接
+        ret = &mut discard => ret.map(|_| ()),
+    };
+    match ret {
+        Ok(()) => debug!("Mirrored {} to {}", source, backend.addr()),
+        Err(e) => debug!("Stop mirroring {} to {}: {}", source, backend.addr(), e),
+    }
+}
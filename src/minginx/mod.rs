@@ -0,0 +1,45 @@
+mod access;
+mod access_log;
+mod admin;
+mod breaker;
+mod capture;
+mod config;
+mod fault;
+mod forward;
+#[cfg(target_os = "linux")]
+mod handover;
+mod health;
+mod http;
+mod mirror;
+mod proxy;
+mod proxy_protocol;
+mod reload;
+mod sessions;
+mod sni;
+mod stats;
+mod tls;
+mod udp;
+mod upstream;
+
+pub use breaker::{CircuitBreaker, CircuitState, CircuitStats};
+pub use capture::{
+    replay, CaptureError, CaptureFile, CaptureHeader, CaptureRecord, Direction, ReplayOptions,
+    ReplayReport,
+};
+pub use config::{
+    AccessConfig, AccessLogConfig, AccessLogFormat, AdminConfig, CaptureConfig,
+    CircuitBreakerConfig, Config, ConfigError, Fault, FaultConfig, Forwarding, HeaderActions,
+    HeaderRuleConfig, HealthCheckConfig, HttpConfig, HttpRouteConfig, ListenerConfig,
+    ListenerTlsConfig, MirrorConfig, MissingSni, RateLimitConfig, ServerConfig, ShutdownConfig,
+    SniRoutingConfig, Strategy, TimeoutConfig, UdpConfig, UpstreamConfig, UpstreamTlsConfig,
+    ENV_PREFIX,
+};
+pub use proxy::{check, proxy, run, start, ProxyHandle};
+pub use proxy_protocol::{
+    encode_proxy_header, read_proxy_header, ProxyHeader, ProxyProtocol, ProxyProtocolError,
+};
+pub use sessions::ShutdownReport;
+pub use sni::{read_client_hello, ClientHelloError};
+pub use stats::{CloseReason, TrafficStats, Transfer};
+pub use tls::MaybeTlsStream;
+pub use upstream::{Backend, BackendGuard, BackendState, BackendStats, HealthEvent, UpstreamPool};
@@ -0,0 +1,693 @@
+use std::{
+    future::{self, Future},
+    net::SocketAddr,
+    path::PathBuf,
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use chrono::Utc;
+use tokio::{
+    io::{self, AsyncWriteExt},
+    net::{TcpListener, TcpStream, UdpSocket},
+    signal::unix::{signal, SignalKind},
+    task::JoinSet,
+    time::{sleep, timeout, Instant},
+};
+use tracing::{info, warn};
+
+#[cfg(target_os = "linux")]
+use super::handover;
+use super::{
+    access_log::AccessRecord,
+    admin,
+    capture::{CaptureHeader, Captured, Captures, Recorder},
+    fault::Faults,
+    forward::{self, Meter},
+    http,
+    mirror::{Mirror, Mirrored},
+    proxy_protocol::{encode_proxy_header, read_proxy_header},
+    reload::{ConfigWatcher, Runtime, Snapshot},
+    sessions::{LiveSession, Protocol, ShutdownReport},
+    sni::read_client_hello,
+    tls, udp, BackendGuard, CloseReason, Config, Forwarding, MaybeTlsStream, TimeoutConfig,
+    TrafficStats, Transfer, UpstreamPool,
+};
+
+// 等待客户端发送 PROXY 头部或者 ClientHello 的时间
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+// 等待客户端完成 TLS 握手的时间
+const TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// 启动时绑定, 或者从旧进程接过的 socket
+#[derive(Debug)]
+pub(crate) enum Socket {
+    Tcp(std::net::TcpListener),
+    Udp(std::net::UdpSocket),
+}
+
+/// 运行中的 minginx, drop 时停止所有 listener
+#[derive(Debug)]
+pub struct ProxyHandle {
+    local_addrs: Vec<SocketAddr>,
+    admin_addr: Option<SocketAddr>,
+    runtime: Arc<Runtime>,
+    tasks: JoinSet<Result<()>>,
+}
+
+/// 检查配置能否启动: 除了 `Config::load` 的校验, 还加载 TLS 证书、私钥和 CA.
+/// 不绑定地址, 不打开访问日志
+pub fn check(config: &Config) -> Result<()> {
+    Runtime::check(config)
+}
+
+/// 绑定所有 listener 并开始 accept, 立即返回.
+/// 配置了 handover_socket 时先从正在运行的旧进程接过地址相同的 listener
+pub async fn start(config: Config) -> Result<ProxyHandle> {
+    let (mut inherited, takeover) = inherit_sockets(&config).await?;
+    let mut listeners = Vec::with_capacity(config.listeners.len());
+    for listener_config in &config.listeners {
+        let addr = listener_config.listen_addr;
+        let socket = match listener_config.udp {
+            Some(_) => Socket::Udp(bind_udp(addr, &mut inherited).await?),
+            None => Socket::Tcp(bind_tcp(addr, &mut inherited).await?),
+        };
+        let local_addr = socket.local_addr()?;
+        info!(
+            "Listen address: {}{}, upstream: {}",
+            local_addr,
+            if socket.is_udp() { " (udp)" } else { "" },
+            listener_config.upstream
+        );
+        listeners.push((socket, local_addr));
+    }
+
+    let admin = match &config.admin {
+        Some(admin) => {
+            let listener = bind_tcp(admin.listen_addr, &mut inherited).await?;
+            info!("Admin address: {}", listener.local_addr()?);
+            Some(listener)
+        }
+        None => None,
+    };
+    // 旧进程上有, 新配置中已经删掉的地址
+    for socket in inherited {
+        info!("Closing inherited socket {:?}", socket.local_addr());
+    }
+
+    #[cfg(target_os = "linux")]
+    let handover = {
+        let sockets = listeners
+            .iter()
+            .map(|(socket, _)| socket.try_clone())
+            .chain(admin.iter().map(|l| l.try_clone().map(Socket::Tcp)))
+            .collect::<io::Result<Vec<_>>>()?;
+        config
+            .shutdown
+            .handover_socket
+            .clone()
+            .map(|path| (path, sockets))
+    };
+
+    let runtime = Arc::new(Runtime::new(config)?);
+    let mut local_addrs = Vec::with_capacity(listeners.len());
+    let mut tasks = JoinSet::new();
+    for (index, (socket, local_addr)) in listeners.into_iter().enumerate() {
+        local_addrs.push(local_addr);
+        let runtime = Arc::clone(&runtime);
+        match socket {
+            Socket::Tcp(listener) => {
+                tasks.spawn(serve(TcpListener::from_std(listener)?, index, runtime));
+            }
+            Socket::Udp(socket) => {
+                tasks.spawn(udp::serve(UdpSocket::from_std(socket)?, index, runtime));
+            }
+        }
+    }
+    let admin_addr = match admin {
+        Some(listener) => {
+            let addr = listener.local_addr()?;
+            let listener = TcpListener::from_std(listener)?;
+            tasks.spawn(admin::serve(listener, Arc::clone(&runtime)));
+            Some(addr)
+        }
+        None => None,
+    };
+    // 所有 socket 和 Runtime 都准备好之后才让旧进程停止, 在这之前失败的话旧进程继续运行
+    #[cfg(target_os = "linux")]
+    if let Some(takeover) = takeover {
+        if let Err(e) = takeover.ack() {
+            warn!("Failed to reply to the old process: {:#}", e);
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    let _ = takeover;
+    // 新进程接过 listener 之后停止
+    #[cfg(target_os = "linux")]
+    if let Some((path, sockets)) = handover {
+        let runtime = Arc::clone(&runtime);
+        tasks.spawn(async move {
+            handover::serve(path, sockets).await?;
+            runtime.shutdown().cancel();
+            Ok(())
+        });
+    }
+
+    Ok(ProxyHandle {
+        local_addrs,
+        admin_addr,
+        runtime,
+        tasks,
+    })
+}
+
+/// 启动并一直运行, 任何一个 listener 出错就返回.
+/// 收到 SIGTERM 或 SIGINT 时停止 accept, 等已有的连接结束后返回
+pub async fn run(config: Config) -> Result<()> {
+    let mut handle = start(config).await?;
+    handle.shutdown_on_signal()?;
+    handle.wait().await?;
+    Ok(())
+}
+
+impl Socket {
+    pub(crate) fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self {
+            Self::Tcp(listener) => listener.local_addr(),
+            Self::Udp(socket) => socket.local_addr(),
+        }
+    }
+
+    pub(crate) fn is_udp(&self) -> bool {
+        matches!(self, Self::Udp(_))
+    }
+
+    /// dup 出一个指向同一个 socket 的 fd
+    pub(crate) fn try_clone(&self) -> io::Result<Self> {
+        Ok(match self {
+            Self::Tcp(listener) => Self::Tcp(listener.try_clone()?),
+            Self::Udp(socket) => Self::Udp(socket.try_clone()?),
+        })
+    }
+}
+
+// 配置了 handover_socket 并且旧进程在运行时, 取得它的所有 socket, 启动成功后再回复
+#[cfg(target_os = "linux")]
+async fn inherit_sockets(config: &Config) -> Result<(Vec<Socket>, Option<handover::Takeover>)> {
+    let Some(path) = config.shutdown.handover_socket.clone() else {
+        return Ok((Vec::new(), None));
+    };
+    tokio::task::spawn_blocking(move || handover::receive(&path)).await?
+}
+
+// 配置校验保证其他平台上没有 handover_socket
+#[cfg(not(target_os = "linux"))]
+async fn inherit_sockets(_config: &Config) -> Result<(Vec<Socket>, Option<()>)> {
+    Ok((Vec::new(), None))
+}
+
+// 优先使用从旧进程接过的同一地址的 socket, 端口 0 总是重新绑定
+fn take_inherited(inherited: &mut Vec<Socket>, addr: SocketAddr, udp: bool) -> Option<Socket> {
+    let i = inherited
+        .iter()
+        .position(|socket| socket.is_udp() == udp && socket.local_addr().ok() == Some(addr))?;
+    let socket = inherited.swap_remove(i);
+    Some(socket)
+}
+
+async fn bind_tcp(addr: SocketAddr, inherited: &mut Vec<Socket>) -> Result<std::net::TcpListener> {
+    if let Some(Socket::Tcp(listener)) = take_inherited(inherited, addr, false) {
+        listener.set_nonblocking(true)?;
+        return Ok(listener);
+    }
+    Ok(TcpListener::bind(addr).await?.into_std()?)
+}
+
+async fn bind_udp(addr: SocketAddr, inherited: &mut Vec<Socket>) -> Result<std::net::UdpSocket> {
+    if let Some(Socket::Udp(socket)) = take_inherited(inherited, addr, true) {
+        socket.set_nonblocking(true)?;
+        return Ok(socket);
+    }
+    Ok(UdpSocket::bind(addr).await?.into_std()?)
+}
+
+impl ProxyHandle {
+    /// 和配置中 listeners 的顺序一致
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.local_addrs
+    }
+
+    /// 配置了 admin 时 admin 接口的地址
+    pub fn admin_addr(&self) -> Option<SocketAddr> {
+        self.admin_addr
+    }
+
+    /// 从启动开始累计的流量统计
+    pub fn stats(&self) -> TrafficStats {
+        self.runtime.traffic().snapshot()
+    }
+
+    /// 新连接当前使用的配置
+    pub fn config(&self) -> Arc<Config> {
+        Arc::clone(&self.runtime.current().config)
+    }
+
+    /// 新连接当前使用的 upstream, reload 之后可能是另一个 pool
+    pub fn pool(&self, name: &str) -> Option<Arc<UpstreamPool>> {
+        self.runtime.current().pools.get(name).cloned()
+    }
+
+    /// 替换新连接使用的配置, 已经建立的连接继续使用原来的 upstream.
+    /// 配置不合法或者修改了 listener 地址时返回错误, 原来的配置继续生效
+    pub fn reload(&self, config: Config) -> Result<()> {
+        self.runtime.reload(config)
+    }
+
+    /// 配置文件变化或者收到 SIGHUP 时自动 reload
+    pub fn watch(&mut self, path: impl Into<PathBuf>) -> Result<()> {
+        let watcher = ConfigWatcher::new(path.into(), Arc::clone(&self.runtime))?;
+        self.tasks.spawn(watcher.run());
+        Ok(())
+    }
+
+    /// 收到 SIGTERM 或 SIGINT 时停止, `wait` 等连接结束后返回
+    pub fn shutdown_on_signal(&mut self) -> Result<()> {
+        let mut terminate = signal(SignalKind::terminate())?;
+        let mut interrupt = signal(SignalKind::interrupt())?;
+        let runtime = Arc::clone(&self.runtime);
+        self.tasks.spawn(async move {
+            tokio::select! {
+                _ = terminate.recv() => info!("Received SIGTERM, shutting down"),
+                _ = interrupt.recv() => info!("Received SIGINT, shutting down"),
+            }
+            runtime.shutdown().cancel();
+            Ok(())
+        });
+        Ok(())
+    }
+
+    /// 停止 accept 新连接, 等已有的连接结束; 超过 grace_period 后强制关闭剩下的连接
+    pub async fn shutdown(self) -> ShutdownReport {
+        self.runtime.shutdown().cancel();
+        self.drain().await
+    }
+
+    /// 一直运行直到出错, 或者收到停止信号, 或者 listener 被新进程接走.
+    /// 停止时和 `shutdown` 一样等待已有的连接
+    pub async fn wait(mut self) -> Result<ShutdownReport> {
+        let shutdown = self.runtime.shutdown().clone();
+        loop {
+            tokio::select! {
+                ret = self.tasks.join_next() => match ret {
+                    Some(ret) => ret??,
+                    None => break,
+                },
+                _ = shutdown.cancelled() => break,
+            }
+        }
+        Ok(self.drain().await)
+    }
+
+    async fn drain(mut self) -> ShutdownReport {
+        // 关闭 listener 和 admin 接口, 停止监听配置文件
+        self.tasks.abort_all();
+        while self.tasks.join_next().await.is_some() {}
+        let grace = self.runtime.current().config.shutdown.grace_period;
+        info!("Stopped accepting, waiting up to {:?} for sessions", grace);
+        let report = self.runtime.sessions().drain(grace).await;
+        // 连接都关闭之后抓包文件不会再有新的数据, 等它们写完
+        self.runtime.captures().flush().await;
+        info!(
+            "Shutdown complete, {} sessions drained, {} cut",
+            report.drained, report.cut
+        );
+        report
+    }
+}
+
+// 一个连接的处理结果, 用于统计和访问日志
+struct Session {
+    // 客户端的真实地址
+    client: SocketAddr,
+    upstream: String,
+    backend: Option<String>,
+    transfer: Transfer,
+}
+
+async fn serve(listener: TcpListener, index: usize, runtime: Arc<Runtime>) -> Result<()> {
+    let local_addr = listener.local_addr()?;
+    loop {
+        let (client, addr) = tokio::select! {
+            ret = listener.accept() => ret?,
+            _ = runtime.shutdown().cancelled() => return Ok(()),
+        };
+        // 每个新连接按当前的配置选择 upstream, 已经建立的连接不受 reload 影响
+        let snapshot = runtime.current();
+        let access = &snapshot.config.listeners[index].access;
+        let permit = runtime.limiter(index).check(access, addr.ip());
+        match &permit {
+            Ok(_) => info!("New connection from {}", addr),
+            Err(denied) => warn!("Denied connection from {}: {}", addr, denied),
+        }
+        let runtime = Arc::clone(&runtime);
+        tokio::spawn(async move {
+            let start = Instant::now();
+            runtime.traffic().open();
+            // 被拒绝的连接直接关闭, 同样计入统计和访问日志
+            let session = match permit {
+                Ok(_permit) => {
+                    let upstream = &snapshot.config.listeners[index].upstream;
+                    let live = runtime
+                        .sessions()
+                        .open(Protocol::Tcp, addr, local_addr, upstream);
+                    let captures = runtime.captures();
+                    let session = handle(client, addr, &snapshot, index, &live, captures).await;
+                    runtime.sessions().close(&live);
+                    session
+                }
+                Err(_) => {
+                    let upstream = &snapshot.config.listeners[index].upstream;
+                    Session::failed(addr, upstream, CloseReason::Denied)
+                }
+            };
+            runtime.traffic().close(&session.transfer);
+            snapshot.access_log.write(&AccessRecord {
+                time: Utc::now(),
+                client: session.client,
+                listener: local_addr,
+                upstream: session.upstream,
+                backend: session.backend,
+                bytes_in: session.transfer.bytes_in,
+                bytes_out: session.transfer.bytes_out,
+                duration_ms: start.elapsed().as_millis() as u64,
+                reason: session.transfer.reason,
+            });
+        });
+    }
+}
+
+impl Session {
+    fn failed(client: SocketAddr, upstream: &str, reason: CloseReason) -> Self {
+        Self {
+            client,
+            upstream: upstream.to_string(),
+            backend: None,
+            transfer: Transfer::failed(reason),
+        }
+    }
+}
+
+// 处理第 index 个 listener 上的一个客户端连接
+async fn handle(
+    mut client: TcpStream,
+    addr: SocketAddr,
+    snapshot: &Arc<Snapshot>,
+    index: usize,
+    live: &LiveSession,
+    captures: &Arc<Captures>,
+) -> Session {
+    let listener = &snapshot.config.listeners[index];
+    let faults = Faults::pick(&listener.faults);
+    if faults.drop_connect() {
+        info!("Dropped connection from {} by fault injection", addr);
+        return Session::failed(addr, &listener.upstream, CloseReason::Fault);
+    }
+    if faults.reset_after().is_some() {
+        // 关闭时发送 RST 而不是 FIN
+        let _ = client.set_linger(Some(Duration::ZERO));
+    }
+    let (mut source, mut destination) = (addr, client.local_addr().unwrap_or(addr));
+    // PROXY 头部在 TLS 握手之前, 是明文
+    if listener.accept_proxy_protocol {
+        match timeout(HEADER_TIMEOUT, read_proxy_header(&mut client)).await {
+            Ok(Ok(Some(header))) => (source, destination) = (header.source, header.destination),
+            Ok(Ok(None)) => {}
+            Ok(Err(e)) => {
+                warn!("Invalid PROXY header from {}: {}", addr, e);
+                return Session::failed(addr, &listener.upstream, CloseReason::ProtocolError);
+            }
+            Err(_) => {
+                warn!("Timed out reading PROXY header from {}", addr);
+                return Session::failed(addr, &listener.upstream, CloseReason::ProtocolError);
+            }
+        }
+    }
+
+    // SNI 路由时先读出 ClientHello, 连上 backend 之后原样发过去
+    let mut client_hello = Vec::new();
+    let pool = match &listener.sni_routing {
+        Some(_) => {
+            let read = read_client_hello(&mut client, &mut client_hello);
+            let sni = match timeout(HEADER_TIMEOUT, read).await {
+                Ok(Ok(sni)) => sni,
+                Ok(Err(e)) => {
+                    warn!("Invalid ClientHello from {}: {}", source, e);
+                    return Session::failed(source, &listener.upstream, CloseReason::ProtocolError);
+                }
+                Err(_) => {
+                    warn!("Timed out reading ClientHello from {}", source);
+                    return Session::failed(source, &listener.upstream, CloseReason::ProtocolError);
+                }
+            };
+            match snapshot.sni_pool(index, sni.as_deref()) {
+                Some(pool) => pool,
+                None => {
+                    warn!("Rejected {} without SNI", source);
+                    return Session::failed(source, &listener.upstream, CloseReason::NoUpstream);
+                }
+            }
+        }
+        None => snapshot.listener_pool(index),
+    };
+
+    let client = match &snapshot.tls[index] {
+        Some(tls) => match timeout(TLS_HANDSHAKE_TIMEOUT, tls::accept(tls, client)).await {
+            Ok(Ok(client)) => client,
+            Ok(Err(e)) => {
+                warn!("TLS handshake with {} failed: {}", source, e);
+                return Session::failed(source, pool.name(), CloseReason::ProtocolError);
+            }
+            Err(_) => {
+                warn!("Timed out in TLS handshake with {}", source);
+                return Session::failed(source, pool.name(), CloseReason::ProtocolError);
+            }
+        },
+        None => client.into(),
+    };
+
+    // HTTP 模式下每个请求单独选择 upstream 和 backend
+    if listener.http.is_some() {
+        live.set_route(source, pool.name(), None);
+        let serve = http::serve(
+            client,
+            Arc::clone(snapshot),
+            index,
+            source,
+            destination,
+            live,
+        );
+        let transfer = live.run(serve).await;
+        return Session {
+            client: source,
+            upstream: pool.name().to_string(),
+            backend: None,
+            transfer,
+        };
+    }
+
+    let Ok(attempt) = pool.admit() else {
+        warn!(
+            "Circuit breaker of upstream {} is open, closing {}",
+            pool.name(),
+            source
+        );
+        return Session::failed(source, pool.name(), CloseReason::CircuitOpen);
+    };
+    let Some((backend, mut upstream)) = connect_upstream(&pool, source, destination).await else {
+        warn!(
+            "No backend available in upstream {}, closing {}",
+            pool.name(),
+            source
+        );
+        if let Some(attempt) = attempt {
+            attempt.failure();
+        }
+        return Session::failed(source, pool.name(), CloseReason::NoUpstream);
+    };
+    if !client_hello.is_empty() {
+        if let Err(e) = upstream.write_all(&client_hello).await {
+            warn!("Failed to send ClientHello to {}: {}", backend.addr(), e);
+            return Session {
+                backend: Some(backend.addr().to_string()),
+                ..Session::failed(source, pool.name(), CloseReason::Error)
+            };
+        }
+    }
+    live.set_route(source, pool.name(), Some(backend.addr()));
+    let forward = async {
+        let mirror = snapshot
+            .mirror_pool(&pool)
+            .map(|shadow| Mirror::start(shadow, source, destination, &client_hello));
+        let recorder = snapshot.config.capture.as_ref().and_then(|config| {
+            let header = CaptureHeader {
+                session: live.id(),
+                start: Utc::now(),
+                client: source,
+                listener: destination,
+                upstream: pool.name().to_string(),
+                backend: backend.addr().to_string(),
+            };
+            Recorder::start(config, header, captures, &client_hello)
+        });
+        if mirror.is_none() && recorder.is_none() && !faults.needs_copy() {
+            let timeouts = pool.timeouts();
+            return proxy_metered(client, upstream, timeouts, pool.forwarding(), live.meter())
+                .await;
+        }
+        // 镜像、抓包和故障注入需要读写客户端的数据, 只能使用 copy
+        let client = Captured::new(client, recorder);
+        let mut client = faults.wrap(Mirrored::new(client, mirror));
+        let meter = live.meter();
+        let session = forward::copy_bidirectional(&mut client, &mut upstream, meter);
+        let mut transfer = watch(meter, faults.run(session), pool.timeouts()).await;
+        // 注入的故障不计入熔断
+        if faults.tripped() {
+            transfer.reason = CloseReason::Fault;
+        }
+        transfer
+    };
+    let mut transfer = match attempt {
+        Some(attempt) => live.run(attempt.observe(forward)).await,
+        None => live.run(forward).await,
+    };
+    transfer.bytes_in += client_hello.len() as u64;
+    backend.record(&transfer);
+    // backend 在连接结束时 drop, 活跃连接数随之减少
+    Session {
+        client: source,
+        upstream: pool.name().to_string(),
+        backend: Some(backend.addr().to_string()),
+        transfer,
+    }
+}
+
+// connect 失败或超时就换一个没试过的健康 backend, 最多尝试 max_attempts 次
+pub(crate) async fn connect_upstream(
+    pool: &UpstreamPool,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Option<(BackendGuard, MaybeTlsStream)> {
+    let mut tried = Vec::new();
+    for _ in 0..pool.max_attempts() {
+        let backend = pool.select_excluding(source.ip(), &tried)?;
+        let result = timeout(
+            pool.timeouts().connect,
+            connect(pool, backend.addr(), source, destination),
+        )
+        .await;
+        // 被动健康检查: 真实流量 connect 的结果同样计入 backend 的健康状态
+        match result {
+            Ok(Ok(upstream)) => {
+                pool.report(&backend, true);
+                return Some((backend, upstream));
+            }
+            Ok(Err(e)) => warn!(
+                "Failed to connect to {} for {}: {}",
+                backend.addr(),
+                source,
+                e
+            ),
+            Err(_) => warn!("Timed out connecting to {} for {}", backend.addr(), source),
+        }
+        pool.report(&backend, false);
+        tried.push(backend.addr().to_string());
+    }
+    None
+}
+
+// 建立到 backend 的连接: TCP connect, 发送 PROXY 头部, 然后 TLS 握手
+pub(crate) async fn connect(
+    pool: &UpstreamPool,
+    addr: &str,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> io::Result<MaybeTlsStream> {
+    let mut upstream = TcpStream::connect(addr).await?;
+    if let Some(version) = pool.send_proxy_protocol() {
+        let header = encode_proxy_header(version, source, destination);
+        upstream.write_all(&header).await?;
+    }
+    match pool.tls() {
+        Some(tls) => tls.connect(addr, upstream).await,
+        None => Ok(upstream.into()),
+    }
+}
+
+/// 在 client 和 upstream 之间双向转发, 直到两个方向都结束或者超时.
+/// 两端都是普通 TCP 连接时才能使用 splice, 否则使用 copy
+pub async fn proxy(
+    client: impl Into<MaybeTlsStream>,
+    upstream: impl Into<MaybeTlsStream>,
+    timeouts: &TimeoutConfig,
+    forwarding: Forwarding,
+) -> Transfer {
+    let meter = Meter::new();
+    proxy_metered(client.into(), upstream.into(), timeouts, forwarding, &meter).await
+}
+
+// 和 `proxy` 一样, 转发的字节数实时记录在 meter 中
+pub(crate) async fn proxy_metered(
+    mut client: MaybeTlsStream,
+    mut upstream: MaybeTlsStream,
+    timeouts: &TimeoutConfig,
+    forwarding: Forwarding,
+    meter: &Meter,
+) -> Transfer {
+    #[cfg(target_os = "linux")]
+    if forwarding == Forwarding::Splice {
+        if let (Some(client), Some(upstream)) = (client.as_tcp(), upstream.as_tcp()) {
+            let session = forward::splice::bidirectional(client, upstream, meter);
+            return watch(meter, session, timeouts).await;
+        }
+    }
+    // 其他平台上配置校验不允许 splice
+    #[cfg(not(target_os = "linux"))]
+    let _ = forwarding;
+    let session = forward::copy_bidirectional(&mut client, &mut upstream, meter);
+    watch(meter, session, timeouts).await
+}
+
+// 运行 session 直到结束, 或者超过 idle / max_session 被取消
+pub(crate) async fn watch(
+    meter: &Meter,
+    session: impl Future<Output = io::Result<()>>,
+    timeouts: &TimeoutConfig,
+) -> Transfer {
+    let reason = tokio::select! {
+        ret = session => match ret {
+            Ok(()) => CloseReason::Closed,
+            Err(e) => {
+                warn!("Error: {}", e);
+                CloseReason::Error
+            }
+        },
+        _ = meter.idle(timeouts.idle) => {
+            info!("Closing idle session after {:?}", timeouts.idle);
+            CloseReason::IdleTimeout
+        }
+        _ = limit(timeouts.max_session) => {
+            info!("Closing session after max session time {:?}", timeouts.max_session);
+            CloseReason::MaxSession
+        }
+    };
+    meter.transfer(reason)
+}
+
+// 0 表示不限制
+async fn limit(duration: Duration) {
+    if duration.is_zero() {
+        return future::pending().await;
+    }
+    sleep(duration).await
+}
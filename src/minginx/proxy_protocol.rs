@@ -0,0 +1,308 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// v2 的 12 字节签名
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+// v1 的头部最长 107 字节, 包括结尾的 \r\n
+const V1_MAX_LEN: usize = 107;
+// v2 的地址和 TLV 总长度上限, 防止客户端让我们读取过多数据
+const V2_MAX_LEN: usize = 4096;
+
+/// HAProxy PROXY protocol 的版本
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    /// 文本格式: `PROXY TCP4 1.2.3.4 5.6.7.8 1234 80\r\n`
+    V1,
+    /// 二进制格式
+    V2,
+}
+
+/// PROXY 头部里携带的真实连接地址
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProxyHeader {
+    pub source: SocketAddr,
+    pub destination: SocketAddr,
+}
+
+#[derive(Error, Debug)]
+pub enum ProxyProtocolError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("missing PROXY protocol signature")]
+    Signature,
+    #[error("invalid PROXY protocol v1 header: {0}")]
+    V1(String),
+    #[error("invalid PROXY protocol v2 header: {0}")]
+    V2(String),
+}
+
+/// 编码一个 PROXY 头部, 两个地址的协议族不同时都用 IPv6 表示
+pub fn encode_proxy_header(
+    version: ProxyProtocol,
+    source: SocketAddr,
+    destination: SocketAddr,
+) -> Vec<u8> {
+    let (source, destination) = match (source, destination) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) | (SocketAddr::V6(_), SocketAddr::V6(_)) => {
+            (source, destination)
+        }
+        _ => (to_v6(source), to_v6(destination)),
+    };
+    match version {
+        ProxyProtocol::V1 => {
+            let family = if source.is_ipv4() { "TCP4" } else { "TCP6" };
+            format!(
+                "PROXY {} {} {} {} {}\r\n",
+                family,
+                source.ip(),
+                destination.ip(),
+                source.port(),
+                destination.port()
+            )
+            .into_bytes()
+        }
+        ProxyProtocol::V2 => {
+            let mut header = V2_SIGNATURE.to_vec();
+            // version 2, PROXY 命令
+            header.push(0x21);
+            match (source.ip(), destination.ip()) {
+                (IpAddr::V4(src), IpAddr::V4(dst)) => {
+                    // AF_INET, STREAM
+                    header.push(0x11);
+                    header.extend_from_slice(&12u16.to_be_bytes());
+                    header.extend_from_slice(&src.octets());
+                    header.extend_from_slice(&dst.octets());
+                }
+                (IpAddr::V6(src), IpAddr::V6(dst)) => {
+                    // AF_INET6, STREAM
+                    header.push(0x21);
+                    header.extend_from_slice(&36u16.to_be_bytes());
+                    header.extend_from_slice(&src.octets());
+                    header.extend_from_slice(&dst.octets());
+                }
+                _ => unreachable!("address families are unified above"),
+            }
+            header.extend_from_slice(&source.port().to_be_bytes());
+            header.extend_from_slice(&destination.port().to_be_bytes());
+            header
+        }
+    }
+}
+
+/// 从连接开头读取 v1 或 v2 的 PROXY 头部, 不会多读头部之后的数据.
+/// `UNKNOWN` 和 `LOCAL` 这类不携带地址的头部返回 None
+pub async fn read_proxy_header<R>(reader: &mut R) -> Result<Option<ProxyHeader>, ProxyProtocolError>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut prefix = [0u8; 8];
+    reader.read_exact(&mut prefix).await?;
+    if prefix.starts_with(b"PROXY ") {
+        read_v1(reader, &prefix).await
+    } else if prefix == V2_SIGNATURE[..8] {
+        read_v2(reader).await
+    } else {
+        Err(ProxyProtocolError::Signature)
+    }
+}
+
+async fn read_v1<R>(
+    reader: &mut R,
+    prefix: &[u8],
+) -> Result<Option<ProxyHeader>, ProxyProtocolError>
+where
+    R: AsyncRead + Unpin,
+{
+    // 逐字节读到 \r\n, 后面的数据属于被代理的连接
+    let mut line = prefix.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LEN {
+            return Err(ProxyProtocolError::V1("header too long".into()));
+        }
+        line.push(reader.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2])
+        .map_err(|_| ProxyProtocolError::V1("not utf-8".into()))?;
+    parse_v1(line).map_err(ProxyProtocolError::V1)
+}
+
+fn parse_v1(line: &str) -> Result<Option<ProxyHeader>, String> {
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", family @ ("TCP4" | "TCP6"), src, dst, src_port, dst_port] => {
+            let ip = |s: &str| -> Result<IpAddr, String> {
+                let ip: IpAddr = s.parse().map_err(|_| format!("invalid address {:?}", s))?;
+                if ip.is_ipv4() != (*family == "TCP4") {
+                    return Err(format!("{} does not match {}", ip, family));
+                }
+                Ok(ip)
+            };
+            let port = |s: &str| -> Result<u16, String> {
+                s.parse().map_err(|_| format!("invalid port {:?}", s))
+            };
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(ip(src)?, port(src_port)?),
+                destination: SocketAddr::new(ip(dst)?, port(dst_port)?),
+            }))
+        }
+        _ => Err(format!("unexpected {:?}", line)),
+    }
+}
+
+async fn read_v2<R>(reader: &mut R) -> Result<Option<ProxyHeader>, ProxyProtocolError>
+where
+    R: AsyncRead + Unpin,
+{
+    let invalid = |message: &str| ProxyProtocolError::V2(message.into());
+    let mut rest = [0u8; 8];
+    reader.read_exact(&mut rest).await?;
+    if rest[..4] != V2_SIGNATURE[8..] {
+        return Err(ProxyProtocolError::Signature);
+    }
+    let (version_command, family) = (rest[4], rest[5]);
+    let len = u16::from_be_bytes([rest[6], rest[7]]) as usize;
+    if version_command >> 4 != 2 {
+        return Err(invalid("unsupported version"));
+    }
+    if len > V2_MAX_LEN {
+        return Err(invalid("header too long"));
+    }
+    // 地址后面可能还有 TLV, 一起读出来丢掉
+    let mut body = vec![0u8; len];
+    reader.read_exact(&mut body).await?;
+
+    match version_command & 0x0f {
+        // LOCAL: 负载均衡器自己的连接, 比如健康检查
+        0x0 => return Ok(None),
+        0x1 => {}
+        _ => return Err(invalid("unsupported command")),
+    }
+    let port = |i: usize| u16::from_be_bytes([body[i], body[i + 1]]);
+    match family {
+        0x11 if len >= 12 => {
+            let src = Ipv4Addr::new(body[0], body[1], body[2], body[3]);
+            let dst = Ipv4Addr::new(body[4], body[5], body[6], body[7]);
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(src.into(), port(8)),
+                destination: SocketAddr::new(dst.into(), port(10)),
+            }))
+        }
+        0x21 if len >= 36 => {
+            let src = Ipv6Addr::from(<[u8; 16]>::try_from(&body[..16]).unwrap());
+            let dst = Ipv6Addr::from(<[u8; 16]>::try_from(&body[16..32]).unwrap());
+            Ok(Some(ProxyHeader {
+                source: SocketAddr::new(src.into(), port(32)),
+                destination: SocketAddr::new(dst.into(), port(34)),
+            }))
+        }
+        // UNSPEC 或者 unix socket 这类没有 IP 的地址
+        0x00 | 0x31 | 0x32 => Ok(None),
+        0x11 | 0x21 => Err(invalid("address block too short")),
+        _ => Err(invalid("unsupported address family")),
+    }
+}
+
+fn to_v6(addr: SocketAddr) -> SocketAddr {
+    match addr.ip() {
+        IpAddr::V4(ip) => SocketAddr::new(ip.to_ipv6_mapped().into(), addr.port()),
+        IpAddr::V6(_) => addr,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addrs() -> (SocketAddr, SocketAddr) {
+        (
+            "10.1.2.3:5000".parse().unwrap(),
+            "192.168.0.1:443".parse().unwrap(),
+        )
+    }
+
+    async fn roundtrip(version: ProxyProtocol, source: SocketAddr, destination: SocketAddr) {
+        let mut data = encode_proxy_header(version, source, destination);
+        data.extend_from_slice(b"payload");
+        let mut reader = data.as_slice();
+        let header = read_proxy_header(&mut reader).await.unwrap().unwrap();
+        assert_eq!((header.source, header.destination), (source, destination));
+        // 头部之后的数据没有被读走
+        assert_eq!(reader, b"payload");
+    }
+
+    #[test]
+    fn v1_should_be_text() {
+        let (src, dst) = addrs();
+        let header = encode_proxy_header(ProxyProtocol::V1, src, dst);
+        assert_eq!(header, b"PROXY TCP4 10.1.2.3 192.168.0.1 5000 443\r\n");
+    }
+
+    #[test]
+    fn v2_should_follow_binary_layout() {
+        let (src, dst) = addrs();
+        let header = encode_proxy_header(ProxyProtocol::V2, src, dst);
+        assert_eq!(&header[..12], &V2_SIGNATURE);
+        assert_eq!(&header[12..16], &[0x21, 0x11, 0, 12]);
+        assert_eq!(&header[16..20], &[10, 1, 2, 3]);
+        assert_eq!(&header[24..28], &[0x13, 0x88, 0x01, 0xbb]);
+    }
+
+    #[tokio::test]
+    async fn headers_should_roundtrip() {
+        let (src, dst) = addrs();
+        let v6: SocketAddr = "[2001:db8::1]:8080".parse().unwrap();
+        for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+            roundtrip(version, src, dst).await;
+            roundtrip(version, v6, "[::1]:443".parse().unwrap()).await;
+            // 混合的地址族统一成 IPv6
+            let data = encode_proxy_header(version, v6, dst);
+            let header = read_proxy_header(&mut data.as_slice()).await.unwrap();
+            assert_eq!(header.unwrap().destination, to_v6(dst));
+        }
+    }
+
+    #[tokio::test]
+    async fn local_and_unknown_should_have_no_address() {
+        let mut v1 = b"PROXY UNKNOWN\r\n".as_slice();
+        assert_eq!(read_proxy_header(&mut v1).await.unwrap(), None);
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend_from_slice(&[0x20, 0x00, 0, 3, 1, 2, 3]);
+        assert_eq!(read_proxy_header(&mut v2.as_slice()).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn v2_tlvs_should_be_skipped() {
+        let (src, dst) = addrs();
+        let mut data = encode_proxy_header(ProxyProtocol::V2, src, dst);
+        // 追加一个 4 字节的 TLV, 并更新长度
+        data[15] += 4;
+        data.extend_from_slice(&[0x04, 0, 1, 0xff]);
+        data.extend_from_slice(b"x");
+        let mut reader = data.as_slice();
+        let header = read_proxy_header(&mut reader).await.unwrap().unwrap();
+        assert_eq!(header.source, src);
+        assert_eq!(reader, b"x");
+    }
+
+    #[tokio::test]
+    async fn invalid_headers_should_be_rejected() {
+        let cases: [&[u8]; 4] = [
+            b"GET / HTTP/1.1\r\n\r\n",
+            b"PROXY TCP4 10.0.0.1 ::1 1 2\r\n",
+            b"PROXY TCP4 10.0.0.1 10.0.0.2 1\r\n",
+            b"PROXY xxxx",
+        ];
+        for case in cases {
+            let mut reader = case;
+            assert!(read_proxy_header(&mut reader).await.is_err(), "{:?}", case);
+        }
+        let long = format!("PROXY TCP4 {}\r\n", "1".repeat(200));
+        assert!(read_proxy_header(&mut long.as_bytes()).await.is_err());
+    }
+}
@@ -0,0 +1,410 @@
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use anyhow::{bail, Context, Result};
+use arc_swap::ArcSwap;
+use notify::{recommended_watcher, Event, RecommendedWatcher, RecursiveMode, Watcher};
+use tokio::{
+    signal::unix::{signal, Signal, SignalKind},
+    sync::mpsc,
+    task::AbortHandle,
+    time::sleep,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use super::{
+    access::ConnectionLimiter,
+    access_log::AccessLog,
+    capture::Captures,
+    health::{probe_loop, udp_probe_loop},
+    http,
+    sessions::Sessions,
+    stats::Stats,
+    tls,
+    upstream::build_pools,
+    Config, MissingSni, UpstreamPool,
+};
+
+// 编辑器保存文件时往往会产生好几个事件, 等一小段时间再读
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+/// 新连接使用的配置和 upstream, reload 时整体替换
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    pub(crate) config: Arc<Config>,
+    pub(crate) pools: HashMap<String, Arc<UpstreamPool>>,
+    pub(crate) access_log: Arc<AccessLog>,
+    // 和 listeners 一一对应, 没有配置 TLS 的是 None
+    pub(crate) tls: Vec<Option<Arc<rustls::ServerConfig>>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct Runtime {
+    current: ArcSwap<Snapshot>,
+    // 每个 pool 的主动探测任务, 同时用来串行化 reload
+    probes: Mutex<Vec<Probe>>,
+    stats: Stats,
+    // 和 listeners 一一对应, reload 不能增减 listener
+    limiters: Vec<Arc<ConnectionLimiter>>,
+    sessions: Sessions,
+    // 取消后所有 listener 停止 accept
+    shutdown: CancellationToken,
+    captures: Arc<Captures>,
+}
+
+// 一个 pool 的探测任务, UDP 探测和 TCP 探测不能互换
+#[derive(Debug)]
+struct Probe {
+    pool: Arc<UpstreamPool>,
+    udp: bool,
+    task: AbortHandle,
+}
+
+/// 监听配置文件的变化和 SIGHUP, 重新加载配置
+#[derive(Debug)]
+pub(crate) struct ConfigWatcher {
+    path: PathBuf,
+    runtime: Arc<Runtime>,
+    // drop 之后就收不到文件事件了, 需要一直持有
+    _watcher: RecommendedWatcher,
+    changes: mpsc::Receiver<()>,
+    hangup: Signal,
+}
+
+impl Snapshot {
+    /// 第 index 个 listener 当前对应的 upstream
+    pub(crate) fn listener_pool(&self, index: usize) -> Arc<UpstreamPool> {
+        // 配置校验保证了 upstream 存在
+        Arc::clone(&self.pools[&self.config.listeners[index].upstream])
+    }
+
+    /// HTTP 模式下按 Host 和路径选择第 index 个 listener 的 upstream
+    pub(crate) fn http_pool(
+        &self,
+        index: usize,
+        host: Option<&str>,
+        path: &str,
+    ) -> Arc<UpstreamPool> {
+        let listener = &self.config.listeners[index];
+        let upstream = listener
+            .http
+            .as_ref()
+            .and_then(|http| http.route(host, path))
+            .unwrap_or(&listener.upstream);
+        Arc::clone(&self.pools[upstream])
+    }
+
+    /// 按 `pool` 配置的比例决定一个连接是否镜像, 需要时返回 shadow upstream
+    pub(crate) fn mirror_pool(&self, pool: &UpstreamPool) -> Option<Arc<UpstreamPool>> {
+        let mirror = pool.mirror()?;
+        if rand::random::<f64>() >= mirror.fraction {
+            return None;
+        }
+        self.pools.get(&mirror.upstream).cloned()
+    }
+
+    /// 按 SNI 选择第 index 个 listener 的 upstream, 没有 SNI 并且配置了拒绝时返回 None
+    pub(crate) fn sni_pool(&self, index: usize, sni: Option<&str>) -> Option<Arc<UpstreamPool>> {
+        let listener = &self.config.listeners[index];
+        let upstream = match (&listener.sni_routing, sni) {
+            (Some(routing), Some(sni)) => routing.route(sni).unwrap_or(&listener.upstream),
+            (Some(routing), None) if routing.missing == MissingSni::Reject => return None,
+            _ => &listener.upstream,
+        };
+        Some(Arc::clone(&self.pools[upstream]))
+    }
+}
+
+impl Runtime {
+    pub(crate) fn new(config: Config) -> Result<Self> {
+        let access_log = Arc::new(AccessLog::new(&config.access_log)?);
+        let tls = build_tls(&config)?;
+        let pools = build_pools(&config.upstreams)?;
+        let mut probes = Vec::new();
+        sync_probes(&mut probes, &pools, &config);
+        let limiters = config
+            .listeners
+            .iter()
+            .map(|_| Default::default())
+            .collect();
+        Ok(Self {
+            current: ArcSwap::from_pointee(Snapshot {
+                config: Arc::new(config),
+                pools,
+                access_log,
+                tls,
+            }),
+            probes: Mutex::new(probes),
+            stats: Stats::default(),
+            limiters,
+            sessions: Sessions::default(),
+            shutdown: CancellationToken::new(),
+            captures: Arc::default(),
+        })
+    }
+
+    /// 加载配置中的证书、私钥和 CA, 和 `new` 的检查相同, 但不启动探测也不打开访问日志
+    pub(crate) fn check(config: &Config) -> Result<()> {
+        build_tls(config)?;
+        build_pools(&config.upstreams)?;
+        Ok(())
+    }
+
+    pub(crate) fn current(&self) -> Arc<Snapshot> {
+        self.current.load_full()
+    }
+
+    pub(crate) fn traffic(&self) -> &Stats {
+        &self.stats
+    }
+
+    pub(crate) fn limiter(&self, index: usize) -> &Arc<ConnectionLimiter> {
+        &self.limiters[index]
+    }
+
+    pub(crate) fn sessions(&self) -> &Sessions {
+        &self.sessions
+    }
+
+    pub(crate) fn shutdown(&self) -> &CancellationToken {
+        &self.shutdown
+    }
+
+    pub(crate) fn captures(&self) -> &Arc<Captures> {
+        &self.captures
+    }
+
+    /// 校验新配置并替换, 只影响之后建立的连接; 失败时保留原来的配置
+    pub(crate) fn reload(&self, config: Config) -> Result<()> {
+        let mut probes = self.probes.lock().unwrap();
+        self.replace(&mut probes, config)
+    }
+
+    /// 在当前配置上修改后 reload, 和其他 reload 串行执行.
+    /// admin 接口的修改不会写回配置文件, 之后从文件 reload 时会被覆盖
+    pub(crate) fn update(&self, f: impl FnOnce(&mut Config) -> Result<()>) -> Result<()> {
+        let mut probes = self.probes.lock().unwrap();
+        let mut config = Config::clone(&self.current.load().config);
+        f(&mut config)?;
+        self.replace(&mut probes, config)
+    }
+
+    fn replace(&self, probes: &mut Vec<Probe>, config: Config) -> Result<()> {
+        let current = self.current.load();
+        if *current.config == config {
+            info!("Config unchanged, skip reload");
+            return Ok(());
+        }
+        if let Err((field, message)) = config.validate() {
+            bail!("{}: {}", field, message);
+        }
+        // listener 的 socket 在启动时就绑定好了, reload 只能修改它们指向的 upstream
+        let listen_addrs = |config: &Config| {
+            config
+                .listeners
+                .iter()
+                .map(|listener| (listener.listen_addr, listener.udp.is_some()))
+                .collect::<Vec<_>>()
+        };
+        if listen_addrs(&current.config) != listen_addrs(&config) {
+            bail!(
+                "listeners: listen addresses and protocols cannot be changed by reload, restart instead"
+            );
+        }
+        if current.config.admin != config.admin {
+            bail!("admin: cannot be changed by reload, restart instead");
+        }
+        if current.config.shutdown.handover_socket != config.shutdown.handover_socket {
+            bail!("shutdown.handover_socket: cannot be changed by reload, restart instead");
+        }
+        let access_log = if current.config.access_log == config.access_log {
+            Arc::clone(&current.access_log)
+        } else {
+            Arc::new(AccessLog::new(&config.access_log)?)
+        };
+
+        // 证书在每次 reload 时重新读取, 更新证书文件之后 reload 即可生效
+        let tls = build_tls(&config)?;
+
+        // 配置没变的 upstream 继续使用原来的 pool, 保留健康状态和连接计数;
+        // 变了的 upstream 重建 pool, 没变的 backend 同样保留
+        let pools = config
+            .upstreams
+            .iter()
+            .map(|(name, upstream)| {
+                let previous = current.pools.get(name);
+                let pool = match previous {
+                    Some(pool) if current.config.upstreams[name] == *upstream => Arc::clone(pool),
+                    _ => Arc::new(UpstreamPool::with_previous(
+                        name,
+                        upstream,
+                        previous.map(Arc::as_ref),
+                    )?),
+                };
+                Ok((name.clone(), pool))
+            })
+            .collect::<Result<HashMap<_, _>>>()?;
+        sync_probes(probes, &pools, &config);
+        self.current.store(Arc::new(Snapshot {
+            config: Arc::new(config),
+            pools,
+            access_log,
+            tls,
+        }));
+        info!("Config reloaded");
+        Ok(())
+    }
+}
+
+impl Drop for Runtime {
+    fn drop(&mut self) {
+        for Probe { task, .. } in self.probes.get_mut().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+}
+
+impl ConfigWatcher {
+    pub(crate) fn new(path: PathBuf, runtime: Arc<Runtime>) -> Result<Self> {
+        let file_name = path
+            .file_name()
+            .map(OsString::from)
+            .with_context(|| format!("{} is not a file", path.display()))?;
+        let (tx, changes) = mpsc::channel(1);
+        let mut watcher = recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                let ours = event
+                    .paths
+                    .iter()
+                    .any(|p| p.file_name() == Some(file_name.as_os_str()));
+                if ours && (event.kind.is_create() || event.kind.is_modify()) {
+                    // 已经有待处理的通知时不需要再发
+                    let _ = tx.try_send(());
+                }
+            }
+            Err(e) => warn!("Config watch error: {}", e),
+        })?;
+        // 监听所在目录而不是文件本身, 这样编辑器用 rename 替换文件也能收到
+        let dir = match path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        let hangup = signal(SignalKind::hangup())?;
+
+        Ok(Self {
+            path,
+            runtime,
+            _watcher: watcher,
+            changes,
+            hangup,
+        })
+    }
+
+    pub(crate) async fn run(mut self) -> Result<()> {
+        info!("Watching {} for changes", self.path.display());
+        loop {
+            tokio::select! {
+                Some(()) = self.changes.recv() => {
+                    sleep(DEBOUNCE).await;
+                    while self.changes.try_recv().is_ok() {}
+                    info!("{} changed, reloading", self.path.display());
+                }
+                _ = self.hangup.recv() => info!("Received SIGHUP, reloading"),
+            }
+            match Config::load(&self.path) {
+                Ok(config) => {
+                    if let Err(e) = self.runtime.reload(config) {
+                        warn!(
+                            "Rejected {}, keep running the old config: {}",
+                            self.path.display(),
+                            e
+                        );
+                    }
+                }
+                Err(e) => warn!("Rejected new config, keep running the old config: {}", e),
+            }
+        }
+    }
+}
+
+fn build_tls(config: &Config) -> Result<Vec<Option<Arc<rustls::ServerConfig>>>> {
+    config
+        .listeners
+        .iter()
+        .enumerate()
+        .map(|(i, listener)| {
+            // HTTP 模式通过 ALPN 让客户端选择 HTTP/2
+            let alpn = match listener.http {
+                Some(_) => http::ALPN,
+                None => &[],
+            };
+            listener
+                .tls
+                .as_ref()
+                .map(|tls| {
+                    tls::server_config(tls, alpn).with_context(|| format!("listeners[{}].tls", i))
+                })
+                .transpose()
+        })
+        .collect()
+}
+
+// 为新出现的 pool 启动探测, 停掉不再使用的 pool 的探测.
+// 探测是 TCP connect, 只被 UDP listener 使用的 upstream 改用 UDP 探测摘除的 backend
+fn sync_probes(
+    probes: &mut Vec<Probe>,
+    pools: &HashMap<String, Arc<UpstreamPool>>,
+    config: &Config,
+) {
+    let udp_only = udp_only_upstreams(config);
+    probes.retain(|probe| {
+        let used = pools.values().any(|p| Arc::ptr_eq(p, &probe.pool))
+            && udp_only.contains(probe.pool.name()) == probe.udp;
+        if !used {
+            probe.task.abort();
+        }
+        used
+    });
+    for pool in pools.values() {
+        if probes.iter().any(|probe| Arc::ptr_eq(&probe.pool, pool)) {
+            continue;
+        }
+        let udp = udp_only.contains(pool.name());
+        let task = match udp {
+            true => tokio::spawn(udp_probe_loop(Arc::clone(pool))),
+            false => tokio::spawn(probe_loop(Arc::clone(pool))),
+        };
+        probes.push(Probe {
+            pool: Arc::clone(pool),
+            udp,
+            task: task.abort_handle(),
+        });
+    }
+}
+
+fn udp_only_upstreams(config: &Config) -> HashSet<&str> {
+    let mut udp = HashSet::new();
+    let mut tcp = HashSet::new();
+    for listener in &config.listeners {
+        if listener.udp.is_some() {
+            udp.insert(listener.upstream.as_str());
+            continue;
+        }
+        tcp.insert(listener.upstream.as_str());
+        if let Some(sni) = &listener.sni_routing {
+            tcp.extend(sni.routes.values().map(String::as_str));
+        }
+        if let Some(http) = &listener.http {
+            tcp.extend(http.routes.iter().map(|route| route.upstream.as_str()));
+        }
+    }
+    udp.retain(|name| !tcp.contains(name));
+    udp
+}
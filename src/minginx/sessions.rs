@@ -0,0 +1,265 @@
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::Duration,
+};
+
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use tokio::{
+    sync::Notify,
+    time::{timeout, Instant},
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use super::{forward::Meter, CloseReason, Transfer};
+
+/// 正在处理的连接, admin 接口可以查看和关闭
+#[derive(Debug, Default)]
+pub(crate) struct Sessions {
+    next_id: AtomicU64,
+    live: Mutex<BTreeMap<u64, Arc<LiveSession>>>,
+    // 已经结束的连接数, 用来计算停止时自然结束了多少
+    closed: AtomicU64,
+    // 每个连接结束时通知, 停止时等待所有连接结束
+    changed: Notify,
+    // 开始停止时取消, HTTP 连接收到后不再处理新请求
+    draining: CancellationToken,
+}
+
+/// 停止时等待已有连接的结果
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// 在 grace period 内自然结束的连接
+    pub drained: usize,
+    /// 超过 grace period 被强制关闭的连接
+    pub cut: usize,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Protocol {
+    Tcp,
+    /// 一个 UDP flow
+    Udp,
+}
+
+#[derive(Debug)]
+pub(crate) struct LiveSession {
+    id: u64,
+    protocol: Protocol,
+    listener: SocketAddr,
+    started: DateTime<Utc>,
+    start: Instant,
+    // 转发过程中实时更新
+    meter: Arc<Meter>,
+    route: Mutex<Route>,
+    cancel: CancellationToken,
+    // 被关闭的原因, 先设置再取消 cancel
+    reason: OnceLock<CloseReason>,
+    draining: CancellationToken,
+}
+
+/// admin 接口返回的连接信息
+#[derive(Debug, Clone, Serialize)]
+pub(crate) struct SessionInfo {
+    id: u64,
+    protocol: Protocol,
+    client: SocketAddr,
+    listener: SocketAddr,
+    upstream: String,
+    /// 还没有连上 backend, 或者是 HTTP 模式时为空
+    backend: Option<String>,
+    started: DateTime<Utc>,
+    duration_ms: u64,
+    bytes_in: u64,
+    bytes_out: u64,
+    /// 只有 UDP flow 有数据报个数
+    #[serde(skip_serializing_if = "Option::is_none")]
+    datagrams_in: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    datagrams_out: Option<u64>,
+}
+
+#[derive(Debug)]
+struct Route {
+    client: SocketAddr,
+    upstream: String,
+    backend: Option<String>,
+}
+
+impl Sessions {
+    pub(crate) fn open(
+        &self,
+        protocol: Protocol,
+        client: SocketAddr,
+        listener: SocketAddr,
+        upstream: &str,
+    ) -> Arc<LiveSession> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let session = Arc::new(LiveSession {
+            id,
+            protocol,
+            listener,
+            started: Utc::now(),
+            start: Instant::now(),
+            meter: Arc::new(Meter::new()),
+            route: Mutex::new(Route {
+                client,
+                upstream: upstream.to_string(),
+                backend: None,
+            }),
+            cancel: CancellationToken::new(),
+            reason: OnceLock::new(),
+            draining: self.draining.clone(),
+        });
+        self.live.lock().unwrap().insert(id, Arc::clone(&session));
+        session
+    }
+
+    pub(crate) fn close(&self, session: &LiveSession) {
+        if self.live.lock().unwrap().remove(&session.id).is_some() {
+            self.closed.fetch_add(1, Ordering::Relaxed);
+            self.changed.notify_waiters();
+        }
+    }
+
+    /// 按建立的顺序
+    pub(crate) fn list(&self) -> Vec<SessionInfo> {
+        let live = self.live.lock().unwrap();
+        live.values().map(|session| session.info()).collect()
+    }
+
+    /// 关闭一个连接, 不存在时返回 false
+    pub(crate) fn kill(&self, id: u64) -> bool {
+        match self.live.lock().unwrap().get(&id) {
+            Some(session) => {
+                session.abort(CloseReason::Aborted);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// 关闭 upstream 中转发到 `backend` 的所有连接, 返回关闭的数量
+    pub(crate) fn kill_backend(&self, upstream: &str, backend: &str) -> usize {
+        let live = self.live.lock().unwrap();
+        let mut killed = 0;
+        for session in live.values() {
+            let route = session.route.lock().unwrap();
+            if route.upstream == upstream && route.backend.as_deref() == Some(backend) {
+                session.abort(CloseReason::Aborted);
+                killed += 1;
+            }
+        }
+        killed
+    }
+
+    /// 通知所有连接开始停止, 等待它们结束; 超过 `grace` 后强制关闭剩下的连接
+    pub(crate) async fn drain(&self, grace: Duration) -> ShutdownReport {
+        self.draining.cancel();
+        let before = self.closed.load(Ordering::Relaxed);
+        let mut cut = 0;
+        if timeout(grace, self.wait_empty()).await.is_err() {
+            for session in self.live.lock().unwrap().values() {
+                session.abort(CloseReason::Shutdown);
+                cut += 1;
+            }
+            warn!("Closing {} sessions still running after {:?}", cut, grace);
+            // 强制关闭的连接同样要写统计和访问日志
+            self.wait_empty().await;
+        }
+        let closed = (self.closed.load(Ordering::Relaxed) - before) as usize;
+        ShutdownReport {
+            drained: closed - cut,
+            cut,
+        }
+    }
+
+    async fn wait_empty(&self) {
+        loop {
+            // 先注册再检查, 避免错过检查之后的通知
+            let changed = self.changed.notified();
+            tokio::pin!(changed);
+            changed.as_mut().enable();
+            if self.live.lock().unwrap().is_empty() {
+                return;
+            }
+            changed.await;
+        }
+    }
+}
+
+impl LiveSession {
+    pub(crate) fn id(&self) -> u64 {
+        self.id
+    }
+
+    pub(crate) fn meter(&self) -> &Arc<Meter> {
+        &self.meter
+    }
+
+    /// 读到 PROXY 头部或者选定 backend 之后更新
+    pub(crate) fn set_route(&self, client: SocketAddr, upstream: &str, backend: Option<&str>) {
+        *self.route.lock().unwrap() = Route {
+            client,
+            upstream: upstream.to_string(),
+            backend: backend.map(str::to_string),
+        };
+    }
+
+    /// 开始停止时取消, 可以在这之后主动结束连接
+    pub(crate) fn draining(&self) -> &CancellationToken {
+        &self.draining
+    }
+
+    /// 运行转发直到结束, 或者被 admin 接口关闭, 或者停止时被强制关闭
+    pub(crate) async fn run(&self, forward: impl Future<Output = Transfer>) -> Transfer {
+        tokio::select! {
+            transfer = forward => transfer,
+            _ = self.cancel.cancelled() => {
+                let reason = self.reason.get().copied().unwrap_or(CloseReason::Aborted);
+                info!("Session {} closed: {:?}", self.id, reason);
+                self.meter.transfer(reason)
+            }
+        }
+    }
+
+    fn abort(&self, reason: CloseReason) {
+        // 已经被关闭过时保留第一次的原因
+        let _ = self.reason.set(reason);
+        self.cancel.cancel();
+    }
+
+    fn info(&self) -> SessionInfo {
+        let route = self.route.lock().unwrap();
+        let (bytes_in, bytes_out) = self.meter.bytes();
+        let (datagrams_in, datagrams_out) = match self.protocol {
+            Protocol::Tcp => (None, None),
+            Protocol::Udp => {
+                let (datagrams_in, datagrams_out) = self.meter.datagrams();
+                (Some(datagrams_in), Some(datagrams_out))
+            }
+        };
+        SessionInfo {
+            id: self.id,
+            protocol: self.protocol,
+            client: route.client,
+            listener: self.listener,
+            upstream: route.upstream.clone(),
+            backend: route.backend.clone(),
+            started: self.started,
+            duration_ms: self.start.elapsed().as_millis() as u64,
+            bytes_in,
+            bytes_out,
+            datagrams_in,
+            datagrams_out,
+        }
+    }
+}
@@ -0,0 +1,222 @@
+use thiserror::Error;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+// TLS record 头部: content type, version, length
+const RECORD_HEADER_LEN: usize = 5;
+const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+const EXTENSION_SERVER_NAME: u16 = 0x0000;
+const NAME_TYPE_HOST_NAME: u8 = 0x00;
+// 正常的 ClientHello 只有几百字节到几 KB, 防止客户端让我们缓存过多数据
+const MAX_CLIENT_HELLO_LEN: usize = 64 * 1024;
+
+#[derive(Error, Debug)]
+pub enum ClientHelloError {
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("not a TLS handshake")]
+    NotTls,
+    #[error("invalid ClientHello: {0}")]
+    Invalid(&'static str),
+}
+
+/// 读取 TLS ClientHello, 返回其中 SNI 扩展的 hostname, 没有 SNI 时返回 None.
+/// 不解密也不修改数据, 读到的所有字节追加到 `buf`, 之后原样转发给 upstream
+pub async fn read_client_hello<R>(
+    reader: &mut R,
+    buf: &mut Vec<u8>,
+) -> Result<Option<String>, ClientHelloError>
+where
+    R: AsyncRead + Unpin,
+{
+    // ClientHello 可能被拆到多个 record 里, 拼起来再解析
+    let mut handshake = Vec::new();
+    loop {
+        let start = buf.len();
+        buf.resize(start + RECORD_HEADER_LEN, 0);
+        reader.read_exact(&mut buf[start..]).await?;
+        let header = &buf[start..];
+        // record 的版本号总是 3.x
+        if header[0] != CONTENT_TYPE_HANDSHAKE || header[1] != 3 {
+            return Err(ClientHelloError::NotTls);
+        }
+        let len = u16::from_be_bytes([header[3], header[4]]) as usize;
+        if len == 0 || buf.len() + len > MAX_CLIENT_HELLO_LEN {
+            return Err(ClientHelloError::Invalid("bad record length"));
+        }
+        let body = buf.len();
+        buf.resize(body + len, 0);
+        reader.read_exact(&mut buf[body..]).await?;
+        handshake.extend_from_slice(&buf[body..]);
+
+        if handshake.len() >= 4 {
+            if handshake[0] != HANDSHAKE_CLIENT_HELLO {
+                return Err(ClientHelloError::Invalid(
+                    "first handshake is not ClientHello",
+                ));
+            }
+            let total =
+                4 + u32::from_be_bytes([0, handshake[1], handshake[2], handshake[3]]) as usize;
+            if handshake.len() >= total {
+                return parse_client_hello(&handshake[4..total]);
+            }
+        }
+    }
+}
+
+// ClientHello 的消息体, 不包括 4 字节的 handshake 头部
+fn parse_client_hello(body: &[u8]) -> Result<Option<String>, ClientHelloError> {
+    let mut hello = Parser(body);
+    // legacy_version, random
+    hello.take(2 + 32)?;
+    // session id, cipher suites, compression methods
+    hello.vec8()?;
+    hello.vec16()?;
+    hello.vec8()?;
+    // 很老的客户端没有扩展
+    if hello.0.is_empty() {
+        return Ok(None);
+    }
+
+    let mut extensions = Parser(hello.vec16()?);
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let data = extensions.vec16()?;
+        if kind != EXTENSION_SERVER_NAME {
+            continue;
+        }
+        let mut names = Parser(Parser(data).vec16()?);
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let name = names.vec16()?;
+            if name_type == NAME_TYPE_HOST_NAME {
+                if name.is_empty() || !name.is_ascii() {
+                    return Err(ClientHelloError::Invalid("bad server name"));
+                }
+                return Ok(Some(String::from_utf8_lossy(name).into_owned()));
+            }
+        }
+        return Ok(None);
+    }
+    Ok(None)
+}
+
+// 按 TLS 的编码规则依次读取字段, 长度不够时返回错误
+struct Parser<'a>(&'a [u8]);
+
+impl<'a> Parser<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], ClientHelloError> {
+        if self.0.len() < n {
+            return Err(ClientHelloError::Invalid("truncated"));
+        }
+        let (head, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Ok(head)
+    }
+
+    fn u8(&mut self) -> Result<u8, ClientHelloError> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ClientHelloError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    // 1 字节长度开头的一段数据
+    fn vec8(&mut self) -> Result<&'a [u8], ClientHelloError> {
+        let len = self.u8()? as usize;
+        self.take(len)
+    }
+
+    // 2 字节长度开头的一段数据
+    fn vec16(&mut self) -> Result<&'a [u8], ClientHelloError> {
+        let len = self.u16()? as usize;
+        self.take(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use rustls::{
+        crypto::ring, pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore,
+    };
+
+    use super::*;
+
+    // 用 rustls 生成真实的 ClientHello
+    fn client_hello(name: &str) -> Vec<u8> {
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+        let name = ServerName::try_from(name.to_string()).unwrap();
+        let mut conn = ClientConnection::new(Arc::new(config), name).unwrap();
+        let mut hello = Vec::new();
+        conn.write_tls(&mut hello).unwrap();
+        hello
+    }
+
+    async fn read(input: &[u8]) -> (Result<Option<String>, ClientHelloError>, Vec<u8>) {
+        let mut buf = Vec::new();
+        let ret = read_client_hello(&mut &input[..], &mut buf).await;
+        (ret, buf)
+    }
+
+    #[tokio::test]
+    async fn sni_should_be_extracted_and_bytes_kept() {
+        let hello = client_hello("api.example.com");
+        let mut input = hello.clone();
+        input.extend_from_slice(b"after");
+        let (ret, buf) = read(&input).await;
+        assert_eq!(ret.unwrap().as_deref(), Some("api.example.com"));
+        // 只读取 ClientHello, 后面的数据留在连接里
+        assert_eq!(buf, hello);
+
+        // IP 地址不会作为 SNI 发送
+        let (ret, _) = read(&client_hello("127.0.0.1")).await;
+        assert_eq!(ret.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn client_hello_split_into_records_should_be_joined() {
+        let hello = client_hello("api.example.com");
+        let body = &hello[RECORD_HEADER_LEN..];
+        let mut input = Vec::new();
+        for chunk in body.chunks(100) {
+            input.extend_from_slice(&[CONTENT_TYPE_HANDSHAKE, 3, 1]);
+            input.extend_from_slice(&(chunk.len() as u16).to_be_bytes());
+            input.extend_from_slice(chunk);
+        }
+        let (ret, buf) = read(&input).await;
+        assert_eq!(ret.unwrap().as_deref(), Some("api.example.com"));
+        assert_eq!(buf, input);
+    }
+
+    #[tokio::test]
+    async fn invalid_client_hello_should_be_rejected() {
+        let (ret, _) = read(b"GET / HTTP/1.1\r\n\r\n").await;
+        assert!(matches!(ret, Err(ClientHelloError::NotTls)));
+
+        let hello = client_hello("api.example.com");
+        let (ret, _) = read(&hello[..hello.len() - 10]).await;
+        assert!(matches!(ret, Err(ClientHelloError::Io(_))));
+
+        // record 和 handshake 的长度一致, 但是扩展的长度超出了消息
+        let mut hello = hello;
+        hello.truncate(hello.len() - 10);
+        let record_len = (hello.len() - RECORD_HEADER_LEN) as u16;
+        hello[3..5].copy_from_slice(&record_len.to_be_bytes());
+        let message_len = record_len as u32 - 4;
+        hello[6..9].copy_from_slice(&message_len.to_be_bytes()[1..]);
+        let (ret, _) = read(&hello).await;
+        assert!(
+            matches!(ret, Err(ClientHelloError::Invalid(_))),
+            "{:?}",
+            ret
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use std::{
+    collections::BTreeMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+};
+
+use serde::Serialize;
+
+/// 连接结束的原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum CloseReason {
+    /// 两个方向都正常关闭
+    Closed,
+    /// 读写出错
+    Error,
+    IdleTimeout,
+    MaxSession,
+    /// 没有可用的 upstream, 或者 upstream 中没有健康的 backend, 或者都连不上
+    NoUpstream,
+    /// upstream 的熔断器打开, 拒绝了新连接
+    CircuitOpen,
+    /// 注入的故障关闭了连接
+    Fault,
+    /// 客户端发送的协议头部不合法, 比如 PROXY 头部
+    ProtocolError,
+    /// 客户端 IP 不允许访问, 或者超过了连接数或新建连接速率的限制
+    Denied,
+    /// 被 admin 接口关闭, 包括 backend 被禁用
+    Aborted,
+    /// 停止时超过 grace period 还没有结束, 被强制关闭
+    Shutdown,
+}
+
+/// 一个连接转发的字节数和结束原因
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Transfer {
+    /// client -> upstream
+    pub bytes_in: u64,
+    /// upstream -> client
+    pub bytes_out: u64,
+    pub reason: CloseReason,
+}
+
+/// 从启动开始累计的流量统计
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize)]
+pub struct TrafficStats {
+    pub connections: u64,
+    pub active: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+    pub close_reasons: BTreeMap<CloseReason, u64>,
+}
+
+// 所有 listener 共用, reload 不会清零
+#[derive(Debug, Default)]
+pub(crate) struct Stats {
+    connections: AtomicU64,
+    active: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    close_reasons: Mutex<BTreeMap<CloseReason, u64>>,
+}
+
+impl Transfer {
+    pub(crate) fn failed(reason: CloseReason) -> Self {
+        Self {
+            bytes_in: 0,
+            bytes_out: 0,
+            reason,
+        }
+    }
+}
+
+impl Stats {
+    pub(crate) fn open(&self) {
+        self.connections.fetch_add(1, Ordering::Relaxed);
+        self.active.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn close(&self, transfer: &Transfer) {
+        self.active.fetch_sub(1, Ordering::Relaxed);
+        self.bytes_in
+            .fetch_add(transfer.bytes_in, Ordering::Relaxed);
+        self.bytes_out
+            .fetch_add(transfer.bytes_out, Ordering::Relaxed);
+        *self
+            .close_reasons
+            .lock()
+            .unwrap()
+            .entry(transfer.reason)
+            .or_default() += 1;
+    }
+
+    pub(crate) fn snapshot(&self) -> TrafficStats {
+        TrafficStats {
+            connections: self.connections.load(Ordering::Relaxed),
+            active: self.active.load(Ordering::Relaxed),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+            close_reasons: self.close_reasons.lock().unwrap().clone(),
+        }
+    }
+}
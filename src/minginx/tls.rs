@@ -0,0 +1,244 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    path::Path,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use anyhow::{Context as _, Result};
+use rustls::{
+    crypto::ring,
+    pki_types::{CertificateDer, PrivateKeyDer, ServerName},
+    ClientConfig, RootCertStore,
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector, TlsStream};
+
+use super::{ListenerTlsConfig, UpstreamTlsConfig};
+
+/// 代理的一端: 普通的 TCP 连接, 或者由 minginx 终止/发起 TLS 的连接
+#[derive(Debug)]
+pub enum MaybeTlsStream {
+    Tcp(TcpStream),
+    Tls(Box<TlsStream<TcpStream>>),
+}
+
+/// 发起到 backend 的 TLS 连接
+#[derive(Debug)]
+pub(crate) struct UpstreamTls {
+    config: Arc<ClientConfig>,
+    // 不配置时使用 backend 地址中的 host
+    sni: Option<ServerName<'static>>,
+}
+
+impl MaybeTlsStream {
+    /// 普通 TCP 连接时返回底层的 socket, splice 只能用在这种连接上
+    pub fn as_tcp(&self) -> Option<&TcpStream> {
+        match self {
+            Self::Tcp(stream) => Some(stream),
+            Self::Tls(_) => None,
+        }
+    }
+}
+
+impl From<TcpStream> for MaybeTlsStream {
+    fn from(stream: TcpStream) -> Self {
+        Self::Tcp(stream)
+    }
+}
+
+impl From<TlsStream<TcpStream>> for MaybeTlsStream {
+    fn from(stream: TlsStream<TcpStream>) -> Self {
+        Self::Tls(Box::new(stream))
+    }
+}
+
+impl AsyncRead for MaybeTlsStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_read(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_read(cx, buf),
+        }
+    }
+}
+
+impl AsyncWrite for MaybeTlsStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_write(cx, buf),
+            Self::Tls(stream) => Pin::new(stream).poll_write(cx, buf),
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_flush(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_flush(cx),
+        }
+    }
+
+    // TLS 连接先发送 close_notify, 再关闭底层 socket 的写
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        match self.get_mut() {
+            Self::Tcp(stream) => Pin::new(stream).poll_shutdown(cx),
+            Self::Tls(stream) => Pin::new(stream).poll_shutdown(cx),
+        }
+    }
+}
+
+/// 读取 listener 的证书链和私钥, `alpn` 为空时不协商应用层协议
+pub(crate) fn server_config(
+    config: &ListenerTlsConfig,
+    alpn: &[&[u8]],
+) -> Result<Arc<rustls::ServerConfig>> {
+    let certs = load_certs(&config.cert)?;
+    let key = load_key(&config.key)?;
+    let mut server =
+        rustls::ServerConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_no_client_auth()
+            .with_single_cert(certs, key)
+            .with_context(|| format!("{}: invalid certificate or key", config.cert.display()))?;
+    server.alpn_protocols = alpn.iter().map(|protocol| protocol.to_vec()).collect();
+    Ok(Arc::new(server))
+}
+
+/// 和客户端完成 TLS 握手
+pub(crate) async fn accept(
+    config: &Arc<rustls::ServerConfig>,
+    stream: TcpStream,
+) -> io::Result<MaybeTlsStream> {
+    let stream = TlsAcceptor::from(Arc::clone(config)).accept(stream).await?;
+    Ok(TlsStream::from(stream).into())
+}
+
+impl UpstreamTls {
+    pub(crate) fn new(config: &UpstreamTlsConfig) -> Result<Self> {
+        let mut roots = RootCertStore::empty();
+        match &config.ca {
+            Some(ca) => {
+                for cert in load_certs(ca)? {
+                    roots
+                        .add(cert)
+                        .with_context(|| format!("{}: invalid CA certificate", ca.display()))?;
+                }
+            }
+            None => roots.extend(webpki_roots::TLS_SERVER_ROOTS.iter().cloned()),
+        }
+        let client = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+        let sni = config
+            .sni
+            .clone()
+            .map(ServerName::try_from)
+            .transpose()
+            .context("invalid sni")?;
+        Ok(Self {
+            config: Arc::new(client),
+            sni,
+        })
+    }
+
+    /// 在已经 connect 的 `stream` 上和 backend `addr` 完成 TLS 握手
+    pub(crate) async fn connect(
+        &self,
+        addr: &str,
+        stream: TcpStream,
+    ) -> io::Result<MaybeTlsStream> {
+        let name = match &self.sni {
+            Some(sni) => sni.clone(),
+            None => server_name(addr)?,
+        };
+        let stream = TlsConnector::from(Arc::clone(&self.config))
+            .connect(name, stream)
+            .await?;
+        Ok(TlsStream::from(stream).into())
+    }
+}
+
+// `host:port` 中的 host, 可以是域名或者 IP
+fn server_name(addr: &str) -> io::Result<ServerName<'static>> {
+    let host = addr.rsplit_once(':').map_or(addr, |(host, _)| host);
+    let host = host.trim_start_matches('[').trim_end_matches(']');
+    ServerName::try_from(host.to_string())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+fn load_certs(path: &Path) -> Result<Vec<CertificateDer<'static>>> {
+    let mut reader = open(path)?;
+    let certs = rustls_pemfile::certs(&mut reader)
+        .collect::<Result<Vec<_>, _>>()
+        .with_context(|| format!("{}: invalid PEM", path.display()))?;
+    if certs.is_empty() {
+        anyhow::bail!("{}: no certificate found", path.display());
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> Result<PrivateKeyDer<'static>> {
+    let mut reader = open(path)?;
+    rustls_pemfile::private_key(&mut reader)
+        .with_context(|| format!("{}: invalid PEM", path.display()))?
+        .with_context(|| format!("{}: no private key found", path.display()))
+}
+
+fn open(path: &Path) -> Result<BufReader<File>> {
+    let file = File::open(path).with_context(|| format!("{}: failed to open", path.display()))?;
+    Ok(BufReader::new(file))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+
+    #[test]
+    fn server_name_should_use_host_of_backend_addr() {
+        assert_eq!(
+            server_name("example.com:443").unwrap(),
+            ServerName::try_from("example.com").unwrap()
+        );
+        assert_eq!(
+            server_name("127.0.0.1:443").unwrap(),
+            ServerName::try_from("127.0.0.1").unwrap()
+        );
+        assert_eq!(
+            server_name("[::1]:443").unwrap(),
+            ServerName::try_from("::1").unwrap()
+        );
+        assert!(server_name("bad_host!:443").is_err());
+    }
+
+    #[test]
+    fn invalid_files_should_be_reported_with_path() {
+        let config = ListenerTlsConfig {
+            cert: "fixtures/tls/server.pem".into(),
+            key: "fixtures/tls/ca.pem".into(),
+        };
+        let err = server_config(&config, &[]).unwrap_err().to_string();
+        assert!(err.contains("ca.pem: no private key found"), "{}", err);
+
+        let config = UpstreamTlsConfig {
+            ca: Some(PathBuf::from("fixtures/tls/missing.pem")),
+            sni: None,
+        };
+        let err = UpstreamTls::new(&config).unwrap_err().to_string();
+        assert!(err.contains("missing.pem: failed to open"), "{}", err);
+    }
+}
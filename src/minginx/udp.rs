@@ -0,0 +1,297 @@
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use bytes::Bytes;
+use chrono::Utc;
+use tokio::{
+    io,
+    net::{lookup_host, UdpSocket},
+    sync::mpsc::{self, error::TrySendError},
+    time::{timeout, Instant},
+};
+use tracing::{debug, info, warn};
+
+use super::{
+    access::{Denied, Permit},
+    access_log::AccessRecord,
+    forward::Meter,
+    proxy::watch,
+    reload::{Runtime, Snapshot},
+    sessions::Protocol,
+    BackendGuard, CloseReason, TimeoutConfig, Transfer, UpstreamPool,
+};
+
+// UDP 数据报的最大长度
+const MAX_DATAGRAM: usize = 65535;
+// 一个 flow 等待转发给 backend 的数据报, 满了之后丢弃新的数据报
+const FLOW_QUEUE: usize = 256;
+
+// 被拒绝的数据报最多每隔这么久记录一条 warn 日志
+const DENIED_LOG_INTERVAL: Duration = Duration::from_secs(1);
+
+// 一个客户端地址的 flow, 建立时确定, 结束前不变
+struct Flow {
+    runtime: Arc<Runtime>,
+    snapshot: Arc<Snapshot>,
+    index: usize,
+    listener: SocketAddr,
+    client: SocketAddr,
+    // 回复客户端使用 listener 的 socket
+    socket: Arc<UdpSocket>,
+    _permit: Permit,
+}
+
+// 被拒绝的客户端每个数据报都会再检查一次, 日志按时间限流, 省略的条数附在下一条日志中
+#[derive(Debug, Default)]
+struct DeniedLog {
+    last: Option<Instant>,
+    suppressed: u64,
+}
+
+/// 在 UDP socket 上接收数据报, 按客户端地址分到 flow, 新的 flow 按当前配置选择 backend.
+/// 每个 flow 使用一个单独的 socket 和 backend 通信, 回复从 listener 的 socket 发回客户端
+pub(crate) async fn serve(socket: UdpSocket, index: usize, runtime: Arc<Runtime>) -> Result<()> {
+    let socket = Arc::new(socket);
+    let local_addr = socket.local_addr()?;
+    let mut flows: HashMap<SocketAddr, mpsc::Sender<Bytes>> = HashMap::new();
+    // flow 结束时通知, 从表中删掉
+    let (expired_tx, mut expired) = mpsc::unbounded_channel();
+    let mut buf = vec![0; MAX_DATAGRAM];
+    let mut denied_log = DeniedLog::default();
+    loop {
+        let (n, client) = tokio::select! {
+            ret = socket.recv_from(&mut buf) => match ret {
+                Ok(ret) => ret,
+                // 比如之前发给某个客户端的数据报触发了 ICMP 错误, 不影响其他 flow
+                Err(e) => {
+                    warn!("Failed to receive datagram on {}: {}", local_addr, e);
+                    continue;
+                }
+            },
+            Some(client) = expired.recv() => {
+                // 同一个客户端可能已经建立了新的 flow
+                if let Entry::Occupied(flow) = flows.entry(client) {
+                    if flow.get().is_closed() {
+                        flow.remove();
+                    }
+                }
+                continue;
+            }
+            _ = runtime.shutdown().cancelled() => return Ok(()),
+        };
+        let datagram = Bytes::copy_from_slice(&buf[..n]);
+        let datagram = match flows.get(&client) {
+            Some(flow) => match flow.try_send(datagram) {
+                Ok(()) => continue,
+                Err(TrySendError::Full(_)) => {
+                    debug!("Dropped datagram from {}, flow queue is full", client);
+                    continue;
+                }
+                // flow 刚刚结束, 建立新的 flow
+                Err(TrySendError::Closed(datagram)) => datagram,
+            },
+            None => datagram,
+        };
+
+        // 新的 flow 按当前的配置检查客户端和选择 upstream
+        let snapshot = runtime.current();
+        let access = &snapshot.config.listeners[index].access;
+        let permit = match runtime.limiter(index).check(access, client.ip()) {
+            Ok(permit) => permit,
+            // 和 TCP 一样计入统计, 每个数据报都会检查, 不记录访问日志
+            Err(denied) => {
+                denied_log.log(client, denied);
+                runtime.traffic().open();
+                runtime
+                    .traffic()
+                    .close(&Transfer::failed(CloseReason::Denied));
+                continue;
+            }
+        };
+        info!("New UDP flow from {}", client);
+        let (tx, rx) = mpsc::channel(FLOW_QUEUE);
+        // 新的队列是空的
+        let _ = tx.try_send(datagram);
+        flows.insert(client, tx);
+        let flow = Flow {
+            runtime: Arc::clone(&runtime),
+            snapshot,
+            index,
+            listener: local_addr,
+            client,
+            socket: Arc::clone(&socket),
+            _permit: permit,
+        };
+        let expired = expired_tx.clone();
+        tokio::spawn(async move {
+            flow.run(rx).await;
+            let _ = expired.send(client);
+        });
+    }
+}
+
+impl DeniedLog {
+    fn log(&mut self, client: SocketAddr, denied: Denied) {
+        let now = Instant::now();
+        if self
+            .last
+            .is_some_and(|last| now - last < DENIED_LOG_INTERVAL)
+        {
+            self.suppressed += 1;
+            return;
+        }
+        match self.suppressed {
+            0 => warn!("Denied UDP datagram from {}: {}", client, denied),
+            n => warn!(
+                "Denied UDP datagram from {}: {} ({} more denied since last log)",
+                client, denied, n
+            ),
+        }
+        self.last = Some(now);
+        self.suppressed = 0;
+    }
+}
+
+impl Flow {
+    async fn run(self, datagrams: mpsc::Receiver<Bytes>) {
+        let start = Instant::now();
+        let (runtime, snapshot, client) = (&self.runtime, &self.snapshot, self.client);
+        runtime.traffic().open();
+        let pool = snapshot.listener_pool(self.index);
+        let live = runtime
+            .sessions()
+            .open(Protocol::Udp, client, self.listener, pool.name());
+        let (backend, transfer) = match connect_upstream(&pool, client).await {
+            Some((backend, upstream)) => {
+                live.set_route(client, pool.name(), Some(backend.addr()));
+                let idle = snapshot.config.listeners[self.index]
+                    .udp
+                    .as_ref()
+                    .map(|udp| udp.flow_idle_timeout)
+                    .unwrap_or_default();
+                let timeouts = TimeoutConfig {
+                    idle,
+                    ..pool.timeouts().clone()
+                };
+                let meter = live.meter();
+                let relay = self.relay(&upstream, datagrams, &pool, &backend, meter);
+                let transfer = live.run(watch(meter, relay, &timeouts)).await;
+                backend.record(&transfer);
+                (Some(backend.addr().to_string()), transfer)
+            }
+            None => {
+                warn!(
+                    "No backend available in upstream {}, dropping flow from {}",
+                    pool.name(),
+                    client
+                );
+                (None, Transfer::failed(CloseReason::NoUpstream))
+            }
+        };
+        runtime.sessions().close(&live);
+
+        let (datagrams_in, datagrams_out) = live.meter().datagrams();
+        info!(
+            "UDP flow from {} closed, {} datagrams in, {} datagrams out",
+            client, datagrams_in, datagrams_out
+        );
+        runtime.traffic().close(&transfer);
+        snapshot.access_log.write(&AccessRecord {
+            time: Utc::now(),
+            client,
+            listener: self.listener,
+            upstream: pool.name().to_string(),
+            backend,
+            bytes_in: transfer.bytes_in,
+            bytes_out: transfer.bytes_out,
+            duration_ms: start.elapsed().as_millis() as u64,
+            reason: transfer.reason,
+        });
+    }
+
+    // 双向转发直到出错, 空闲超时由调用方处理.
+    // listener 停止之后不再有新的数据报, 继续把 backend 的回复发给客户端
+    async fn relay(
+        &self,
+        upstream: &UdpSocket,
+        mut datagrams: mpsc::Receiver<Bytes>,
+        pool: &UpstreamPool,
+        backend: &BackendGuard,
+        meter: &Meter,
+    ) -> io::Result<()> {
+        // 被动健康检查: connect 之后的 UDP socket 能收到 ICMP port unreachable,
+        // 在下一次 send 或 recv 时返回
+        let refused = |e: io::Error| {
+            if e.kind() == io::ErrorKind::ConnectionRefused {
+                pool.report(backend, false);
+            }
+            e
+        };
+        let mut buf = vec![0; MAX_DATAGRAM];
+        let mut listening = true;
+        let mut replied = false;
+        loop {
+            tokio::select! {
+                datagram = datagrams.recv(), if listening => match datagram {
+                    Some(datagram) => {
+                        upstream.send(&datagram).await.map_err(refused)?;
+                        meter.datagram_in(datagram.len());
+                    }
+                    None => listening = false,
+                },
+                ret = upstream.recv(&mut buf) => {
+                    let n = ret.map_err(refused)?;
+                    if !replied {
+                        pool.report(backend, true);
+                        replied = true;
+                    }
+                    self.socket.send_to(&buf[..n], self.client).await?;
+                    meter.datagram_out(n);
+                }
+            }
+        }
+    }
+}
+
+// UDP 的 connect 只是解析地址, 失败时换一个 backend, 最多尝试 max_attempts 次
+async fn connect_upstream(
+    pool: &UpstreamPool,
+    client: SocketAddr,
+) -> Option<(BackendGuard, UdpSocket)> {
+    let mut tried = Vec::new();
+    for _ in 0..pool.max_attempts() {
+        let backend = pool.select_excluding(client.ip(), &tried)?;
+        match timeout(pool.timeouts().connect, connect(backend.addr())).await {
+            Ok(Ok(upstream)) => return Some((backend, upstream)),
+            Ok(Err(e)) => warn!(
+                "Failed to connect to {} for {}: {}",
+                backend.addr(),
+                client,
+                e
+            ),
+            Err(_) => warn!("Timed out resolving {} for {}", backend.addr(), client),
+        }
+        tried.push(backend.addr().to_string());
+    }
+    None
+}
+
+// connect 之后这个 socket 只接收 backend 发来的数据报
+pub(crate) async fn connect(addr: &str) -> io::Result<UdpSocket> {
+    let addr = lookup_host(addr)
+        .await?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no address found"))?;
+    let local: SocketAddr = match addr {
+        SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+        SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+    };
+    let socket = UdpSocket::bind(local).await?;
+    socket.connect(addr).await?;
+    Ok(socket)
+}
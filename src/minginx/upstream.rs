@@ -0,0 +1,681 @@
+use std::{
+    collections::HashMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    net::IpAddr,
+    ops::Deref,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicU8, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+};
+
+use anyhow::{Context, Result};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use tokio::sync::{broadcast, Notify};
+use tracing::{info, warn};
+
+use super::{
+    breaker::{Attempt, CircuitBreaker, Rejected},
+    http::IdleConnections,
+    tls::UpstreamTls,
+    Forwarding, HealthCheckConfig, MirrorConfig, ProxyProtocol, Strategy, TimeoutConfig, Transfer,
+    UpstreamConfig,
+};
+
+// 一致性哈希环上每个权重对应的虚拟节点数
+const VIRTUAL_NODES: u32 = 160;
+const HEALTH_EVENTS: usize = 64;
+
+#[derive(Debug)]
+pub struct Backend {
+    addr: String,
+    weight: u32,
+    active: AtomicUsize,
+    total: AtomicU64,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    healthy: AtomicBool,
+    // BackendState, reload 重建 pool 时随 backend 保留
+    state: AtomicU8,
+    // 切换到 Disabled 时通知, HTTP 模式的隧道收到后关闭
+    disabled: Notify,
+    // (连续失败次数, 连续成功次数)
+    streak: Mutex<(u32, u32)>,
+}
+
+/// 运维通过 admin 接口设置的 backend 状态, 和健康检查的结果无关
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendState {
+    #[default]
+    Active,
+    /// 不再分配新连接, 已有的连接继续转发直到结束
+    Draining,
+    /// 不再分配新连接, 并关闭已有的连接; HTTP 模式下关闭 upgrade 的隧道和空闲的 keep-alive 连接
+    Disabled,
+}
+
+/// backend 当前的状态和累计流量
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct BackendStats {
+    pub addr: String,
+    pub healthy: bool,
+    pub state: BackendState,
+    pub active: usize,
+    pub total: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// backend 健康状态发生变化
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HealthEvent {
+    pub upstream: String,
+    pub backend: String,
+    pub healthy: bool,
+}
+
+/// 选中的 backend, 持有期间计入活跃连接数, drop 时减掉
+#[derive(Debug)]
+pub struct BackendGuard {
+    backend: Arc<Backend>,
+}
+
+#[derive(Debug)]
+pub struct UpstreamPool {
+    name: String,
+    strategy: Strategy,
+    backends: Vec<Arc<Backend>>,
+    next: AtomicUsize,
+    // smooth weighted round-robin 每个 backend 的 current weight
+    current_weights: Mutex<Vec<i64>>,
+    // (hash, backend index), 按 hash 排序
+    ring: Vec<(u64, usize)>,
+    health_check: HealthCheckConfig,
+    timeouts: TimeoutConfig,
+    forwarding: Forwarding,
+    send_proxy_protocol: Option<ProxyProtocol>,
+    max_attempts: u32,
+    tls: Option<UpstreamTls>,
+    mirror: Option<MirrorConfig>,
+    circuit_breaker: Option<Arc<CircuitBreaker>>,
+    // HTTP 模式下到各个 backend 的空闲连接
+    idle: IdleConnections,
+    events: broadcast::Sender<HealthEvent>,
+}
+
+impl Backend {
+    pub fn addr(&self) -> &str {
+        &self.addr
+    }
+
+    pub fn weight(&self) -> u32 {
+        self.weight
+    }
+
+    /// 当前活跃的连接数
+    pub fn active(&self) -> usize {
+        self.active.load(Ordering::Relaxed)
+    }
+
+    /// 累计分配到的连接数
+    pub fn total(&self) -> u64 {
+        self.total.load(Ordering::Relaxed)
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Relaxed)
+    }
+
+    pub fn state(&self) -> BackendState {
+        match self.state.load(Ordering::Relaxed) {
+            1 => BackendState::Draining,
+            2 => BackendState::Disabled,
+            _ => BackendState::Active,
+        }
+    }
+
+    pub fn set_state(&self, state: BackendState) {
+        if self.state() != state {
+            info!("Backend {} is {:?}", self.addr, state);
+        }
+        self.state.store(state as u8, Ordering::Relaxed);
+        if state == BackendState::Disabled {
+            self.disabled.notify_waiters();
+        }
+    }
+
+    /// 等到 backend 被设置为 Disabled
+    pub(crate) async fn disabled(&self) {
+        loop {
+            // 先注册再检查, 避免错过检查之后的通知
+            let disabled = self.disabled.notified();
+            tokio::pin!(disabled);
+            disabled.as_mut().enable();
+            if self.state() == BackendState::Disabled {
+                return;
+            }
+            disabled.await;
+        }
+    }
+
+    pub fn stats(&self) -> BackendStats {
+        BackendStats {
+            addr: self.addr.clone(),
+            healthy: self.is_healthy(),
+            state: self.state(),
+            active: self.active(),
+            total: self.total(),
+            bytes_in: self.bytes_in.load(Ordering::Relaxed),
+            bytes_out: self.bytes_out.load(Ordering::Relaxed),
+        }
+    }
+
+    pub(crate) fn record(&self, transfer: &Transfer) {
+        self.bytes_in
+            .fetch_add(transfer.bytes_in, Ordering::Relaxed);
+        self.bytes_out
+            .fetch_add(transfer.bytes_out, Ordering::Relaxed);
+    }
+}
+
+impl BackendGuard {
+    fn new(backend: Arc<Backend>) -> Self {
+        backend.active.fetch_add(1, Ordering::Relaxed);
+        backend.total.fetch_add(1, Ordering::Relaxed);
+        Self { backend }
+    }
+}
+
+impl Deref for BackendGuard {
+    type Target = Backend;
+
+    fn deref(&self) -> &Self::Target {
+        &self.backend
+    }
+}
+
+impl Drop for BackendGuard {
+    fn drop(&mut self) {
+        self.backend.active.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl UpstreamPool {
+    /// 配置了 TLS 时读取 CA 证书, 读取失败返回错误
+    pub fn new(name: impl Into<String>, config: &UpstreamConfig) -> Result<Self> {
+        Self::with_previous(name, config, None)
+    }
+
+    /// 和 `new` 一样, 但地址和权重没变的 backend 沿用 `previous` 中的,
+    /// 保留健康状态、连接计数和 admin 设置的状态
+    pub(crate) fn with_previous(
+        name: impl Into<String>,
+        config: &UpstreamConfig,
+        previous: Option<&UpstreamPool>,
+    ) -> Result<Self> {
+        let name = name.into();
+        let tls = config
+            .tls
+            .as_ref()
+            .map(UpstreamTls::new)
+            .transpose()
+            .with_context(|| format!("upstreams.{}.tls", name))?;
+        let backends: Vec<_> = config
+            .servers
+            .iter()
+            .map(|server| {
+                let existing = previous
+                    .into_iter()
+                    .flat_map(|pool| &pool.backends)
+                    .find(|b| b.addr == server.addr && b.weight == server.weight);
+                match existing {
+                    Some(backend) => Arc::clone(backend),
+                    None => Arc::new(Backend {
+                        addr: server.addr.clone(),
+                        weight: server.weight,
+                        active: AtomicUsize::new(0),
+                        total: AtomicU64::new(0),
+                        bytes_in: AtomicU64::new(0),
+                        bytes_out: AtomicU64::new(0),
+                        healthy: AtomicBool::new(true),
+                        state: AtomicU8::new(BackendState::Active as u8),
+                        disabled: Notify::new(),
+                        streak: Mutex::new((0, 0)),
+                    }),
+                }
+            })
+            .collect();
+        // 配置没变时沿用之前的熔断器, 保留状态和计数
+        let circuit_breaker = config.circuit_breaker.as_ref().map(|breaker| {
+            previous
+                .and_then(|pool| pool.circuit_breaker.as_ref())
+                .filter(|previous| previous.config() == breaker)
+                .map(Arc::clone)
+                .unwrap_or_else(|| Arc::new(CircuitBreaker::new(&name, breaker.clone())))
+        });
+
+        let mut ring = Vec::new();
+        if config.strategy == Strategy::ConsistentHash {
+            for (i, backend) in backends.iter().enumerate() {
+                for v in 0..VIRTUAL_NODES * backend.weight {
+                    ring.push((hash(&(&backend.addr, v)), i));
+                }
+            }
+            ring.sort_unstable();
+        }
+
+        Ok(Self {
+            name,
+            strategy: config.strategy,
+            current_weights: Mutex::new(vec![0; backends.len()]),
+            backends,
+            next: AtomicUsize::new(0),
+            ring,
+            health_check: config.health_check.clone(),
+            timeouts: config.timeouts.clone(),
+            forwarding: config.forwarding,
+            send_proxy_protocol: config.send_proxy_protocol,
+            max_attempts: config.max_attempts,
+            tls,
+            mirror: config.mirror.clone(),
+            circuit_breaker,
+            idle: IdleConnections::default(),
+            events: broadcast::channel(HEALTH_EVENTS).0,
+        })
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn backends(&self) -> &[Arc<Backend>] {
+        &self.backends
+    }
+
+    pub fn health_check(&self) -> &HealthCheckConfig {
+        &self.health_check
+    }
+
+    pub fn timeouts(&self) -> &TimeoutConfig {
+        &self.timeouts
+    }
+
+    pub fn forwarding(&self) -> Forwarding {
+        self.forwarding
+    }
+
+    pub fn send_proxy_protocol(&self) -> Option<ProxyProtocol> {
+        self.send_proxy_protocol
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub(crate) fn tls(&self) -> Option<&UpstreamTls> {
+        self.tls.as_ref()
+    }
+
+    pub fn mirror(&self) -> Option<&MirrorConfig> {
+        self.mirror.as_ref()
+    }
+
+    pub fn circuit_breaker(&self) -> Option<&CircuitBreaker> {
+        self.circuit_breaker.as_deref()
+    }
+
+    /// 按熔断器的状态决定是否放行一个新连接, 没有配置熔断时总是放行
+    pub(crate) fn admit(&self) -> Result<Option<Attempt>, Rejected> {
+        match &self.circuit_breaker {
+            Some(breaker) => breaker.acquire().map(Some).ok_or(Rejected),
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn idle_connections(&self) -> &IdleConnections {
+        &self.idle
+    }
+
+    /// 订阅 backend 健康状态的变化
+    pub fn subscribe(&self) -> broadcast::Receiver<HealthEvent> {
+        self.events.subscribe()
+    }
+
+    /// 记录一次探测或者真实流量的结果, 连续失败/成功达到阈值时切换健康状态
+    pub fn report(&self, backend: &Backend, ok: bool) {
+        let mut streak = backend.streak.lock().unwrap();
+        let (failures, successes) = &mut *streak;
+        let healthy = backend.is_healthy();
+        let changed = if ok {
+            *failures = 0;
+            *successes = successes.saturating_add(1);
+            !healthy && *successes >= self.health_check.healthy_threshold
+        } else {
+            *successes = 0;
+            *failures = failures.saturating_add(1);
+            healthy && *failures >= self.health_check.unhealthy_threshold
+        };
+        if !changed {
+            return;
+        }
+
+        backend.healthy.store(!healthy, Ordering::Relaxed);
+        if healthy {
+            warn!("Backend {} in upstream {} is down", backend.addr, self.name);
+        } else {
+            info!("Backend {} in upstream {} is up", backend.addr, self.name);
+        }
+        // 没有订阅者时发送会失败, 忽略即可
+        let _ = self.events.send(HealthEvent {
+            upstream: self.name.clone(),
+            backend: backend.addr.clone(),
+            healthy: !healthy,
+        });
+    }
+
+    /// 按策略为来自 `client` 的连接选择一个健康的 backend, 都不健康时返回 None
+    pub fn select(&self, client: IpAddr) -> Option<BackendGuard> {
+        self.select_excluding(client, &[])
+    }
+
+    /// 和 `select` 一样, 但跳过 `exclude` 中的 backend 地址, 用于 connect 失败后重试
+    pub fn select_excluding(&self, client: IpAddr, exclude: &[String]) -> Option<BackendGuard> {
+        let candidates: Vec<usize> = (0..self.backends.len())
+            .filter(|&i| {
+                let backend = &self.backends[i];
+                backend.is_healthy()
+                    && backend.state() == BackendState::Active
+                    && !exclude.contains(&backend.addr)
+            })
+            .collect();
+        if candidates.is_empty() {
+            return None;
+        }
+        let index = match self.strategy {
+            Strategy::RoundRobin => {
+                candidates[self.next.fetch_add(1, Ordering::Relaxed) % candidates.len()]
+            }
+            Strategy::WeightedRoundRobin => self.select_weighted(&candidates),
+            Strategy::LeastConnections => self.select_least_connections(&candidates),
+            Strategy::RandomTwoChoices => self.select_two_choices(&candidates),
+            Strategy::ConsistentHash => self.select_hash(client, &candidates),
+        };
+        Some(BackendGuard::new(Arc::clone(&self.backends[index])))
+    }
+
+    // nginx 的 smooth weighted round-robin: 每轮给所有 backend 加上自身权重,
+    // 选 current weight 最大的, 再减去总权重, 这样高权重的 backend 不会被连续选中
+    fn select_weighted(&self, candidates: &[usize]) -> usize {
+        let mut current = self.current_weights.lock().unwrap();
+        let mut total = 0;
+        let mut best = candidates[0];
+        for &i in candidates {
+            current[i] += self.backends[i].weight as i64;
+            total += self.backends[i].weight as i64;
+            if current[i] > current[best] {
+                best = i;
+            }
+        }
+        current[best] -= total;
+        best
+    }
+
+    // 比较 active / weight, 交叉相乘避免浮点数
+    fn select_least_connections(&self, candidates: &[usize]) -> usize {
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        let n = candidates.len();
+        // 从轮转的位置开始找, 连接数相同时不会总是选中第一个
+        (0..n)
+            .map(|i| candidates[(start + i) % n])
+            .min_by(|&a, &b| {
+                let (a, b) = (&self.backends[a], &self.backends[b]);
+                (a.active() as u64 * b.weight as u64).cmp(&(b.active() as u64 * a.weight as u64))
+            })
+            .unwrap_or(candidates[0])
+    }
+
+    fn select_two_choices(&self, candidates: &[usize]) -> usize {
+        let n = candidates.len();
+        if n == 1 {
+            return candidates[0];
+        }
+        let mut rng = rand::thread_rng();
+        let a = rng.gen_range(0..n);
+        // 第二个随机选择不能和第一个相同
+        let b = (a + rng.gen_range(1..n)) % n;
+        let (a, b) = (candidates[a], candidates[b]);
+        if self.backends[b].active() < self.backends[a].active() {
+            b
+        } else {
+            a
+        }
+    }
+
+    // 顺着哈希环找到第一个候选的 backend, 其他客户端的映射不受影响
+    fn select_hash(&self, client: IpAddr, candidates: &[usize]) -> usize {
+        let key = hash(&client);
+        let pos = self.ring.partition_point(|(h, _)| *h < key);
+        (0..self.ring.len())
+            .map(|i| self.ring[(pos + i) % self.ring.len()].1)
+            .find(|i| candidates.contains(i))
+            .unwrap_or(candidates[0])
+    }
+}
+
+/// 根据配置创建所有 upstream pool
+pub fn build_pools<'a>(
+    upstreams: impl IntoIterator<Item = (&'a String, &'a UpstreamConfig)>,
+) -> Result<HashMap<String, Arc<UpstreamPool>>> {
+    upstreams
+        .into_iter()
+        .map(|(name, config)| Ok((name.clone(), Arc::new(UpstreamPool::new(name, config)?))))
+        .collect()
+}
+
+fn hash(value: &impl Hash) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+    use crate::minginx::ServerConfig;
+
+    fn pool(strategy: Strategy, weights: &[u32]) -> UpstreamPool {
+        let servers = weights
+            .iter()
+            .enumerate()
+            .map(|(i, &weight)| ServerConfig {
+                addr: format!("127.0.0.1:{}", 4000 + i),
+                weight,
+            })
+            .collect();
+        let config = UpstreamConfig {
+            strategy,
+            servers,
+            health_check: HealthCheckConfig {
+                unhealthy_threshold: 2,
+                healthy_threshold: 2,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        UpstreamPool::new("test", &config).unwrap()
+    }
+
+    // 选择 n 次并且不释放连接, 返回每个 backend 被选中的次数
+    fn distribution(pool: &UpstreamPool, n: usize, client: impl Fn(usize) -> IpAddr) -> Vec<u64> {
+        let _guards: Vec<_> = (0..n).map(|i| pool.select(client(i)).unwrap()).collect();
+        pool.backends().iter().map(|b| b.total()).collect()
+    }
+
+    fn client(i: usize) -> IpAddr {
+        IpAddr::V4(Ipv4Addr::from(0x0a00_0000 + i as u32))
+    }
+
+    #[test]
+    fn round_robin_should_be_even() {
+        let pool = pool(Strategy::RoundRobin, &[1, 1, 1]);
+        assert_eq!(distribution(&pool, 300, client), [100, 100, 100]);
+    }
+
+    #[test]
+    fn weighted_round_robin_should_follow_weights() {
+        let pool = pool(Strategy::WeightedRoundRobin, &[5, 1, 1]);
+        // smooth: a a b a c a a, 高权重的 backend 不会连续被选满
+        let order: Vec<_> = (0..7)
+            .map(|i| pool.select(client(i)).unwrap().addr().to_string())
+            .collect();
+        assert_eq!(
+            order[..3],
+            ["127.0.0.1:4000", "127.0.0.1:4000", "127.0.0.1:4001"]
+        );
+        assert_eq!(distribution(&pool, 700, client), [505, 101, 101]);
+    }
+
+    #[test]
+    fn least_connections_should_balance_active_connections() {
+        let pool = pool(Strategy::LeastConnections, &[1, 1, 2]);
+        let _guards: Vec<_> = (0..400).map(|i| pool.select(client(i)).unwrap()).collect();
+        let active: Vec<_> = pool.backends().iter().map(|b| b.active()).collect();
+        assert_eq!(active, [100, 100, 200]);
+        drop(_guards);
+        assert!(pool.backends().iter().all(|b| b.active() == 0));
+    }
+
+    #[test]
+    fn random_two_choices_should_stay_close_to_even() {
+        let pool = pool(Strategy::RandomTwoChoices, &[1, 1, 1, 1]);
+        let counts = distribution(&pool, 4000, client);
+        // 连接一直保持, 两选一会把差距控制得很小
+        assert!(
+            counts.iter().all(|&c| (990..=1010).contains(&c)),
+            "{:?}",
+            counts
+        );
+    }
+
+    #[test]
+    fn consistent_hash_should_be_sticky_and_spread() {
+        let pool = pool(Strategy::ConsistentHash, &[1, 1, 1]);
+        let ip = client(42);
+        let first = pool.select(ip).unwrap().addr().to_string();
+        assert!((0..10).all(|_| pool.select(ip).unwrap().addr() == first));
+
+        let counts = distribution(&pool, 3000, client);
+        assert!(counts.iter().all(|&c| c > 600), "{:?}", counts);
+    }
+
+    #[test]
+    fn unhealthy_backend_should_be_skipped_until_it_recovers() {
+        let pool = pool(Strategy::RoundRobin, &[1, 1, 1]);
+        let mut events = pool.subscribe();
+        let down = Arc::clone(&pool.backends()[1]);
+
+        // 一次失败不会摘除, 中间的成功会重置计数
+        pool.report(&down, false);
+        pool.report(&down, true);
+        pool.report(&down, false);
+        assert!(down.is_healthy());
+        pool.report(&down, false);
+        assert!(!down.is_healthy());
+        assert_eq!(
+            events.try_recv().unwrap(),
+            HealthEvent {
+                upstream: "test".into(),
+                backend: down.addr().into(),
+                healthy: false,
+            }
+        );
+
+        let counts = distribution(&pool, 10, client);
+        assert_eq!(counts[1], 0);
+
+        pool.report(&down, true);
+        pool.report(&down, true);
+        assert!(down.is_healthy());
+        assert!(events.try_recv().unwrap().healthy);
+    }
+
+    #[test]
+    fn consistent_hash_should_only_move_clients_of_unhealthy_backend() {
+        let pool = pool(Strategy::ConsistentHash, &[1, 1, 1]);
+        let before: Vec<_> = (0..300)
+            .map(|i| pool.select(client(i)).unwrap().addr().to_string())
+            .collect();
+        let down = Arc::clone(&pool.backends()[0]);
+        pool.report(&down, false);
+        pool.report(&down, false);
+
+        for (i, addr) in before.iter().enumerate() {
+            let now = pool.select(client(i)).unwrap();
+            assert_ne!(now.addr(), down.addr());
+            if addr != down.addr() {
+                assert_eq!(now.addr(), addr);
+            }
+        }
+    }
+
+    #[test]
+    fn select_should_return_none_when_all_backends_are_down() {
+        let pool = pool(Strategy::LeastConnections, &[1]);
+        let backend = Arc::clone(&pool.backends()[0]);
+        pool.report(&backend, false);
+        pool.report(&backend, false);
+        assert!(pool.select(client(0)).is_none());
+    }
+
+    #[test]
+    fn select_excluding_should_skip_tried_backends() {
+        for strategy in [Strategy::RoundRobin, Strategy::ConsistentHash] {
+            let pool = pool(strategy, &[1, 1, 1]);
+            let first = pool.select(client(0)).unwrap().addr().to_string();
+            let mut tried = vec![first];
+            let second = pool.select_excluding(client(0), &tried).unwrap();
+            assert!(!tried.contains(&second.addr().to_string()));
+            tried.push(second.addr().to_string());
+            let third = pool.select_excluding(client(0), &tried).unwrap();
+            tried.push(third.addr().to_string());
+            assert!(pool.select_excluding(client(0), &tried).is_none());
+        }
+    }
+
+    #[test]
+    fn draining_backend_should_be_skipped_and_kept_on_rebuild() {
+        let pool = pool(Strategy::RoundRobin, &[1, 1]);
+        let drained = Arc::clone(&pool.backends()[0]);
+        drained.set_state(BackendState::Draining);
+        for i in 0..4 {
+            assert_eq!(pool.select(client(i)).unwrap().addr(), "127.0.0.1:4001");
+        }
+
+        // 新增一个 backend, 原来的 backend 和它的状态保留
+        let mut config = UpstreamConfig {
+            strategy: Strategy::RoundRobin,
+            servers: pool
+                .backends()
+                .iter()
+                .map(|b| ServerConfig {
+                    addr: b.addr().to_string(),
+                    weight: b.weight(),
+                })
+                .collect(),
+            ..Default::default()
+        };
+        config.servers.push(ServerConfig {
+            addr: "127.0.0.1:4002".into(),
+            weight: 1,
+        });
+        let rebuilt = UpstreamPool::with_previous("test", &config, Some(&pool)).unwrap();
+        assert!(Arc::ptr_eq(&rebuilt.backends()[0], &drained));
+        assert_eq!(rebuilt.backends()[0].stats().total, 0);
+        assert_eq!(rebuilt.backends()[1].total(), 4);
+        assert_eq!(rebuilt.backends()[2].state(), BackendState::Active);
+    }
+}
@@ -0,0 +1,277 @@
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{http::StatusCode, routing::post, Json, Router};
+use ecosystem::chat::{
+    ChatClient, ChatServer, ChatServerBuilder, ChatServerHandle, HttpShortener, Message, Shortener,
+    UrlShortenerPlugin,
+};
+use futures::{future::BoxFuture, FutureExt, SinkExt, StreamExt};
+use serde_json::{json, Value};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tokio_util::codec::{Framed, LinesCodec};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+async fn start_server(builder: &mut ChatServerBuilder) -> Result<ChatServerHandle> {
+    builder.addr("127.0.0.1:0").build()?.start().await
+}
+
+async fn next_message<S>(client: &mut ChatClient<S>) -> Result<Message>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match timeout(WAIT, client.next()).await? {
+        Some(message) => message,
+        None => anyhow::bail!("connection closed"),
+    }
+}
+
+#[tokio::test]
+async fn chat_should_broadcast_join_chat_and_leave() -> Result<()> {
+    let server = start_server(&mut ChatServer::builder()).await?;
+    let addr = server.local_addr();
+
+    let mut alice = ChatClient::connect(addr, "alice").await?;
+    let mut bob = ChatClient::connect(addr, "bob").await?;
+    assert_eq!(next_message(&mut alice).await?, Message::user_joined("bob"));
+
+    bob.send("hello: alice").await?;
+    assert_eq!(
+        next_message(&mut alice).await?,
+        Message::chat("bob", "hello: alice")
+    );
+
+    drop(bob);
+    assert_eq!(next_message(&mut alice).await?, Message::user_left("bob"));
+
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn unix_and_tcp_peers_should_talk_to_each_other() -> Result<()> {
+    let path = std::env::temp_dir().join(format!("ecosystem-chat-{}.sock", std::process::id()));
+    let server = start_server(ChatServer::builder().unix(path.clone())).await?;
+
+    let mut tcp = ChatClient::connect(server.local_addr(), "tcp").await?;
+    let mut unix = ChatClient::connect_unix(&path, "unix").await?;
+    assert_eq!(next_message(&mut tcp).await?, Message::user_joined("unix"));
+
+    unix.send("from unix").await?;
+    assert_eq!(
+        next_message(&mut tcp).await?,
+        Message::chat("unix", "from unix")
+    );
+    tcp.send("from tcp").await?;
+    assert_eq!(
+        next_message(&mut unix).await?,
+        Message::chat("tcp", "from tcp")
+    );
+
+    server.shutdown().await?;
+    assert!(!path.exists());
+    Ok(())
+}
+
+#[tokio::test]
+async fn server_should_refuse_peers_over_the_limit() -> Result<()> {
+    let server = start_server(ChatServer::builder().max_peers(1)).await?;
+    let addr = server.local_addr();
+
+    let mut alice = ChatClient::connect(addr, "alice").await?;
+    // 等 alice 被加入 state 之后再连接第二个 peer
+    let mut probe = ChatClient::connect(addr, "probe").await;
+    while probe.is_ok() {
+        drop(probe);
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        probe = ChatClient::connect(addr, "probe").await;
+    }
+    let err = probe.unwrap_err();
+    assert!(err.to_string().contains("Server is full"), "{}", err);
+
+    server.shutdown().await?;
+    // alice 可能先收到 probe 的 join/left 消息, 最终连接会被关闭
+    while let Some(message) = timeout(WAIT, alice.next()).await? {
+        message?;
+    }
+    Ok(())
+}
+
+// 还没有输入用户名的 peer 都通过了提前检查, 同时加入时只有 max_peers 个能成功
+#[tokio::test]
+async fn concurrent_handshakes_should_not_exceed_the_limit() -> Result<()> {
+    let server = start_server(ChatServer::builder().max_peers(1)).await?;
+    let mut peers = Vec::new();
+    for _ in 0..4 {
+        let stream = TcpStream::connect(server.local_addr()).await?;
+        let mut peer = Framed::new(stream, LinesCodec::new());
+        let prompt = timeout(WAIT, peer.next()).await?.unwrap()?;
+        assert_eq!(prompt, "Enter your username:");
+        peers.push(peer);
+    }
+    for (i, peer) in peers.iter_mut().enumerate() {
+        peer.send(format!("peer{}", i)).await?;
+    }
+    let mut refused = 0;
+    for peer in &mut peers {
+        if let Ok(Some(line)) = timeout(Duration::from_millis(300), peer.next()).await {
+            assert_eq!(line?, "Server is full");
+            refused += 1;
+        }
+    }
+    assert_eq!(refused, 3);
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn shutdown_should_not_wait_for_pending_handshakes() -> Result<()> {
+    let server = start_server(&mut ChatServer::builder()).await?;
+    let mut stream = TcpStream::connect(server.local_addr()).await?;
+    let mut prompt = [0; 8];
+    stream.read_exact(&mut prompt).await?;
+    timeout(WAIT, server.shutdown()).await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn shutdown_should_disconnect_clients() -> Result<()> {
+    let server = start_server(&mut ChatServer::builder()).await?;
+    let mut alice = ChatClient::connect(server.local_addr(), "alice").await?;
+
+    server.shutdown().await?;
+    // 用户名还没被读取时 server 就关闭的话, 客户端收到的是 RST 而不是 EOF
+    let next = timeout(WAIT, alice.next()).await?;
+    assert!(!matches!(next, Some(Ok(_))), "{:?}", next);
+    Ok(())
+}
+
+// 关闭时每个 peer 离开都会广播. 多线程 runtime 下被 abort 的 writer 可能在其他 worker 上
+// 先释放 receiver, 广播会遇到发送失败, 需要从 state 中移除
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn shutdown_should_disconnect_many_clients() -> Result<()> {
+    let server = start_server(&mut ChatServer::builder()).await?;
+    let addr = server.local_addr();
+    let mut first = ChatClient::connect(addr, "peer0").await?;
+    let mut others = Vec::new();
+    for i in 1..20 {
+        let username = format!("peer{}", i);
+        others.push(ChatClient::connect(addr, &username).await?);
+        // 收到 join 消息说明新的 peer 已经加入 state
+        assert_eq!(
+            next_message(&mut first).await?,
+            Message::user_joined(&username)
+        );
+    }
+
+    timeout(WAIT, server.shutdown()).await??;
+    for client in std::iter::once(&mut first).chain(&mut others) {
+        while let Some(Ok(_)) = timeout(WAIT, client.next()).await? {}
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn invalid_username_should_be_rejected_by_client() {
+    let err = ChatClient::connect("127.0.0.1:1", "a:b").await.unwrap_err();
+    assert!(err.to_string().contains("invalid username"), "{}", err);
+}
+
+#[derive(Debug, Default)]
+struct CountingShortener {
+    count: AtomicUsize,
+}
+
+impl Shortener for CountingShortener {
+    fn shorten<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, Result<String>> {
+        let id = self.count.fetch_add(1, Ordering::Relaxed) + 1;
+        async move { Ok(format!("http://s.io/{}", id)) }.boxed()
+    }
+}
+
+#[tokio::test]
+async fn shortener_plugin_should_rewrite_long_urls_and_expand_on_request() -> Result<()> {
+    let long = "https://example.com/a/very/long/path?with=query&and=more";
+    let plugin = UrlShortenerPlugin::new(Arc::new(CountingShortener::default()), 30);
+    let server = start_server(ChatServer::builder().plugin(Arc::new(plugin))).await?;
+    let addr = server.local_addr();
+
+    let mut alice = ChatClient::connect(addr, "alice").await?;
+    let mut bob = ChatClient::connect(addr, "bob").await?;
+    assert_eq!(next_message(&mut alice).await?, Message::user_joined("bob"));
+
+    bob.send(format!("look {} and http://a.io", long)).await?;
+    assert_eq!(
+        next_message(&mut alice).await?,
+        Message::chat("bob", "look http://s.io/1 and http://a.io")
+    );
+
+    // 查询原始链接只回复给请求者
+    alice.send("/expand http://s.io/1").await?;
+    assert_eq!(
+        next_message(&mut alice).await?,
+        Message::chat("shortener", format!("http://s.io/1 -> {}", long))
+    );
+    bob.send("done").await?;
+    assert_eq!(
+        next_message(&mut alice).await?,
+        Message::chat("bob", "done")
+    );
+
+    server.shutdown().await
+}
+
+#[derive(Debug)]
+struct HangingShortener;
+
+impl Shortener for HangingShortener {
+    fn shorten<'a>(&'a self, _url: &'a str) -> BoxFuture<'a, Result<String>> {
+        futures::future::pending().boxed()
+    }
+}
+
+#[tokio::test]
+async fn shortener_plugin_should_keep_original_url_when_shortener_hangs() -> Result<()> {
+    let plugin =
+        UrlShortenerPlugin::new(Arc::new(HangingShortener), 10).timeout(Duration::from_millis(100));
+    let server = start_server(ChatServer::builder().plugin(Arc::new(plugin))).await?;
+    let addr = server.local_addr();
+
+    let mut alice = ChatClient::connect(addr, "alice").await?;
+    let mut bob = ChatClient::connect(addr, "bob").await?;
+    assert_eq!(next_message(&mut alice).await?, Message::user_joined("bob"));
+    bob.send("see https://example.com/long").await?;
+    assert_eq!(
+        next_message(&mut alice).await?,
+        Message::chat("bob", "see https://example.com/long")
+    );
+    server.shutdown().await
+}
+
+#[tokio::test]
+async fn http_shortener_should_call_shortener_api() -> Result<()> {
+    let app = Router::new().route(
+        "/",
+        post(|Json(body): Json<Value>| async move {
+            let url = body["url"].as_str().unwrap_or_default();
+            let short = format!("http://short/{}", url.len());
+            (StatusCode::CREATED, Json(json!({ "url": short })))
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let endpoint = format!("http://{}/", listener.local_addr()?);
+    tokio::spawn(async move { axum::serve(listener, app).await });
+
+    let shortener = HttpShortener::new(endpoint);
+    assert_eq!(shortener.shorten("http://a.io").await?, "http://short/11");
+    Ok(())
+}
@@ -0,0 +1,2067 @@
+use std::{
+    collections::BTreeMap,
+    net::SocketAddr,
+    path::PathBuf,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::Result;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, Request},
+    http::{HeaderMap, StatusCode, Uri, Version},
+    response::Response,
+    routing::{any, get},
+    Router,
+};
+use bytes::Bytes;
+use ecosystem::minginx::{
+    self, replay, AccessConfig, AccessLogConfig, AccessLogFormat, AdminConfig, CaptureConfig,
+    CaptureFile, CircuitBreakerConfig, CircuitState, CloseReason, Config, Direction, Forwarding,
+    HeaderActions, HeaderRuleConfig, HealthCheckConfig, HealthEvent, HttpConfig, HttpRouteConfig,
+    ListenerConfig, ListenerTlsConfig, MirrorConfig, MissingSni, ProxyProtocol, RateLimitConfig,
+    ReplayOptions, ServerConfig, ShutdownReport, SniRoutingConfig, Strategy, TimeoutConfig,
+    TrafficStats, UdpConfig, UpstreamConfig, UpstreamTlsConfig,
+};
+use http_body_util::{BodyExt, Empty};
+use hyper_util::rt::{TokioExecutor, TokioIo};
+use rustls::{pki_types::ServerName, ClientConfig, ClientConnection, RootCertStore};
+use tokio::{
+    io::{AsyncBufRead, AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    net::{TcpListener, TcpStream, UdpSocket},
+    sync::{broadcast, mpsc},
+    time::timeout,
+};
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+
+const WAIT: Duration = Duration::from_secs(5);
+
+async fn start_backends(n: usize) -> Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::with_capacity(n);
+    for id in 0..n {
+        let listener = TcpListener::bind("127.0.0.1:0").await?;
+        addrs.push(listener.local_addr()?);
+        tokio::spawn(serve_backend(listener, id));
+    }
+    Ok(addrs)
+}
+
+// 本地的 backend: 连接建立后先回复自己的编号, 然后把收到的数据原样返回
+async fn serve_backend(listener: TcpListener, id: usize) {
+    while let Ok((mut stream, _)) = listener.accept().await {
+        tokio::spawn(async move {
+            stream.write_all(format!("{}\n", id).as_bytes()).await?;
+            let (mut reader, mut writer) = stream.split();
+            tokio::io::copy(&mut reader, &mut writer).await?;
+            Ok::<_, anyhow::Error>(())
+        });
+    }
+}
+
+fn config(strategy: Strategy, backends: &[SocketAddr], weights: &[u32]) -> Config {
+    config_with_health(strategy, backends, weights, Default::default())
+}
+
+fn config_with_health(
+    strategy: Strategy,
+    backends: &[SocketAddr],
+    weights: &[u32],
+    health_check: HealthCheckConfig,
+) -> Config {
+    let servers = backends
+        .iter()
+        .zip(weights)
+        .map(|(addr, &weight)| ServerConfig {
+            addr: addr.to_string(),
+            weight,
+        })
+        .collect();
+    Config {
+        listeners: vec![ListenerConfig {
+            upstream: "web".into(),
+            ..Default::default()
+        }],
+        upstreams: BTreeMap::from([(
+            "web".into(),
+            UpstreamConfig {
+                strategy,
+                servers,
+                health_check,
+                ..Default::default()
+            },
+        )]),
+        // 测试中不输出访问日志
+        access_log: AccessLogConfig {
+            path: Some("/dev/null".into()),
+            ..Default::default()
+        },
+        ..Default::default()
+    }
+}
+
+// 连接到 minginx, 返回 backend 编号和连接, 连接保持打开
+async fn connect(proxy: SocketAddr) -> Result<(usize, BufReader<TcpStream>)> {
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await?);
+    let mut line = String::new();
+    timeout(WAIT, stream.read_line(&mut line)).await??;
+    Ok((line.trim().parse()?, stream))
+}
+
+// 依次建立 n 个连接, 返回每个 backend 分到的连接数; keep_open 为 true 时返回所有连接, 否则逐个关闭
+async fn distribution(
+    proxy: SocketAddr,
+    n: usize,
+    keep_open: bool,
+) -> Result<(Vec<usize>, Vec<BufReader<TcpStream>>)> {
+    let mut counts = vec![0; 3];
+    let mut open = Vec::new();
+    for _ in 0..n {
+        let (id, stream) = connect(proxy).await?;
+        counts[id] += 1;
+        if keep_open {
+            open.push(stream);
+        }
+    }
+    Ok((counts, open))
+}
+
+#[tokio::test]
+async fn round_robin_should_spread_connections_evenly() -> Result<()> {
+    let backends = start_backends(3).await?;
+    let handle = minginx::start(config(Strategy::RoundRobin, &backends, &[1, 1, 1])).await?;
+    let proxy = handle.local_addrs()[0];
+
+    let (counts, _) = distribution(proxy, 30, false).await?;
+    assert_eq!(counts, [10, 10, 10]);
+
+    // 数据经过 minginx 原样往返
+    let (_, mut stream) = connect(proxy).await?;
+    stream.write_all(b"ping").await?;
+    let mut buf = [0; 4];
+    timeout(WAIT, stream.read_exact(&mut buf)).await??;
+    assert_eq!(&buf, b"ping");
+    Ok(())
+}
+
+#[tokio::test]
+async fn weighted_round_robin_should_follow_weights() -> Result<()> {
+    let backends = start_backends(3).await?;
+    let config = config(Strategy::WeightedRoundRobin, &backends, &[3, 2, 1]);
+    let handle = minginx::start(config).await?;
+
+    let (counts, _) = distribution(handle.local_addrs()[0], 60, false).await?;
+    assert_eq!(counts, [30, 20, 10]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn least_connections_should_track_live_connections() -> Result<()> {
+    let backends = start_backends(3).await?;
+    let config = config(Strategy::LeastConnections, &backends, &[1, 1, 2]);
+    let handle = minginx::start(config).await?;
+    let pool = handle.pool("web").unwrap();
+
+    // 连接保持打开, 按 active / weight 分配
+    let (counts, _open) = distribution(handle.local_addrs()[0], 12, true).await?;
+    assert_eq!(counts, [3, 3, 6]);
+    let active: Vec<_> = pool.backends().iter().map(|b| b.active()).collect();
+    assert_eq!(active, [3, 3, 6]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn random_two_choices_should_stay_balanced() -> Result<()> {
+    let backends = start_backends(3).await?;
+    let config = config(Strategy::RandomTwoChoices, &backends, &[1, 1, 1]);
+    let handle = minginx::start(config).await?;
+
+    // 连接保持打开, 两选一会把 backend 之间的差距控制得很小
+    let (counts, _open) = distribution(handle.local_addrs()[0], 30, true).await?;
+    assert!(
+        counts.iter().all(|&c| (8..=12).contains(&c)),
+        "{:?}",
+        counts
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn consistent_hash_should_pin_client_to_one_backend() -> Result<()> {
+    let backends = start_backends(3).await?;
+    let config = config(Strategy::ConsistentHash, &backends, &[1, 1, 1]);
+    let handle = minginx::start(config).await?;
+
+    let (counts, _) = distribution(handle.local_addrs()[0], 10, false).await?;
+    assert!(counts.contains(&10), "{:?}", counts);
+    Ok(())
+}
+
+async fn next_event(events: &mut broadcast::Receiver<HealthEvent>) -> Result<HealthEvent> {
+    Ok(timeout(WAIT, events.recv()).await??)
+}
+
+#[tokio::test]
+async fn active_health_check_should_remove_and_restore_backend() -> Result<()> {
+    let mut backends = start_backends(2).await?;
+    // 第三个 backend 先不监听
+    let dead = TcpListener::bind("127.0.0.1:0").await?;
+    backends.push(dead.local_addr()?);
+    drop(dead);
+
+    let health_check = HealthCheckConfig {
+        interval: Duration::from_millis(50),
+        unhealthy_threshold: 1,
+        healthy_threshold: 2,
+        ..Default::default()
+    };
+    let config = config_with_health(Strategy::RoundRobin, &backends, &[1, 1, 1], health_check);
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+    let mut events = handle.pool("web").unwrap().subscribe();
+
+    let event = next_event(&mut events).await?;
+    assert_eq!(
+        (event.backend.as_str(), event.healthy),
+        (&*backends[2].to_string(), false)
+    );
+    let (counts, _) = distribution(proxy, 10, false).await?;
+    assert_eq!(counts, [5, 5, 0]);
+
+    // backend 恢复监听之后, 连续两次探测成功重新加入
+    let listener = TcpListener::bind(backends[2]).await?;
+    tokio::spawn(serve_backend(listener, 2));
+    let event = next_event(&mut events).await?;
+    assert_eq!(
+        (event.backend.as_str(), event.healthy),
+        (&*backends[2].to_string(), true)
+    );
+    let (counts, _) = distribution(proxy, 9, false).await?;
+    assert_eq!(counts, [3, 3, 3]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn passive_health_check_should_mark_backend_down_from_traffic() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let dead = TcpListener::bind("127.0.0.1:0").await?;
+    let dead_addr = dead.local_addr()?;
+    // 主动探测间隔很长, 只有启动时探测一次, 此时 backend 是好的
+    let health_check = HealthCheckConfig {
+        interval: Duration::from_secs(3600),
+        unhealthy_threshold: 2,
+        ..Default::default()
+    };
+    let config = config_with_health(
+        Strategy::RoundRobin,
+        &[backends[0], dead_addr],
+        &[1, 1],
+        health_check,
+    );
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+    let pool = handle.pool("web").unwrap();
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    assert!(pool.backends()[1].is_healthy());
+    drop(dead);
+
+    // 发给已经关闭的 backend 的连接失败后转到另一个 backend, 失败两次之后 backend 被摘除
+    let mut events = pool.subscribe();
+    let (counts, _) = distribution(proxy, 4, false).await?;
+    assert_eq!(counts, [4, 0, 0]);
+    let event = next_event(&mut events).await?;
+    assert_eq!(
+        (event.backend, event.healthy),
+        (dead_addr.to_string(), false)
+    );
+    let (counts, _) = distribution(proxy, 5, false).await?;
+    assert_eq!(counts, [5, 0, 0]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn reload_should_switch_new_connections_and_keep_old_sessions() -> Result<()> {
+    let backends = start_backends(3).await?;
+    let handle = minginx::start(config(Strategy::RoundRobin, &backends[..1], &[1])).await?;
+    let proxy = handle.local_addrs()[0];
+    let (id, mut old) = connect(proxy).await?;
+    assert_eq!(id, 0);
+
+    handle.reload(config(Strategy::RoundRobin, &backends[1..], &[1, 1]))?;
+    let (counts, _) = distribution(proxy, 4, false).await?;
+    assert_eq!(counts, [0, 2, 2]);
+
+    // reload 之前建立的连接还连着原来的 backend
+    old.write_all(b"ping").await?;
+    let mut buf = [0; 4];
+    timeout(WAIT, old.read_exact(&mut buf)).await??;
+    assert_eq!(&buf, b"ping");
+    Ok(())
+}
+
+#[tokio::test]
+async fn reload_should_reject_invalid_config_and_keep_unchanged_pools() -> Result<()> {
+    let backends = start_backends(3).await?;
+    let mut config = config(Strategy::RoundRobin, &backends[..1], &[1]);
+    let handle = minginx::start(config.clone()).await?;
+    let proxy = handle.local_addrs()[0];
+    let web = handle.pool("web").unwrap();
+
+    let mut invalid = config.clone();
+    invalid.listeners[0].upstream = "api".into();
+    let err = handle.reload(invalid).unwrap_err();
+    assert!(err.to_string().contains("listeners[0].upstream"), "{}", err);
+
+    let mut moved = config.clone();
+    moved.listeners[0].listen_addr = "127.0.0.1:1".parse()?;
+    assert!(handle.reload(moved).is_err());
+    assert_eq!(*handle.config(), config);
+    assert_eq!(distribution(proxy, 2, false).await?.0, [2, 0, 0]);
+
+    // 新增一个 upstream, web 没有变化, 继续使用原来的 pool
+    let api = UpstreamConfig {
+        servers: vec![ServerConfig {
+            addr: backends[2].to_string(),
+            weight: 1,
+        }],
+        ..config.upstreams["web"].clone()
+    };
+    config.upstreams.insert("api".into(), api);
+    handle.reload(config)?;
+    assert!(Arc::ptr_eq(&web, &handle.pool("web").unwrap()));
+    assert_eq!(web.backends()[0].total(), 2);
+    assert!(handle.pool("api").is_some());
+    Ok(())
+}
+
+static NEXT_FILE: AtomicUsize = AtomicUsize::new(0);
+
+fn write_config(path: &PathBuf, backend: SocketAddr) -> Result<()> {
+    let content = format!(
+        "listeners:\n  - listen_addr: 127.0.0.1:0\n    upstream: web\nupstreams:\n  web:\n    servers:\n      - addr: {}\naccess_log:\n  path: /dev/null\n",
+        backend
+    );
+    std::fs::write(path, content)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn watch_should_reload_when_config_file_changes() -> Result<()> {
+    let backends = start_backends(2).await?;
+    let path = std::env::temp_dir().join(format!(
+        "minginx-watch-{}-{}.yml",
+        std::process::id(),
+        NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    write_config(&path, backends[0])?;
+    let mut handle = minginx::start(Config::load(&path)?).await?;
+    handle.watch(&path)?;
+    let proxy = handle.local_addrs()[0];
+    assert_eq!(connect(proxy).await?.0, 0);
+
+    write_config(&path, backends[1])?;
+    timeout(WAIT, async {
+        while connect(proxy).await?.0 != 1 {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
+
+    // 写坏的配置被拒绝, 继续使用上一次的配置
+    std::fs::write(&path, "listeners: [")?;
+    tokio::time::sleep(Duration::from_millis(300)).await;
+    assert_eq!(connect(proxy).await?.0, 1);
+    std::fs::remove_file(&path)?;
+    Ok(())
+}
+
+// 一个没有监听的本地地址
+fn closed_addr() -> Result<SocketAddr> {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0")?;
+    Ok(listener.local_addr()?)
+}
+
+#[tokio::test]
+async fn connect_failure_should_fail_over_to_next_backend() -> Result<()> {
+    let mut backends = start_backends(3).await?;
+    backends[0] = closed_addr()?;
+    backends[1] = closed_addr()?;
+    // 阈值很高, 坏掉的 backend 一直留在 pool 里, 只能靠重试绕过
+    let health_check = HealthCheckConfig {
+        interval: Duration::from_secs(3600),
+        unhealthy_threshold: 1000,
+        ..Default::default()
+    };
+    let config = config_with_health(Strategy::RoundRobin, &backends, &[1, 1, 1], health_check);
+    let handle = minginx::start(config.clone()).await?;
+    let (counts, _) = distribution(handle.local_addrs()[0], 6, false).await?;
+    assert_eq!(counts, [0, 0, 6]);
+
+    // 只尝试一次的时候, 选中坏掉的 backend 就直接断开客户端
+    let mut config = config;
+    config.upstreams.get_mut("web").unwrap().max_attempts = 1;
+    handle.reload(config)?;
+    let mut failed = 0;
+    for _ in 0..6 {
+        if connect(handle.local_addrs()[0]).await.is_err() {
+            failed += 1;
+        }
+    }
+    assert_eq!(failed, 4);
+    Ok(())
+}
+
+async fn start_with_timeouts(timeouts: TimeoutConfig) -> Result<minginx::ProxyHandle> {
+    let backends = start_backends(1).await?;
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.upstreams.get_mut("web").unwrap().timeouts = timeouts;
+    minginx::start(config).await
+}
+
+async fn ping(stream: &mut BufReader<TcpStream>) -> Result<()> {
+    stream.write_all(b"ping").await?;
+    let mut buf = [0; 4];
+    timeout(WAIT, stream.read_exact(&mut buf)).await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn idle_session_should_be_closed() -> Result<()> {
+    let handle = start_with_timeouts(TimeoutConfig {
+        idle: Duration::from_millis(300),
+        ..Default::default()
+    })
+    .await?;
+    let (_, mut stream) = connect(handle.local_addrs()[0]).await?;
+
+    // 持续有数据的连接不会因为 idle 被断开
+    for _ in 0..6 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        ping(&mut stream).await?;
+    }
+    let mut buf = Vec::new();
+    let n = timeout(WAIT, stream.read_to_end(&mut buf)).await??;
+    assert_eq!(n, 0);
+    Ok(())
+}
+
+#[tokio::test]
+async fn session_should_be_closed_after_max_session() -> Result<()> {
+    let handle = start_with_timeouts(TimeoutConfig {
+        max_session: Duration::from_millis(300),
+        ..Default::default()
+    })
+    .await?;
+    let (_, mut stream) = connect(handle.local_addrs()[0]).await?;
+
+    let start = tokio::time::Instant::now();
+    timeout(WAIT, async {
+        while ping(&mut stream).await.is_ok() {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await?;
+    assert!(start.elapsed() >= Duration::from_millis(250));
+    Ok(())
+}
+
+// 客户端看到连接关闭时统计可能还没更新, 等所有连接都结束
+async fn settled_stats(handle: &minginx::ProxyHandle) -> Result<TrafficStats> {
+    let stats = timeout(WAIT, async {
+        loop {
+            let stats = handle.stats();
+            if stats.active == 0 {
+                return stats;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await?;
+    Ok(stats)
+}
+
+#[tokio::test]
+async fn sessions_should_be_counted_and_logged() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let log = std::env::temp_dir().join(format!(
+        "minginx-access-{}-{}.log",
+        std::process::id(),
+        NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.upstreams.get_mut("web").unwrap().timeouts.idle = Duration::from_millis(200);
+    config.access_log = AccessLogConfig {
+        path: Some(log.clone()),
+        format: AccessLogFormat::Template("$upstream $backend $bytes_in $bytes_out $reason".into()),
+    };
+    config.admin = Some(AdminConfig {
+        listen_addr: "127.0.0.1:0".parse()?,
+    });
+    let handle = minginx::start(config).await?;
+
+    // backend 先回复 "0\n", 然后原样返回 "ping"
+    let (_, mut stream) = connect(handle.local_addrs()[0]).await?;
+    ping(&mut stream).await?;
+    let mut buf = Vec::new();
+    timeout(WAIT, stream.read_to_end(&mut buf)).await??;
+
+    let stats = settled_stats(&handle).await?;
+    assert_eq!(
+        (stats.connections, stats.bytes_in, stats.bytes_out),
+        (1, 4, 6)
+    );
+    assert_eq!(stats.close_reasons[&CloseReason::IdleTimeout], 1);
+
+    let url = format!("http://{}/stats", handle.admin_addr().unwrap());
+    let admin: serde_json::Value = reqwest::get(url).await?.json().await?;
+    assert_eq!(admin["bytes_out"], 6);
+    assert_eq!(admin["close_reasons"]["idle_timeout"], 1);
+    assert_eq!(admin["upstreams"]["web"][0]["bytes_in"], 4);
+
+    // 访问日志由后台线程写入
+    let line = timeout(WAIT, async {
+        loop {
+            let content = std::fs::read_to_string(&log).unwrap_or_default();
+            if !content.is_empty() {
+                return content;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await?;
+    assert_eq!(line, format!("web {} 4 6 idle_timeout\n", backends[0]));
+    std::fs::remove_file(&log)?;
+    Ok(())
+}
+
+// 读到 EOF 之后才回复收到的字节数, 依赖 minginx 把客户端的 FIN 传过来
+async fn start_counting_backend() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await?;
+                tokio::time::sleep(Duration::from_millis(50)).await;
+                stream.write_all(buf.len().to_string().as_bytes()).await?;
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn half_close_should_be_propagated() -> Result<()> {
+    let data: Vec<u8> = (0..1024 * 1024).map(|i| (i % 251) as u8).collect();
+    for forwarding in [Forwarding::Copy, Forwarding::Splice] {
+        let backends = [start_counting_backend().await?];
+        let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+        config.upstreams.get_mut("web").unwrap().forwarding = forwarding;
+        let handle = minginx::start(config).await?;
+        let mut stream = TcpStream::connect(handle.local_addrs()[0]).await?;
+
+        // 客户端关闭写之后, 仍然能收到 backend 的回复
+        stream.write_all(&data).await?;
+        stream.shutdown().await?;
+        let mut reply = String::new();
+        timeout(WAIT, stream.read_to_string(&mut reply)).await??;
+        assert_eq!(reply, data.len().to_string(), "{:?}", forwarding);
+
+        let stats = settled_stats(&handle).await?;
+        assert_eq!(stats.bytes_in, data.len() as u64, "{:?}", forwarding);
+        assert_eq!(stats.close_reasons[&CloseReason::Closed], 1);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn splice_should_forward_data_unchanged() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.upstreams.get_mut("web").unwrap().forwarding = Forwarding::Splice;
+    let handle = minginx::start(config).await?;
+    let (_, stream) = connect(handle.local_addrs()[0]).await?;
+    let (mut reader, mut writer) = tokio::io::split(stream);
+
+    let data: Vec<u8> = (0..4 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+    let sent = data.clone();
+    let write = tokio::spawn(async move {
+        writer.write_all(&sent).await?;
+        writer.shutdown().await?;
+        Ok::<_, anyhow::Error>(())
+    });
+    let mut echoed = Vec::new();
+    timeout(WAIT, reader.read_to_end(&mut echoed)).await??;
+    write.await??;
+    assert!(echoed == data);
+    Ok(())
+}
+
+// 模拟需要真实客户端地址的 upstream: 自己解析 PROXY 头部, 回复 "source destination\n"
+async fn start_proxy_protocol_backend() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                let reply = parse_proxy_header(&mut stream).await?;
+                stream.write_all(format!("{}\n", reply).as_bytes()).await?;
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok(addr)
+}
+
+async fn parse_proxy_header(stream: &mut BufReader<TcpStream>) -> Result<String> {
+    let mut prefix = [0u8; 6];
+    stream.read_exact(&mut prefix).await?;
+    if &prefix == b"PROXY " {
+        let mut line = String::new();
+        stream.read_line(&mut line).await?;
+        // TCP4 src dst sport dport
+        let parts: Vec<_> = line.trim_end().split(' ').collect();
+        return Ok(format!(
+            "{}:{} {}:{}",
+            parts[1], parts[3], parts[2], parts[4]
+        ));
+    }
+    let mut rest = [0u8; 10];
+    stream.read_exact(&mut rest).await?;
+    assert_eq!(&prefix, b"\r\n\r\n\0\r");
+    assert_eq!(&rest[..6], b"\nQUIT\n");
+    // v2 PROXY, TCP over IPv4
+    assert_eq!(&rest[6..8], b"\x21\x11");
+    let mut body = vec![0u8; u16::from_be_bytes([rest[8], rest[9]]) as usize];
+    stream.read_exact(&mut body).await?;
+    let ip = |b: &[u8]| std::net::Ipv4Addr::new(b[0], b[1], b[2], b[3]);
+    let port = |b: &[u8]| u16::from_be_bytes([b[0], b[1]]);
+    Ok(format!(
+        "{}:{} {}:{}",
+        ip(&body[0..4]),
+        port(&body[8..10]),
+        ip(&body[4..8]),
+        port(&body[10..12])
+    ))
+}
+
+async fn read_reply(stream: &mut (impl AsyncBufRead + Unpin)) -> Result<String> {
+    let mut line = String::new();
+    timeout(WAIT, stream.read_line(&mut line)).await??;
+    Ok(line.trim_end().to_string())
+}
+
+#[tokio::test]
+async fn upstream_should_receive_client_address_in_proxy_header() -> Result<()> {
+    let backends = [start_proxy_protocol_backend().await?];
+    for version in [ProxyProtocol::V1, ProxyProtocol::V2] {
+        let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+        config.upstreams.get_mut("web").unwrap().send_proxy_protocol = Some(version);
+        let handle = minginx::start(config).await?;
+        let proxy = handle.local_addrs()[0];
+
+        let mut stream = BufReader::new(TcpStream::connect(proxy).await?);
+        let client = stream.get_ref().local_addr()?;
+        let reply = read_reply(&mut stream).await?;
+        assert_eq!(reply, format!("{} {}", client, proxy), "{:?}", version);
+    }
+    Ok(())
+}
+
+#[tokio::test]
+async fn listener_should_accept_proxy_header_from_load_balancer() -> Result<()> {
+    let backends = [start_proxy_protocol_backend().await?];
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.listeners[0].accept_proxy_protocol = true;
+    config.upstreams.get_mut("web").unwrap().send_proxy_protocol = Some(ProxyProtocol::V1);
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+
+    // 前面的负载均衡器用 v2 发送真实地址, minginx 用 v1 继续传给 upstream
+    let source: SocketAddr = "203.0.113.7:4242".parse()?;
+    let destination: SocketAddr = "198.51.100.1:443".parse()?;
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await?);
+    let header = minginx::encode_proxy_header(ProxyProtocol::V2, source, destination);
+    stream.write_all(&header).await?;
+    let reply = read_reply(&mut stream).await?;
+    assert_eq!(reply, format!("{} {}", source, destination));
+    drop(stream);
+
+    // 没有 PROXY 头部的连接被直接关闭
+    let mut stream = TcpStream::connect(proxy).await?;
+    stream.write_all(b"GET / HTTP/1.1\r\n\r\n").await?;
+    // 未读完的数据会让关闭变成 RST, EOF 和 reset 都可以
+    let mut buf = Vec::new();
+    let ret = timeout(WAIT, stream.read_to_end(&mut buf)).await?;
+    assert!(matches!(ret, Ok(0)) || ret.is_err(), "{:?}", ret);
+    let stats = settled_stats(&handle).await?;
+    assert_eq!(
+        stats.close_reasons.get(&CloseReason::ProtocolError),
+        Some(&1)
+    );
+    Ok(())
+}
+
+// fixtures/tls 中的证书由测试 CA 签发, 对 localhost 和 127.0.0.1 有效
+fn listener_tls() -> ListenerTlsConfig {
+    ListenerTlsConfig {
+        cert: "fixtures/tls/server.pem".into(),
+        key: "fixtures/tls/server.key".into(),
+    }
+}
+
+#[test]
+fn check_should_load_tls_files() -> Result<()> {
+    let mut config = config(Strategy::RoundRobin, &[closed_addr()?], &[1]);
+    config.listeners[0].tls = Some(listener_tls());
+    minginx::check(&config)?;
+
+    config.listeners[0].tls = Some(ListenerTlsConfig {
+        cert: "fixtures/tls/missing.pem".into(),
+        ..listener_tls()
+    });
+    let err = format!("{:#}", minginx::check(&config).unwrap_err());
+    assert!(err.contains("listeners[0].tls"), "{}", err);
+    Ok(())
+}
+
+fn tls_connector() -> Result<TlsConnector> {
+    Ok(TlsConnector::from(Arc::new(tls_client_config()?)))
+}
+
+fn tls_client_config() -> Result<ClientConfig> {
+    let mut roots = RootCertStore::empty();
+    let mut pem = std::io::BufReader::new(std::fs::File::open("fixtures/tls/ca.pem")?);
+    for cert in rustls_pemfile::certs(&mut pem) {
+        roots.add(cert?)?;
+    }
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth();
+    Ok(config)
+}
+
+// TLS backend: 握手后回复客户端发送的 SNI (没有时回复 "-"), 然后把收到的数据原样返回
+async fn start_tls_backend() -> Result<SocketAddr> {
+    let tls = listener_tls();
+    let certs = rustls_pemfile::certs(&mut std::io::BufReader::new(std::fs::File::open(
+        &tls.cert,
+    )?))
+    .collect::<Result<Vec<_>, _>>()?;
+    let key =
+        rustls_pemfile::private_key(&mut std::io::BufReader::new(std::fs::File::open(&tls.key)?))?
+            .unwrap();
+    let config = rustls::ServerConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_no_client_auth()
+    .with_single_cert(certs, key)?;
+    let acceptor = TlsAcceptor::from(Arc::new(config));
+
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            let acceptor = acceptor.clone();
+            tokio::spawn(async move {
+                let mut stream = acceptor.accept(stream).await?;
+                let sni = stream.get_ref().1.server_name().unwrap_or("-").to_string();
+                stream.write_all(format!("{}\n", sni).as_bytes()).await?;
+                let (mut reader, mut writer) = tokio::io::split(stream);
+                tokio::io::copy(&mut reader, &mut writer).await?;
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn listener_should_terminate_tls() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.listeners[0].tls = Some(listener_tls());
+    let handle = minginx::start(config).await?;
+
+    let stream = TcpStream::connect(handle.local_addrs()[0]).await?;
+    let stream = tls_connector()?
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?;
+    let mut stream = BufReader::new(stream);
+    // backend 收到的是明文
+    assert_eq!(read_reply(&mut stream).await?, "0");
+    stream.write_all(b"hello\n").await?;
+    assert_eq!(read_reply(&mut stream).await?, "hello");
+    stream.shutdown().await?;
+    let mut rest = Vec::new();
+    timeout(WAIT, stream.read_to_end(&mut rest)).await??;
+
+    let stats = settled_stats(&handle).await?;
+    assert_eq!((stats.bytes_in, stats.bytes_out), (6, 8));
+    assert_eq!(stats.close_reasons[&CloseReason::Closed], 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn upstream_should_originate_tls_with_custom_ca_and_sni() -> Result<()> {
+    let backends = [start_tls_backend().await?];
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    let upstream_tls = |sni: Option<&str>, ca: Option<&str>| UpstreamTlsConfig {
+        ca: ca.map(PathBuf::from),
+        sni: sni.map(String::from),
+    };
+    config.upstreams.get_mut("web").unwrap().tls =
+        Some(upstream_tls(Some("localhost"), Some("fixtures/tls/ca.pem")));
+    let handle = minginx::start(config.clone()).await?;
+    let proxy = handle.local_addrs()[0];
+
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await?);
+    assert_eq!(read_reply(&mut stream).await?, "localhost");
+    stream.write_all(b"ping\n").await?;
+    assert_eq!(read_reply(&mut stream).await?, "ping");
+    drop(stream);
+
+    // 不配置 sni 时用 backend 的 IP 校验证书, IP 不会作为 SNI 发送
+    config.upstreams.get_mut("web").unwrap().tls =
+        Some(upstream_tls(None, Some("fixtures/tls/ca.pem")));
+    handle.reload(config.clone())?;
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await?);
+    assert_eq!(read_reply(&mut stream).await?, "-");
+    drop(stream);
+
+    // 公共根证书不信任测试 CA, 握手失败
+    config.upstreams.get_mut("web").unwrap().tls = Some(upstream_tls(Some("localhost"), None));
+    handle.reload(config)?;
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await?);
+    assert_eq!(read_reply(&mut stream).await?, "");
+    drop(stream);
+    let stats = settled_stats(&handle).await?;
+    assert_eq!(stats.close_reasons[&CloseReason::NoUpstream], 1);
+    Ok(())
+}
+
+// 用 rustls 生成 ClientHello, 不需要完成握手
+fn client_hello(name: &str) -> Result<Vec<u8>> {
+    let config =
+        ClientConfig::builder_with_provider(Arc::new(rustls::crypto::ring::default_provider()))
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(RootCertStore::empty())
+            .with_no_client_auth();
+    let mut conn =
+        ClientConnection::new(Arc::new(config), ServerName::try_from(name.to_string())?)?;
+    let mut hello = Vec::new();
+    conn.write_tls(&mut hello)?;
+    Ok(hello)
+}
+
+#[tokio::test]
+async fn sni_routing_should_pick_upstream_and_forward_client_hello() -> Result<()> {
+    let backends = start_backends(3).await?;
+    let mut config = config(Strategy::RoundRobin, &backends[..1], &[1]);
+    let web = config.upstreams["web"].clone();
+    let tls_backend = start_tls_backend().await?;
+    for (name, backend) in [
+        ("api", backends[1]),
+        ("blog", backends[2]),
+        ("tls", tls_backend),
+    ] {
+        let mut upstream = web.clone();
+        upstream.servers[0].addr = backend.to_string();
+        config.upstreams.insert(name.into(), upstream);
+    }
+    config.listeners[0].sni_routing = Some(SniRoutingConfig {
+        routes: BTreeMap::from([
+            ("api.example.com".into(), "api".into()),
+            ("*.blog.example.com".into(), "blog".into()),
+            ("localhost".into(), "tls".into()),
+        ]),
+        missing: MissingSni::Default,
+    });
+    let handle = minginx::start(config.clone()).await?;
+    let proxy = handle.local_addrs()[0];
+
+    for (name, expected) in [
+        ("api.example.com", "1"),
+        ("me.blog.example.com", "2"),
+        ("other.example.com", "0"),
+        // IP 不会作为 SNI 发送, 使用默认的 upstream
+        ("127.0.0.1", "0"),
+    ] {
+        let hello = client_hello(name)?;
+        let mut stream = BufReader::new(TcpStream::connect(proxy).await?);
+        stream.write_all(&hello).await?;
+        assert_eq!(read_reply(&mut stream).await?, expected, "{}", name);
+        // backend 收到的 ClientHello 和客户端发送的完全一致
+        let mut echo = vec![0; hello.len()];
+        timeout(WAIT, stream.read_exact(&mut echo)).await??;
+        assert_eq!(echo, hello, "{}", name);
+    }
+
+    // minginx 不终止 TLS, 客户端和 backend 直接完成握手
+    let stream = TcpStream::connect(proxy).await?;
+    let stream = tls_connector()?
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?;
+    let mut stream = BufReader::new(stream);
+    assert_eq!(read_reply(&mut stream).await?, "localhost");
+
+    config.listeners[0].sni_routing.as_mut().unwrap().missing = MissingSni::Reject;
+    handle.reload(config)?;
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await?);
+    stream.write_all(&client_hello("127.0.0.1")?).await?;
+    assert_eq!(read_reply(&mut stream).await?, "");
+    Ok(())
+}
+
+// HTTP backend: 回复自己的编号, 连接的对端端口, URI, 以及 Host 和 minginx 添加的头部.
+// /headers 每行回复一个收到的头部, /upgrade 切换到 echo 协议, 把收到的数据原样返回
+async fn start_http_backend(id: usize) -> Result<SocketAddr> {
+    let app = Router::new()
+        .route("/upgrade", get(upgrade_echo))
+        .route("/headers", any(echo_headers))
+        .fallback(
+        move |ConnectInfo(peer): ConnectInfo<SocketAddr>, uri: Uri, headers: HeaderMap| async move {
+            let header = |name| {
+                headers
+                    .get(name)
+                    .and_then(|value| value.to_str().ok())
+                    .unwrap_or("-")
+                    .to_string()
+            };
+            format!(
+                "{} {} {} {} {} {}",
+                id,
+                peer.port(),
+                uri,
+                header("host"),
+                header("x-forwarded-for"),
+                header("x-forwarded-proto")
+            )
+        },
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        axum::serve(
+            listener,
+            app.into_make_service_with_connect_info::<SocketAddr>(),
+        )
+        .await
+    });
+    Ok(addr)
+}
+
+async fn echo_headers(headers: HeaderMap) -> String {
+    headers
+        .iter()
+        .map(|(name, value)| format!("{}: {}\n", name, value.to_str().unwrap_or("?")))
+        .collect()
+}
+
+async fn upgrade_echo(req: Request) -> Response {
+    tokio::spawn(async move {
+        let upgraded = TokioIo::new(hyper::upgrade::on(req).await?);
+        let (mut reader, mut writer) = tokio::io::split(upgraded);
+        tokio::io::copy(&mut reader, &mut writer).await?;
+        Ok::<_, anyhow::Error>(())
+    });
+    Response::builder()
+        .status(StatusCode::SWITCHING_PROTOCOLS)
+        .header("connection", "upgrade")
+        .header("upgrade", "echo")
+        .body(Body::empty())
+        .unwrap()
+}
+
+// web 是默认的 upstream; api.example.com 发给 api, /static 发给 static, down.example.com 没有可用的 backend
+async fn http_config() -> Result<Config> {
+    let backends = [
+        start_http_backend(0).await?,
+        start_http_backend(1).await?,
+        start_http_backend(2).await?,
+        closed_addr()?,
+    ];
+    let mut config = config(Strategy::RoundRobin, &backends[..1], &[1]);
+    let web = config.upstreams["web"].clone();
+    for (name, backend) in [
+        ("api", backends[1]),
+        ("static", backends[2]),
+        ("down", backends[3]),
+    ] {
+        let mut upstream = web.clone();
+        upstream.servers[0].addr = backend.to_string();
+        config.upstreams.insert(name.into(), upstream);
+    }
+    let route = |host: Option<&str>, path_prefix: &str, upstream: &str| HttpRouteConfig {
+        host: host.map(str::to_string),
+        path_prefix: path_prefix.into(),
+        upstream: upstream.into(),
+    };
+    config.listeners[0].http = Some(HttpConfig {
+        routes: vec![
+            route(Some("api.example.com"), "/", "api"),
+            route(Some("down.example.com"), "/", "down"),
+            route(None, "/static", "static"),
+        ],
+        headers: Vec::new(),
+    });
+    Ok(config)
+}
+
+async fn http_get(proxy: SocketAddr, host: &str, path: &str) -> Result<(u16, Vec<String>)> {
+    // 每个请求使用新的客户端连接
+    let resp = reqwest::Client::new()
+        .get(format!("http://{}{}", proxy, path))
+        .header("host", host)
+        .header("x-forwarded-for", "10.0.0.1")
+        .send()
+        .await?;
+    let status = resp.status().as_u16();
+    let body = resp.text().await?;
+    Ok((status, body.split(' ').map(str::to_string).collect()))
+}
+
+#[tokio::test]
+async fn http_mode_should_route_requests_and_reuse_upstream_connections() -> Result<()> {
+    let handle = minginx::start(http_config().await?).await?;
+    let proxy = handle.local_addrs()[0];
+
+    for (host, path, expected) in [
+        ("API.example.com:8080", "/users?id=1", "1"),
+        ("www.example.com", "/static/a.css", "2"),
+        ("www.example.com", "/", "0"),
+    ] {
+        let (status, reply) = http_get(proxy, host, path).await?;
+        assert_eq!(status, 200);
+        assert_eq!(reply[0], expected, "{} {}", host, path);
+        assert_eq!(reply[2], path);
+        assert_eq!(reply[3], host);
+        assert_eq!(reply[4..], ["10.0.0.1,", "127.0.0.1", "http"]);
+    }
+
+    // 上一个请求结束后, 到 backend 的连接被下一个客户端连接上的请求复用
+    let (_, first) = http_get(proxy, "api.example.com", "/").await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let (_, second) = http_get(proxy, "api.example.com", "/").await?;
+    assert_eq!(first[1], second[1]);
+
+    let (status, _) = http_get(proxy, "down.example.com", "/").await?;
+    assert_eq!(status, 502);
+    Ok(())
+}
+
+// 每个连接只回复第一个请求, 读到第二个请求之后关闭, 模拟 keep-alive 超时和请求同时发生.
+// 回复的是连接的编号, 健康检查的连接也计算在内
+async fn start_one_shot_http_backend() -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        let mut conns = 0;
+        while let Ok((stream, _)) = listener.accept().await {
+            conns += 1;
+            tokio::spawn(async move {
+                let mut stream = BufReader::new(stream);
+                for reply in [true, false] {
+                    while !read_reply(&mut stream).await?.is_empty() {}
+                    if reply {
+                        let body = conns.to_string();
+                        let head =
+                            format!("HTTP/1.1 200 OK\r\ncontent-length: {}\r\n\r\n", body.len());
+                        stream.write_all((head + &body).as_bytes()).await?;
+                    }
+                }
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn http_mode_should_retry_idempotent_requests_on_closed_idle_connections() -> Result<()> {
+    let mut config = http_config().await?;
+    let backend = start_one_shot_http_backend().await?;
+    config.upstreams.get_mut("api").unwrap().servers[0].addr = backend.to_string();
+    config.upstreams.get_mut("api").unwrap().circuit_breaker = Some(CircuitBreakerConfig {
+        failure_threshold: 1,
+        ..Default::default()
+    });
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+    let get = || async {
+        let resp = reqwest::Client::new()
+            .get(format!("http://{}/", proxy))
+            .header("host", "api.example.com")
+            .send()
+            .await?;
+        Ok::<_, anyhow::Error>((resp.status().as_u16(), resp.text().await?))
+    };
+
+    let (status, first) = get().await?;
+    assert_eq!(status, 200);
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    // 复用的连接被 backend 关闭, 在新连接上重发, 熔断器不计失败
+    let (status, second) = get().await?;
+    assert_eq!(status, 200);
+    assert_ne!(first, second);
+    let pool = handle.pool("api").unwrap();
+    assert_eq!(
+        pool.circuit_breaker().unwrap().state(),
+        CircuitState::Closed
+    );
+
+    // 有请求体的请求不能重发
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    let resp = reqwest::Client::new()
+        .post(format!("http://{}/", proxy))
+        .header("host", "api.example.com")
+        .body("data")
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 502);
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_mode_should_serve_http2_over_tls() -> Result<()> {
+    let mut config = http_config().await?;
+    config.listeners[0].tls = Some(listener_tls());
+    let handle = minginx::start(config).await?;
+
+    let mut client_config = tls_client_config()?;
+    client_config.alpn_protocols = vec![b"h2".to_vec()];
+    let stream = TcpStream::connect(handle.local_addrs()[0]).await?;
+    let stream = TlsConnector::from(Arc::new(client_config))
+        .connect(ServerName::try_from("localhost")?, stream)
+        .await?;
+    assert_eq!(stream.get_ref().1.alpn_protocol(), Some(&b"h2"[..]));
+    let (mut sender, conn) =
+        hyper::client::conn::http2::handshake(TokioExecutor::new(), TokioIo::new(stream)).await?;
+    tokio::spawn(conn);
+
+    // HTTP/2 的 Host 在 URI 中, backend 收到的是 HTTP/1.1 请求
+    for (uri, expected) in [
+        ("https://api.example.com/h2", "1 /h2 api.example.com"),
+        ("https://localhost/static/h2", "2 /static/h2 localhost"),
+    ] {
+        let req = hyper::Request::get(uri).body(Empty::<Bytes>::new())?;
+        let resp = timeout(WAIT, sender.send_request(req)).await??;
+        assert_eq!(resp.version(), Version::HTTP_2);
+        let body = resp.into_body().collect().await?.to_bytes();
+        let reply: Vec<_> = std::str::from_utf8(&body)?.split(' ').collect();
+        assert_eq!(
+            [reply[0], reply[2], reply[3]].join(" "),
+            expected,
+            "{}",
+            uri
+        );
+        assert_eq!(reply[4..], ["127.0.0.1", "https"]);
+    }
+    Ok(())
+}
+
+// 向 api.example.com 请求切换到 echo 协议, 返回切换之后的连接
+async fn upgrade(proxy: SocketAddr) -> Result<BufReader<TcpStream>> {
+    let mut stream = BufReader::new(TcpStream::connect(proxy).await?);
+    stream
+        .write_all(b"GET /upgrade HTTP/1.1\r\nHost: api.example.com\r\nConnection: Upgrade\r\nUpgrade: echo\r\n\r\n")
+        .await?;
+    let mut head = Vec::new();
+    loop {
+        let line = read_reply(&mut stream).await?;
+        if line.is_empty() {
+            break;
+        }
+        head.push(line.to_ascii_lowercase());
+    }
+    assert!(head[0].starts_with("http/1.1 101"), "{:?}", head);
+    assert!(head.contains(&"upgrade: echo".to_string()), "{:?}", head);
+    Ok(stream)
+}
+
+#[tokio::test]
+async fn http_mode_should_pass_upgrade_through() -> Result<()> {
+    let handle = minginx::start(http_config().await?).await?;
+    let mut stream = upgrade(handle.local_addrs()[0]).await?;
+
+    // 切换协议之后 minginx 双向转发原始数据
+    stream.write_all(b"ping\n").await?;
+    assert_eq!(read_reply(&mut stream).await?, "ping");
+    stream.shutdown().await?;
+    let mut rest = Vec::new();
+    timeout(WAIT, stream.read_to_end(&mut rest)).await??;
+    drop(stream);
+
+    let stats = settled_stats(&handle).await?;
+    assert_eq!(stats.close_reasons[&CloseReason::Closed], 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_mode_should_close_tunnels_and_idle_connections_of_disabled_backend() -> Result<()> {
+    let mut config = http_config().await?;
+    config.admin = Some(AdminConfig {
+        listen_addr: "127.0.0.1:0".parse()?,
+    });
+    let api = config.upstreams["api"].servers[0].addr.clone();
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+    let state_url = format!(
+        "http://{}/upstreams/api/backends/{}/state",
+        handle.admin_addr().unwrap(),
+        api
+    );
+    let set_state = |state: &'static str| {
+        reqwest::Client::new()
+            .put(&state_url)
+            .json(&serde_json::json!({ "state": state }))
+            .send()
+    };
+
+    let mut tunnel = upgrade(proxy).await?;
+    tunnel.write_all(b"ping\n").await?;
+    assert_eq!(read_reply(&mut tunnel).await?, "ping");
+    // 请求结束后到 backend 的连接放回空闲列表
+    let (_, first) = http_get(proxy, "api.example.com", "/").await?;
+    tokio::time::sleep(Duration::from_millis(100)).await;
+
+    assert_eq!(set_state("disabled").await?.status(), 204);
+    let mut rest = Vec::new();
+    timeout(WAIT, tunnel.read_to_end(&mut rest)).await??;
+
+    // 空闲连接已经被丢掉, 重新启用之后建立新的连接
+    assert_eq!(set_state("active").await?.status(), 204);
+    let (_, second) = http_get(proxy, "api.example.com", "/").await?;
+    assert_ne!(first[1], second[1]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn http_mode_should_rewrite_headers_and_add_request_id() -> Result<()> {
+    let mut config = http_config().await?;
+    let rule =
+        |methods: &[&str], request: HeaderActions, response: HeaderActions| HeaderRuleConfig {
+            host: None,
+            path_prefix: "/headers".into(),
+            methods: methods.iter().map(|method| method.to_string()).collect(),
+            request,
+            response,
+        };
+    config.listeners[0].http.as_mut().unwrap().headers = vec![
+        rule(
+            &[],
+            HeaderActions {
+                remove: vec!["x-secret".into()],
+                set: [("x-client".into(), "$client_ip via $upstream".into())].into(),
+                ..Default::default()
+            },
+            HeaderActions {
+                set: [("x-request-id".into(), "$request_id".into())].into(),
+                ..Default::default()
+            },
+        ),
+        rule(
+            &["POST"],
+            HeaderActions {
+                add: [("x-method".into(), "$method".into())].into(),
+                ..Default::default()
+            },
+            Default::default(),
+        ),
+    ];
+    let handle = minginx::start(config).await?;
+    let url = format!("http://{}/headers", handle.local_addrs()[0]);
+    let client = reqwest::Client::new();
+
+    let resp = client.get(&url).header("x-secret", "1").send().await?;
+    let request_id = resp.headers()["x-request-id"].to_str()?.to_string();
+    assert_eq!(request_id.len(), 32);
+    let body = resp.text().await?;
+    let headers: Vec<_> = body.lines().collect();
+    assert!(
+        headers.contains(&format!("x-request-id: {}", request_id).as_str()),
+        "{}",
+        body
+    );
+    assert!(headers.contains(&"x-client: 127.0.0.1 via web"), "{}", body);
+    assert!(
+        !body.contains("x-secret") && !body.contains("x-method"),
+        "{}",
+        body
+    );
+
+    // 已有的 X-Request-Id 原样转发
+    let resp = client
+        .post(&url)
+        .header("x-request-id", "from-lb")
+        .send()
+        .await?;
+    assert_eq!(resp.headers()["x-request-id"], "from-lb");
+    let body = resp.text().await?;
+    assert!(
+        body.lines().any(|line| line == "x-request-id: from-lb"),
+        "{}",
+        body
+    );
+    assert!(
+        body.lines().any(|line| line == "x-method: POST"),
+        "{}",
+        body
+    );
+    Ok(())
+}
+
+// 被拒绝的连接在 accept 之后立即关闭, backend 不会回复编号
+async fn assert_denied(proxy: SocketAddr) -> Result<()> {
+    let mut stream = TcpStream::connect(proxy).await?;
+    let mut buf = [0; 1];
+    let ret = timeout(WAIT, stream.read(&mut buf)).await?;
+    assert!(matches!(ret, Ok(0) | Err(_)), "{:?}", ret);
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_should_deny_clients_and_limit_connections() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.listeners[0].access = AccessConfig {
+        allow: vec!["127.0.0.0/8".parse()?],
+        max_connections_per_ip: 2,
+        ..Default::default()
+    };
+    let handle = minginx::start(config.clone()).await?;
+    let proxy = handle.local_addrs()[0];
+
+    let (_, first) = connect(proxy).await?;
+    let (_, second) = connect(proxy).await?;
+    assert_denied(proxy).await?;
+    // 连接关闭后名额释放
+    drop(first);
+    timeout(WAIT, async {
+        while connect(proxy).await.is_err() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await?;
+    drop(second);
+
+    config.listeners[0].access.deny = vec!["127.0.0.1/32".parse()?];
+    handle.reload(config)?;
+    assert_denied(proxy).await?;
+
+    let stats = settled_stats(&handle).await?;
+    assert!(
+        stats.close_reasons[&CloseReason::Denied] >= 2,
+        "{:?}",
+        stats
+    );
+    Ok(())
+}
+
+#[tokio::test]
+async fn access_should_rate_limit_new_connections_per_ip() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.listeners[0].access.rate_limit_per_ip = Some(RateLimitConfig {
+        per_second: 1,
+        burst: 3,
+    });
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+
+    for _ in 0..3 {
+        connect(proxy).await?;
+    }
+    assert_denied(proxy).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_should_drain_disable_add_and_remove_backends() -> Result<()> {
+    let backends = start_backends(3).await?;
+    let mut config = config(Strategy::RoundRobin, &backends[..2], &[1, 1]);
+    config.admin = Some(AdminConfig {
+        listen_addr: "127.0.0.1:0".parse()?,
+    });
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+    let admin = format!("http://{}", handle.admin_addr().unwrap());
+    let client = reqwest::Client::new();
+    let backend_url = |i: usize| format!("{}/upstreams/web/backends/{}", admin, backends[i]);
+    let set_state = |i: usize, state: &'static str| {
+        client
+            .put(format!("{}/state", backend_url(i)))
+            .json(&serde_json::json!({ "state": state }))
+            .send()
+    };
+
+    let (id, mut drained) = connect(proxy).await?;
+    assert_eq!(id, 0);
+    let sessions: serde_json::Value = client
+        .get(format!("{}/sessions", admin))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(sessions[0]["backend"], backends[0].to_string());
+
+    // draining: 新连接不再分给它, 已有的连接继续转发
+    assert_eq!(set_state(0, "draining").await?.status(), 204);
+    let (counts, _) = distribution(proxy, 4, false).await?;
+    assert_eq!(counts, [0, 4, 0]);
+    ping(&mut drained).await?;
+
+    // disabled: 已有的连接也被关闭
+    assert_eq!(set_state(0, "disabled").await?.status(), 204);
+    let mut rest = Vec::new();
+    timeout(WAIT, drained.read_to_end(&mut rest)).await??;
+
+    let resp = client
+        .post(format!("{}/upstreams/web/backends", admin))
+        .json(&serde_json::json!({ "addr": backends[2].to_string() }))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 201);
+    let resp = client.delete(backend_url(1)).send().await?;
+    assert_eq!(resp.status(), 204);
+    let (counts, _) = distribution(proxy, 4, false).await?;
+    assert_eq!(counts, [0, 0, 4]);
+
+    // 重建 pool 之后 backend 的状态和计数保留
+    let upstreams: serde_json::Value = client
+        .get(format!("{}/upstreams", admin))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(upstreams["web"][0]["state"], "disabled");
+    assert_eq!(upstreams["web"][0]["total"], 1);
+    assert_eq!(upstreams["web"][1]["addr"], backends[2].to_string());
+
+    let resp = client.delete(backend_url(1)).send().await?;
+    assert_eq!(resp.status(), 404);
+    let resp = client
+        .delete(format!("{}/upstreams/missing/backends/a:1", admin))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+    // 删掉最后一个 backend 的配置不能通过校验
+    client.delete(backend_url(0)).send().await?;
+    let resp = client.delete(backend_url(2)).send().await?;
+    assert_eq!(resp.status(), 400);
+
+    let stats = settled_stats(&handle).await?;
+    assert_eq!(stats.close_reasons[&CloseReason::Aborted], 1);
+    Ok(())
+}
+
+#[tokio::test]
+async fn admin_should_close_session_by_id() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.admin = Some(AdminConfig {
+        listen_addr: "127.0.0.1:0".parse()?,
+    });
+    let handle = minginx::start(config).await?;
+    let admin = format!("http://{}", handle.admin_addr().unwrap());
+    let client = reqwest::Client::new();
+
+    let (_, mut stream) = connect(handle.local_addrs()[0]).await?;
+    ping(&mut stream).await?;
+    let sessions: serde_json::Value = client
+        .get(format!("{}/sessions", admin))
+        .send()
+        .await?
+        .json()
+        .await?;
+    assert_eq!(sessions[0]["bytes_in"], 4);
+    let id = sessions[0]["id"].as_u64().unwrap();
+
+    let resp = client
+        .delete(format!("{}/sessions/{}", admin, id))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 204);
+    let mut rest = Vec::new();
+    timeout(WAIT, stream.read_to_end(&mut rest)).await??;
+    let resp = client
+        .delete(format!("{}/sessions/{}", admin, id))
+        .send()
+        .await?;
+    assert_eq!(resp.status(), 404);
+    Ok(())
+}
+
+#[tokio::test]
+async fn shutdown_should_drain_sessions_then_cut_the_rest() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.shutdown.grace_period = Duration::from_millis(500);
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+
+    let (_, mut finishing) = connect(proxy).await?;
+    let (_, mut stuck) = connect(proxy).await?;
+    let shutdown = tokio::spawn(handle.shutdown());
+
+    // 不再 accept, 已有的连接继续转发
+    tokio::time::sleep(Duration::from_millis(50)).await;
+    assert!(TcpStream::connect(proxy).await.is_err());
+    ping(&mut finishing).await?;
+    drop(finishing);
+
+    let report = timeout(WAIT, shutdown).await??;
+    assert_eq!(report, ShutdownReport { drained: 1, cut: 1 });
+    let mut rest = Vec::new();
+    timeout(WAIT, stuck.read_to_end(&mut rest)).await??;
+    Ok(())
+}
+
+#[tokio::test]
+async fn shutdown_should_close_idle_http_connections_without_waiting() -> Result<()> {
+    let handle = minginx::start(http_config().await?).await?;
+    let proxy = handle.local_addrs()[0];
+
+    // 客户端保持 keep-alive 连接
+    let client = reqwest::Client::new();
+    let resp = client.get(format!("http://{}/", proxy)).send().await?;
+    assert_eq!(resp.text().await?.split(' ').next(), Some("0"));
+
+    // 默认的 grace period 是 30 秒, 空闲的 HTTP 连接应该立即关闭
+    let report = timeout(WAIT, handle.shutdown()).await?;
+    assert_eq!(report, ShutdownReport { drained: 1, cut: 0 });
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+#[tokio::test]
+async fn handover_should_pass_listeners_to_new_process() -> Result<()> {
+    let backends = start_backends(2).await?;
+    let socket = std::env::temp_dir().join(format!(
+        "minginx-handover-{}-{}.sock",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let mut config = config(Strategy::RoundRobin, &backends[..1], &[1]);
+    config.shutdown.handover_socket = Some(socket.clone());
+    let old = minginx::start(config.clone()).await?;
+    let proxy = old.local_addrs()[0];
+    let (id, mut in_flight) = connect(proxy).await?;
+    assert_eq!(id, 0);
+
+    // 新进程使用同一个地址, 换成另一个 backend
+    config.listeners[0].listen_addr = proxy;
+    config.upstreams.get_mut("web").unwrap().servers[0].addr = backends[1].to_string();
+    let new = minginx::start(config).await?;
+    assert_eq!(new.local_addrs(), [proxy]);
+    let old = tokio::spawn(old.wait());
+
+    // 旧进程收到回复之后才停止 accept, 在这之前的新连接还会分给它
+    timeout(WAIT, async {
+        while connect(proxy).await?.0 != 1 {}
+        Ok::<_, anyhow::Error>(())
+    })
+    .await??;
+    ping(&mut in_flight).await?;
+    drop(in_flight);
+
+    let report = timeout(WAIT, old).await???;
+    assert!(report.drained >= 1, "{:?}", report);
+    assert_eq!(report.cut, 0);
+    std::fs::remove_file(socket)?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn handover_should_keep_old_process_when_new_process_fails_to_start() -> Result<()> {
+    let backends = start_backends(2).await?;
+    let socket = std::env::temp_dir().join(format!(
+        "minginx-handover-{}-{}.sock",
+        std::process::id(),
+        rand::random::<u32>()
+    ));
+    let mut config = config(Strategy::RoundRobin, &backends[..1], &[1]);
+    config.shutdown.handover_socket = Some(socket.clone());
+    let old = minginx::start(config.clone()).await?;
+    let proxy = old.local_addrs()[0];
+    let old = tokio::spawn(old.wait());
+
+    // 新配置的证书不存在, 新进程启动失败, 没有回复旧进程
+    config.listeners[0].listen_addr = proxy;
+    config.upstreams.get_mut("web").unwrap().servers[0].addr = backends[1].to_string();
+    let mut broken = config.clone();
+    broken.listeners[0].tls = Some(ListenerTlsConfig {
+        cert: "fixtures/tls/missing.pem".into(),
+        ..listener_tls()
+    });
+    assert!(minginx::start(broken).await.is_err());
+    // 给旧进程处理 (不应该收到的) 回复的时间
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert!(!old.is_finished());
+    for _ in 0..4 {
+        assert_eq!(connect(proxy).await?.0, 0);
+    }
+
+    // 旧进程继续等待下一个新进程
+    let new = minginx::start(config).await?;
+    assert_eq!(new.local_addrs(), [proxy]);
+    timeout(WAIT, old).await???;
+    assert_eq!(connect(proxy).await?.0, 1);
+    std::fs::remove_file(socket)?;
+    Ok(())
+}
+
+// 本地的 UDP backend: 回复自己的编号和收到的数据
+async fn start_udp_backends(n: usize) -> Result<Vec<SocketAddr>> {
+    let mut addrs = Vec::with_capacity(n);
+    for id in 0..n {
+        let socket = UdpSocket::bind("127.0.0.1:0").await?;
+        addrs.push(socket.local_addr()?);
+        tokio::spawn(serve_udp_backend(socket, id));
+    }
+    Ok(addrs)
+}
+
+async fn serve_udp_backend(socket: UdpSocket, id: usize) {
+    let mut buf = [0; 1024];
+    while let Ok((n, peer)) = socket.recv_from(&mut buf).await {
+        let reply = format!("{} {}", id, String::from_utf8_lossy(&buf[..n]));
+        let _ = socket.send_to(reply.as_bytes(), peer).await;
+    }
+}
+
+async fn udp_request(client: &UdpSocket, proxy: SocketAddr, payload: &str) -> Result<String> {
+    client.send_to(payload.as_bytes(), proxy).await?;
+    let mut buf = [0; 1024];
+    let n = timeout(WAIT, client.recv(&mut buf)).await??;
+    Ok(String::from_utf8_lossy(&buf[..n]).into_owned())
+}
+
+#[tokio::test]
+async fn udp_flows_should_be_balanced_counted_and_expired() -> Result<()> {
+    let backends = start_udp_backends(2).await?;
+    let mut config = config(Strategy::RoundRobin, &backends, &[1, 1]);
+    config.listeners[0].udp = Some(UdpConfig {
+        flow_idle_timeout: Duration::from_millis(300),
+    });
+    config.admin = Some(AdminConfig {
+        listen_addr: "127.0.0.1:0".parse()?,
+    });
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+    let admin = format!("http://{}", handle.admin_addr().unwrap());
+
+    let a = UdpSocket::bind("127.0.0.1:0").await?;
+    let b = UdpSocket::bind("127.0.0.1:0").await?;
+    assert_eq!(udp_request(&a, proxy, "a1").await?, "0 a1");
+    assert_eq!(udp_request(&b, proxy, "b1").await?, "1 b1");
+    // 同一个客户端地址的数据报属于同一个 flow, 发给同一个 backend
+    assert_eq!(udp_request(&a, proxy, "a2").await?, "0 a2");
+
+    let sessions: serde_json::Value = reqwest::get(format!("{}/sessions", admin))
+        .await?
+        .json()
+        .await?;
+    let flow = &sessions[0];
+    assert_eq!(flow["protocol"], "udp");
+    assert_eq!(flow["client"], a.local_addr()?.to_string());
+    assert_eq!(flow["backend"], backends[0].to_string());
+    assert_eq!(flow["datagrams_in"], 2);
+    assert_eq!(flow["datagrams_out"], 2);
+    assert_eq!(flow["bytes_in"], 4);
+    assert_eq!(flow["bytes_out"], 8);
+
+    // 空闲超时后 flow 被回收, 之后的数据报建立新的 flow
+    let stats = settled_stats(&handle).await?;
+    assert_eq!(stats.connections, 2);
+    assert_eq!(stats.close_reasons[&CloseReason::IdleTimeout], 2);
+    assert_eq!(udp_request(&a, proxy, "a3").await?, "0 a3");
+    assert_eq!(handle.stats().connections, 3);
+    Ok(())
+}
+
+#[tokio::test]
+async fn udp_backend_should_recover_after_coming_back() -> Result<()> {
+    let mut backends = start_udp_backends(1).await?;
+    // 第二个 backend 先不监听, 数据报会收到 ICMP port unreachable
+    let dead = UdpSocket::bind("127.0.0.1:0").await?;
+    backends.push(dead.local_addr()?);
+    drop(dead);
+    let health_check = HealthCheckConfig {
+        interval: Duration::from_millis(50),
+        unhealthy_threshold: 1,
+        healthy_threshold: 2,
+        ..Default::default()
+    };
+    let mut config = config_with_health(Strategy::RoundRobin, &backends, &[1, 1], health_check);
+    config.listeners[0].udp = Some(UdpConfig::default());
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+    let mut events = handle.pool("web").unwrap().subscribe();
+
+    // 每个客户端地址是一个新的 flow, 分到 backends[1] 的 flow 发送第二个数据报时
+    // 收到 ICMP 的错误, backend 被摘除
+    let mut replies = Vec::new();
+    for i in 0..2 {
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        let mut buf = [0; 64];
+        for j in 0..2 {
+            client
+                .send_to(format!("c{}{}", i, j).as_bytes(), proxy)
+                .await?;
+            if let Ok(n) = timeout(Duration::from_millis(50), client.recv(&mut buf)).await {
+                replies.push(String::from_utf8_lossy(&buf[..n?]).into_owned());
+            }
+        }
+    }
+    assert_eq!(replies, ["0 c00", "0 c01"]);
+    let event = next_event(&mut events).await?;
+    assert_eq!(
+        (event.backend.as_str(), event.healthy),
+        (&*backends[1].to_string(), false)
+    );
+
+    // 探测收到 ICMP 的错误, 没有监听时一直保持摘除
+    assert!(timeout(Duration::from_millis(300), events.recv())
+        .await
+        .is_err());
+
+    // backend 恢复监听之后, UDP 探测让它重新加入
+    let socket = UdpSocket::bind(backends[1]).await?;
+    tokio::spawn(serve_udp_backend(socket, 1));
+    let event = next_event(&mut events).await?;
+    assert_eq!(
+        (event.backend.as_str(), event.healthy),
+        (&*backends[1].to_string(), true)
+    );
+    let mut ids = Vec::new();
+    for i in 0..4 {
+        let client = UdpSocket::bind("127.0.0.1:0").await?;
+        let reply = udp_request(&client, proxy, &format!("d{}", i)).await?;
+        ids.push(reply[..1].to_string());
+    }
+    ids.sort();
+    assert_eq!(ids, ["0", "0", "1", "1"]);
+    Ok(())
+}
+
+#[tokio::test]
+async fn denied_udp_datagrams_should_be_counted() -> Result<()> {
+    let backends = start_udp_backends(1).await?;
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.listeners[0].udp = Some(UdpConfig::default());
+    config.listeners[0].access = AccessConfig {
+        deny: vec!["127.0.0.0/8".parse()?],
+        ..Default::default()
+    };
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+    let client = UdpSocket::bind("127.0.0.1:0").await?;
+    for _ in 0..3 {
+        client.send_to(b"hello", proxy).await?;
+    }
+    let mut buf = [0; 64];
+    assert!(timeout(Duration::from_millis(200), client.recv(&mut buf))
+        .await
+        .is_err());
+    let stats = settled_stats(&handle).await?;
+    assert_eq!(stats.connections, 3);
+    assert_eq!(stats.close_reasons[&CloseReason::Denied], 3);
+    Ok(())
+}
+
+// shadow backend: 先发一大段不会被转发的回复, 然后把收到的全部数据交给测试
+async fn start_shadow_backend() -> Result<(SocketAddr, mpsc::UnboundedReceiver<Vec<u8>>)> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    let (tx, rx) = mpsc::unbounded_channel();
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let tx = tx.clone();
+            tokio::spawn(async move {
+                stream.write_all(&vec![b'x'; 1024 * 1024]).await?;
+                let mut buf = Vec::new();
+                stream.read_to_end(&mut buf).await?;
+                let _ = tx.send(buf);
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok((addr, rx))
+}
+
+fn mirror_config(backends: &[SocketAddr], shadow: SocketAddr, fraction: f64) -> Config {
+    let mut config = config(Strategy::RoundRobin, backends, &[1]);
+    let mut upstream = config.upstreams["web"].clone();
+    upstream.servers[0].addr = shadow.to_string();
+    config.upstreams.insert("shadow".into(), upstream);
+    config.upstreams.get_mut("web").unwrap().mirror = Some(MirrorConfig {
+        upstream: "shadow".into(),
+        fraction,
+    });
+    config
+}
+
+// 发送一段数据, 确认原样返回, 关闭之后读到 EOF
+async fn echo(proxy: SocketAddr, data: &[u8]) -> Result<()> {
+    let (id, mut stream) = connect(proxy).await?;
+    assert_eq!(id, 0);
+    stream.write_all(data).await?;
+    stream.shutdown().await?;
+    let mut echoed = Vec::new();
+    timeout(WAIT, stream.read_to_end(&mut echoed)).await??;
+    assert!(echoed == data);
+    Ok(())
+}
+
+#[tokio::test]
+async fn mirror_should_copy_client_data_and_discard_shadow_replies() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let (shadow, mut mirrored) = start_shadow_backend().await?;
+    let handle = minginx::start(mirror_config(&backends, shadow, 1.0)).await?;
+    let proxy = handle.local_addrs()[0];
+    let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+    echo(proxy, &data).await?;
+    let received = timeout(WAIT, mirrored.recv()).await?.unwrap();
+    assert!(received == data);
+
+    // fraction 为 0 时不镜像
+    handle.reload(mirror_config(&backends, shadow, 0.0))?;
+    echo(proxy, b"hello").await?;
+    assert!(timeout(Duration::from_millis(200), mirrored.recv())
+        .await
+        .is_err());
+
+    // shadow 连不上不影响主连接
+    handle.reload(mirror_config(&backends, closed_addr()?, 1.0))?;
+    echo(proxy, b"hello").await?;
+    let stats = settled_stats(&handle).await?;
+    assert_eq!(stats.close_reasons[&CloseReason::Closed], 3);
+    Ok(())
+}
+
+// failing 为 true 时 accept 之后稍等一会就 reset, 否则和 serve_backend 一样
+async fn start_flaky_backend(failing: Arc<AtomicBool>) -> Result<SocketAddr> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            let failing = failing.load(Ordering::Relaxed);
+            tokio::spawn(async move {
+                if failing {
+                    tokio::time::sleep(Duration::from_millis(50)).await;
+                    stream.set_linger(Some(Duration::ZERO))?;
+                    return Ok(());
+                }
+                stream.write_all(b"0\n").await?;
+                let (mut reader, mut writer) = stream.split();
+                tokio::io::copy(&mut reader, &mut writer).await?;
+                Ok::<_, anyhow::Error>(())
+            });
+        }
+    });
+    Ok(addr)
+}
+
+#[tokio::test]
+async fn circuit_breaker_should_open_on_early_resets_and_recover() -> Result<()> {
+    let failing = Arc::new(AtomicBool::new(true));
+    let backends = [start_flaky_backend(Arc::clone(&failing)).await?];
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.upstreams.get_mut("web").unwrap().circuit_breaker = Some(CircuitBreakerConfig {
+        failure_threshold: 2,
+        open_duration: Duration::from_millis(300),
+        half_open_requests: 1,
+        early_reset: Duration::from_secs(1),
+    });
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+    let breaker = || {
+        handle
+            .pool("web")
+            .unwrap()
+            .circuit_breaker()
+            .unwrap()
+            .stats()
+    };
+
+    // connect 成功, 但很快被 reset; connect 成功时被动健康检查不会摘除 backend
+    for _ in 0..2 {
+        assert!(connect(proxy).await.is_err());
+        settled_stats(&handle).await?;
+    }
+    assert_eq!(breaker().state, CircuitState::Open);
+    assert!(connect(proxy).await.is_err());
+    let stats = settled_stats(&handle).await?;
+    assert_eq!(stats.close_reasons[&CloseReason::Error], 2);
+    assert_eq!(stats.close_reasons[&CloseReason::CircuitOpen], 1);
+    assert_eq!(breaker().rejected, 1);
+
+    // open_duration 之后半开, 试探连接成功后关闭
+    failing.store(false, Ordering::Relaxed);
+    tokio::time::sleep(Duration::from_millis(350)).await;
+    assert_eq!(breaker().state, CircuitState::HalfOpen);
+    let (_, mut stream) = connect(proxy).await?;
+    stream.shutdown().await?;
+    timeout(WAIT, stream.read_to_end(&mut Vec::new())).await??;
+    settled_stats(&handle).await?;
+    assert_eq!(breaker().state, CircuitState::Closed);
+    assert_eq!(breaker().opened, 1);
+    connect(proxy).await?;
+    Ok(())
+}
+
+#[tokio::test]
+async fn faults_should_be_injected_and_toggled_at_runtime() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+    config.admin = Some(AdminConfig {
+        listen_addr: "127.0.0.1:0".parse()?,
+    });
+    let handle = minginx::start(config).await?;
+    let proxy = handle.local_addrs()[0];
+    let url = format!("http://{}/listeners/0/faults", handle.admin_addr().unwrap());
+    let client = reqwest::Client::new();
+    let set_faults = |faults: serde_json::Value| client.put(&url).json(&faults).send();
+
+    assert_eq!(
+        set_faults(serde_json::json!([{ "type": "drop_connect" }]))
+            .await?
+            .status(),
+        204
+    );
+    assert!(connect(proxy).await.is_err());
+
+    // 回复 "0\n" 之后只能再收到 2 个字节
+    set_faults(serde_json::json!([{ "type": "truncate", "bytes": 4 }])).await?;
+    let (_, mut stream) = connect(proxy).await?;
+    stream.write_all(b"hello").await?;
+    let mut received = Vec::new();
+    timeout(WAIT, stream.read_to_end(&mut received)).await??;
+    assert_eq!(received, b"he");
+
+    set_faults(serde_json::json!([{ "type": "latency", "delay": "200ms" }])).await?;
+    let start = tokio::time::Instant::now();
+    connect(proxy).await?;
+    assert!(start.elapsed() >= Duration::from_millis(200));
+
+    set_faults(serde_json::json!([{ "type": "reset", "after": "50ms" }])).await?;
+    let (_, mut stream) = connect(proxy).await?;
+    let err = timeout(WAIT, stream.read_to_end(&mut Vec::new()))
+        .await?
+        .unwrap_err();
+    assert_eq!(err.kind(), std::io::ErrorKind::ConnectionReset);
+
+    // 关闭之后恢复正常
+    set_faults(serde_json::json!([{ "type": "reset", "enabled": false }])).await?;
+    let faults: serde_json::Value = client.get(&url).send().await?.json().await?;
+    assert_eq!(
+        faults,
+        serde_json::json!([{ "type": "reset", "after": "0s", "probability": 1.0, "enabled": false }])
+    );
+    let (_, mut stream) = connect(proxy).await?;
+    ping(&mut stream).await?;
+    drop(stream);
+
+    let invalid = set_faults(serde_json::json!([{ "type": "drop_connect", "probability": 2 }]));
+    assert_eq!(invalid.await?.status(), 400);
+    let stats = settled_stats(&handle).await?;
+    assert_eq!(stats.close_reasons[&CloseReason::Fault], 3);
+    Ok(())
+}
+
+// 等待 dir 中除了 skip 之外出现一个写完的抓包文件, 也就是有 upstream 方向的结束记录
+async fn wait_capture(dir: &std::path::Path, skip: &[PathBuf]) -> Result<(PathBuf, CaptureFile)> {
+    timeout(WAIT, async {
+        loop {
+            for entry in std::fs::read_dir(dir).into_iter().flatten() {
+                let path = entry?.path();
+                if skip.contains(&path) {
+                    continue;
+                }
+                let Ok(capture) = CaptureFile::parse(&std::fs::read(&path)?) else {
+                    continue;
+                };
+                let finished = capture.records.iter().any(|record| {
+                    record.direction == Direction::UpstreamToClient && record.data.is_empty()
+                });
+                if finished {
+                    return Ok((path, capture));
+                }
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    })
+    .await?
+}
+
+#[tokio::test]
+async fn capture_should_record_filtered_sessions_for_replay() -> Result<()> {
+    let backends = start_backends(1).await?;
+    let dir = std::env::temp_dir().join(format!(
+        "minginx-capture-{}-{}",
+        std::process::id(),
+        NEXT_FILE.fetch_add(1, Ordering::Relaxed)
+    ));
+    let capture_config = |clients: &str, max_session_bytes| {
+        let mut config = config(Strategy::RoundRobin, &backends, &[1]);
+        config.capture = Some(CaptureConfig {
+            dir: dir.clone(),
+            clients: vec![clients.parse().unwrap()],
+            upstreams: vec!["web".into()],
+            max_session_bytes,
+            max_total_bytes: 1024 * 1024,
+        });
+        config
+    };
+    let handle = minginx::start(capture_config("127.0.0.0/8", 1024)).await?;
+    let proxy = handle.local_addrs()[0];
+    echo(proxy, b"hello capture").await?;
+    let (first, capture) = wait_capture(&dir, &[]).await?;
+    assert_eq!(capture.header.upstream, "web");
+    assert_eq!(capture.header.backend, backends[0].to_string());
+    assert_eq!(capture.data(Direction::ClientToUpstream), b"hello capture");
+    assert_eq!(
+        capture.data(Direction::UpstreamToClient),
+        b"0\nhello capture"
+    );
+
+    // 直接对 backend 重放, 回复和抓包时相同
+    let options = ReplayOptions {
+        keep_timing: true,
+        idle_timeout: Duration::from_millis(200),
+    };
+    let report = replay(&capture, &capture.header.backend, &options).await?;
+    assert_eq!(report.first_mismatch(), None);
+
+    // 不在 clients 中的客户端不抓
+    handle.reload(capture_config("10.0.0.0/8", 1024))?;
+    echo(proxy, b"hello").await?;
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    assert_eq!(std::fs::read_dir(&dir)?.count(), 1);
+
+    // 超过 max_session_bytes 之后不再记录
+    handle.reload(capture_config("127.0.0.1/32", 4))?;
+    echo(proxy, b"hello").await?;
+    // shutdown 等抓包文件写完才返回
+    handle.shutdown().await;
+    let files: Vec<_> = std::fs::read_dir(&dir)?.collect::<Result<_, _>>()?;
+    let truncated = files.iter().find(|entry| entry.path() != first).unwrap();
+    let truncated = CaptureFile::read(truncated.path()).await?;
+    let recorded: usize = truncated
+        .records
+        .iter()
+        .map(|record| record.data.len())
+        .sum();
+    assert_eq!(recorded, 4);
+    std::fs::remove_dir_all(&dir)?;
+    Ok(())
+}